@@ -0,0 +1,194 @@
+use crate::cpu::CPUState;
+use crate::system::bus::MIP_SEIP;
+use crate::system::rv32;
+
+// CSR addresses, the top 4 bits of the address encode the accessibility
+//  11..10 = 0b11 read-only, otherwise read/write
+//  9..8   = lowest privilege level that can access the CSR
+
+// Unprivileged Counter/Timers
+pub const CYCLE: rv32::Word = 0xc00;
+pub const TIME: rv32::Word = 0xc01;
+pub const CYCLEH: rv32::Word = 0xc80;
+pub const TIMEH: rv32::Word = 0xc81;
+
+// Supervisor Trap Setup
+pub const SSTATUS: rv32::Word = 0x100;
+pub const SIE: rv32::Word = 0x104;
+pub const STVEC: rv32::Word = 0x105;
+pub const SCOUNTEREN: rv32::Word = 0x106;
+
+// Supervisor Trap Handling
+pub const SSCRATCH: rv32::Word = 0x140;
+pub const SEPC: rv32::Word = 0x141;
+pub const SCAUSE: rv32::Word = 0x142;
+pub const STVAL: rv32::Word = 0x143;
+pub const SIP: rv32::Word = 0x144;
+
+// Supervisor Protection and Translation
+pub const SATP: rv32::Word = 0x180;
+
+// Machine Information Registers
+pub const MVENDORID: rv32::Word = 0xf11;
+pub const MARCHID: rv32::Word = 0xf12;
+pub const MIMPID: rv32::Word = 0xf13;
+pub const MHARTID: rv32::Word = 0xf14;
+
+// Machine Trap Setup
+pub const MSTATUS: rv32::Word = 0x300;
+pub const MISA: rv32::Word = 0x301;
+pub const MEDELEG: rv32::Word = 0x302;
+pub const MIDELEG: rv32::Word = 0x303;
+pub const MIE: rv32::Word = 0x304;
+pub const MTVEC: rv32::Word = 0x305;
+pub const MCOUNTEREN: rv32::Word = 0x306;
+
+// Machine Trap Handling
+pub const MSCRATCH: rv32::Word = 0x340;
+pub const MEPC: rv32::Word = 0x341;
+pub const MCAUSE: rv32::Word = 0x342;
+pub const MTVAL: rv32::Word = 0x343;
+pub const MIP: rv32::Word = 0x344;
+
+// Machine Counter/Timers
+pub const MCYCLE: rv32::Word = 0xb00;
+pub const MCYCLEH: rv32::Word = 0xb80;
+
+// Debug/Trace Registers (shared with Debug Mode)
+pub const TSELECT: rv32::Word = 0x7a0;
+pub const TDATA1: rv32::Word = 0x7a1;
+pub const TDATA2: rv32::Word = 0x7a2;
+pub const TDATA3: rv32::Word = 0x7a3;
+pub const TINFO: rv32::Word = 0x7a4;
+
+// Debug Mode Registers
+pub const DCSR: rv32::Word = 0x7b0;
+pub const DPC: rv32::Word = 0x7b1;
+pub const DSCRATCH0: rv32::Word = 0x7b2;
+
+// mstatus fields
+pub const MSTATUS_SIE: rv32::Word = 1 << 1;
+pub const MSTATUS_MIE: rv32::Word = 1 << 3;
+pub const MSTATUS_SPIE: rv32::Word = 1 << 5;
+pub const MSTATUS_MPIE: rv32::Word = 1 << 7;
+pub const MSTATUS_SPP: rv32::Word = 1 << 8;
+pub const MSTATUS_MPP: rv32::Word = 3 << 11;
+// the bits of mstatus visible through sstatus, SIE SPIE SPP SUM MXR
+const SSTATUS_MASK: rv32::Word = 0x000c0122;
+// S-mode can only set its own software interrupt through sip
+const SIP_WRITABLE: rv32::Word = 1 << 1;
+
+// dcsr fields, the debugger can change ebreakm/s/u, step and prv
+pub const DCSR_XDEBUGVER: rv32::Word = 4 << 28;
+pub const DCSR_CAUSE_TRIGGER: rv32::Word = 2 << 6;
+const DCSR_WRITABLE: rv32::Word = 0xb007;
+
+impl CPUState {
+    fn privilege(&self) -> rv32::Word {
+        self.extraflags & 3
+    }
+
+    fn csr_accessible(&self, csr: rv32::Word) -> bool {
+        let required = (csr >> 8) & 3;
+        // debug mode CSRs are invisible outside of debug mode
+        if (DCSR..=DSCRATCH0).contains(&csr) && !self.debug_mode {
+            return false;
+        }
+        self.debug_mode || self.privilege() >= required
+    }
+
+    pub fn read_csr(&self, csr: rv32::Word) -> Option<rv32::Word> {
+        if !self.csr_accessible(csr) {
+            return None;
+        }
+        let value = match csr {
+            CYCLE | MCYCLE => self.cyclel,
+            CYCLEH | MCYCLEH => self.cycleh,
+            TIME => self.timel,
+            TIMEH => self.timeh,
+            MVENDORID => self.mvendorid,
+            MARCHID => self.marchid,
+            MIMPID => self.mimpid,
+            MHARTID => self.mhartid,
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN | MCOUNTEREN => 0,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.mip & self.mideleg,
+            SATP => 0, // Bare, we have no MMU
+            MSTATUS => self.mstatus,
+            MISA => self.misa,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            TSELECT => self.triggers.tselect(),
+            TDATA1 => self.triggers.tdata(1),
+            TDATA2 => self.triggers.tdata(2),
+            TDATA3 => self.triggers.tdata(3),
+            TINFO => self.triggers.tinfo(),
+            DCSR => self.dcsr,
+            DPC => self.dpc,
+            DSCRATCH0 => self.dscratch0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn write_csr(&mut self, csr: rv32::Word, value: rv32::Word) -> Option<()> {
+        if !self.csr_accessible(csr) || (csr >> 10) & 3 == 3 {
+            return None;
+        }
+        match csr {
+            MCYCLE => self.cyclel = value,
+            MCYCLEH => self.cycleh = value,
+            SSTATUS => self.mstatus = (self.mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK),
+            SIE => self.mie = (self.mie & !self.mideleg) | (value & self.mideleg),
+            STVEC => self.stvec = value,
+            SCOUNTEREN | MCOUNTEREN => (), // every counter is always readable
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !3,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            SIP => {
+                let writable = SIP_WRITABLE & self.mideleg;
+                self.mip = (self.mip & !writable) | (value & writable)
+            }
+            SATP => (), // WARL, only Bare is supported
+            MSTATUS => self.mstatus = value,
+            MISA => (), // WARL, extensions can't be turned off at runtime
+            // only the exceptions and interrupts S-mode can actually take
+            MEDELEG => self.medeleg = value & 0xb3ff,
+            MIDELEG => self.mideleg = value & 0x222,
+            MIE => self.mie = value,
+            MTVEC => self.mtvec = value,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MIP => {
+                self.mip = value;
+                self.seip = value & MIP_SEIP;
+            }
+            TSELECT => self.triggers.write_tselect(value),
+            TDATA1 => self.triggers.write_tdata(1, value, self.debug_mode),
+            TDATA2 => self.triggers.write_tdata(2, value, self.debug_mode),
+            TDATA3 => self.triggers.write_tdata(3, value, self.debug_mode),
+            TINFO => (), // writes are ignored
+            DCSR => self.dcsr = (self.dcsr & !DCSR_WRITABLE) | (value & DCSR_WRITABLE),
+            DPC => self.dpc = value,
+            DSCRATCH0 => self.dscratch0 = value,
+            _ => return None,
+        }
+        Some(())
+    }
+}
@@ -1,8 +1,13 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
+pub mod csr;
+pub mod sbi;
+pub mod trigger;
+
 use crate::ext::decode;
+use crate::loader::symbols::SymbolTable;
 use crate::system::bus::*;
-use crate::system::ram;
+use crate::system::console;
 use crate::system::rv32;
 use std::{cell::RefCell, rc::Rc};
 
@@ -24,6 +29,7 @@ pub struct CPUState {
     pub x: [rv32::Word; 32],
     pub pc: rv32::Word,
     pub trap: rv32::Word,
+    pub tval: rv32::Word, // value for mtval when trap is set by an instruction
     pub bus: Rc<RefCell<Bus>>,
     // for simplicities sake we will just put CSRs here, in the CPU.
     // in reality they are part of the system and should be in the system module
@@ -36,41 +42,130 @@ pub struct CPUState {
     pub mstatus: rv32::Word, // Machine status reg to disable interrupts
 
     // Timers
-    pub cyclel: rv32::Word,   // Lower 32 bits of the cycle counter
-    pub cycleh: rv32::Word,   // Upper 32 bits of the cycle counter
-    pub timel: rv32::Word,    // Lower 32 bits of the timer
-    pub timeh: rv32::Word,    // Upper 32 bits of the timer
-    pub timecmpl: rv32::Word, // Lower 32 bits of the timer compare register
-    pub timecmph: rv32::Word, // Upper 32 bits of the timer compare register
+    pub cyclel: rv32::Word, // Lower 32 bits of the cycle counter
+    pub cycleh: rv32::Word, // Upper 32 bits of the cycle counter
+    pub timel: rv32::Word,  // Lower 32 bits of the timer (mirrors the CLINT's mtime)
+    pub timeh: rv32::Word,  // Upper 32 bits of the timer
 
     // Machine Information Registers
     pub mvendorid: rv32::Word, // Vendor ID of the hart
     pub marchid: rv32::Word,   // Architecture ID of the hart
     pub mimpid: rv32::Word,    // Implementation ID of the hart
     pub mhartid: rv32::Word,   // Hardware thread ID of the hart
+    pub misa: rv32::Word,      // Supported ISA extensions
 
     // Machine Trap Stuffs
     pub mscratch: rv32::Word, // Scratch register for machine trap handlers
     pub mtvec: rv32::Word,    // Address of trap handler
     pub mie: rv32::Word,      // Machine interrupt enable
     pub mip: rv32::Word,      // Machine interrupt pending
+    pub seip: rv32::Word,     // mip.SEIP as written by software, the PLIC's line is ORed in
 
     pub mepc: rv32::Word,   // Machine exception program counter
     pub mtval: rv32::Word,  // Machine trap value
     pub mcause: rv32::Word, // Machine trap cause
 
-    // Note: only a few bits are used.  (Machine = 3, User = 0)
+    pub medeleg: rv32::Word, // Exceptions delegated to supervisor mode
+    pub mideleg: rv32::Word, // Interrupts delegated to supervisor mode
+
+    // Supervisor Trap Stuffs, sstatus, sie and sip are views of the machine CSRs
+    pub stvec: rv32::Word,    // Address of supervisor trap handler
+    pub sscratch: rv32::Word, // Scratch register for supervisor trap handlers
+    pub sepc: rv32::Word,     // Supervisor exception program counter
+    pub scause: rv32::Word,   // Supervisor trap cause
+    pub stval: rv32::Word,    // Supervisor trap value
+
+    // Debug (Sdtrig triggers and the bits of Sdext we need to halt)
+    pub triggers: trigger::TriggerModule,
+    pub dcsr: rv32::Word,      // Debug control and status
+    pub dpc: rv32::Word,       // Debug program counter
+    pub dscratch0: rv32::Word, // Debug scratch register
+    pub debug_mode: bool,      // Hart is halted in debug mode
+
+    pub bus_error: Option<String>, // why the bus refused the last access
+
+    // Note: only a few bits are used.  (Machine = 3, Supervisor = 1, User = 0)
     // Bits 0..1 = privilege.
     // Bit 2 = WFI (Wait for interrupt)
     // Bit 3+ = Load/Store reservation LSBs.
     pub extraflags: rv32::Word,
 }
 
+impl CPUState {
+    // A fetch, load or store the bus refused, raised as an access fault with
+    // the address for mtval
+    pub fn access_fault(&mut self, cause: rv32::Word, address: rv32::Word, error: String) {
+        self.trap = cause + 1;
+        self.tval = address;
+        self.bus_error = Some(error);
+    }
+
+    // Match a load / store address, or the data when given, against the
+    // triggers. Returns true if a trigger fired and the access must not happen
+    pub fn check_triggers(
+        &mut self,
+        access: trigger::Access,
+        address: rv32::Word,
+        data: Option<rv32::Word>,
+    ) -> bool {
+        let privilege = self.extraflags & 3;
+        let mie = self.mstatus & 0x8 != 0;
+        let action = match data {
+            Some(data) => self.triggers.match_data(access, data, privilege, mie),
+            None => self.triggers.match_address(access, address, privilege, mie),
+        };
+        match action {
+            Some(action) => {
+                self.fire_trigger(action, address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Carry out a trigger's action, either raising a breakpoint exception
+    // with tval as mtval or halting the hart in debug mode at the current pc
+    pub fn fire_trigger(&mut self, action: trigger::TriggerAction, tval: rv32::Word) {
+        match action {
+            trigger::TriggerAction::Breakpoint => {
+                self.trap = 3 + 1;
+                self.tval = tval;
+            }
+            trigger::TriggerAction::DebugMode => {
+                println!(
+                    "VM > Trigger hit, halting in debug mode at 0x{:08x}",
+                    self.pc
+                );
+                self.dpc = self.pc;
+                self.dcsr = (self.dcsr & !(0x7 << 6) & !3)
+                    | csr::DCSR_CAUSE_TRIGGER
+                    | (self.extraflags & 3);
+                self.debug_mode = true;
+            }
+        }
+    }
+}
+
+// What to do when the bus refuses an access, stop the VM and say why, or
+// hand the guest an access fault the way hardware would. Firmware probing
+// for devices that aren't there needs the trap
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BusErrors {
+    Stop,
+    Trap,
+}
+
 pub struct CPU {
     state: CPUState,
     instruction_decoder: Rc<RefCell<decode::DecodeCycle>>,
     extensions: Vec<char>,
     last_it_time: u128,
+    reset_vector: rv32::Word, // where pc points coming out of reset
+    symbols: SymbolTable,     // names for addresses in traces and dumps
+    trace: bool,
+    sbi: bool,     // ECALLs from S-mode are handled by the built in SBI
+    stopped: bool, // the guest asked to be powered off
+    bus_errors: BusErrors,
 }
 
 impl CPU {
@@ -79,35 +174,56 @@ impl CPU {
         instruction_decoder: Rc<RefCell<decode::DecodeCycle>>,
         extensions: Vec<char>,
     ) -> CPU {
+        let reset_vector = bus.borrow().ram_base();
         CPU {
             state: CPUState {
                 x: [0; 32],
                 pc: 0,
                 trap: 0,
+                tval: 0,
                 bus,
                 mstatus: 0,
                 cyclel: 0,
                 cycleh: 0,
                 timel: 0,
                 timeh: 0,
-                timecmpl: 0,
-                timecmph: 0,
                 mvendorid: 0,
                 marchid: 0,
                 mimpid: 0,
                 mhartid: 0,
+                misa: 0,
                 mscratch: 0,
                 mtvec: 0,
                 mie: 0,
                 mip: 0,
+                seip: 0,
                 mepc: 0,
                 mtval: 0,
                 mcause: 0,
+                medeleg: 0,
+                mideleg: 0,
+                stvec: 0,
+                sscratch: 0,
+                sepc: 0,
+                scause: 0,
+                stval: 0,
+                triggers: trigger::TriggerModule::new(),
+                dcsr: csr::DCSR_XDEBUGVER | 3,
+                dpc: 0,
+                dscratch0: 0,
+                debug_mode: false,
+                bus_error: None,
                 extraflags: 0,
             },
             instruction_decoder,
             extensions,
             last_it_time: 0,
+            reset_vector,
+            symbols: SymbolTable::new(),
+            trace: true,
+            sbi: false,
+            stopped: false,
+            bus_errors: BusErrors::Stop,
         }
     }
 
@@ -122,43 +238,237 @@ impl CPU {
             .expect("Time went backwards")
             .as_micros();
 
-        self.state.pc = DRAM_BASE as rv32::Word;
+        self.state.pc = self.reset_vector;
+        self.stopped = false;
+        // we come out of reset in machine mode, or straight into the
+        // supervisor when the built in SBI stands in for the firmware
+        self.state.extraflags = if self.sbi { 1 } else { 3 };
         self.state.x[0] = 0x00000000; // x0 is tied to ground
-        self.state.x[2] = DRAM_BASE + DRAM_SIZE as u32; // x2 the stack pointer
+        self.state.x[2] = self.state.bus.borrow().ram_top(); // x2 the stack pointer
         self.state.mvendorid = 0x696969; // Vendor ID of the hart
         self.state.marchid = 0x285700; // Architecture ID of the hart
         self.state.mimpid = 0; // Implementation ID of the hart
         self.state.mhartid = 0; // Hardware thread ID of the hart
+        self.state.misa = self.extensions.iter().fold(1 << 30, |misa, ext| match ext {
+            'a'..='y' => misa | 1 << (*ext as u32 - 'a' as u32),
+            _ => misa,
+        }); // MXL = 1 (32 bit) and a bit for each single letter extension
+        self.state.misa |= 1 << ('s' as u32 - 'a' as u32) | 1 << ('u' as u32 - 'a' as u32);
 
         println!("VM > CPU Initialisd with extensions {:?}", self.extensions);
         self.dump_reg();
     }
 
     pub fn get_pc(&self) -> rv32::Word {
-        return self.state.pc;
+        self.state.pc
     }
 
-    fn fetch(&self) -> rv32::Word {
-        self.state.bus.borrow_mut().load_32(self.state.pc)
+    pub fn set_pc(&mut self, pc: rv32::Word) {
+        self.state.pc = pc;
     }
 
-    pub fn step(&mut self, elapsed_micros: u128) -> Result<(), String> {
-        // CSR stuff before fetch execute
-        let new_timer = ((self.state.timel as u128) + elapsed_micros) as rv32::Word;
-        if new_timer > self.state.timel {
-            self.state.timeh += 1;
+    pub fn set_reg(&mut self, reg: usize, value: rv32::Word) {
+        if reg != 0 {
+            self.state.x[reg] = value;
         }
-        self.state.timel = new_timer;
+    }
 
-        // handle time interrupt
-        if self.state.timel >= self.state.timecmpl
-            && self.state.timeh <= self.state.timecmph
-            && (self.state.timecmpl, self.state.timecmph) != (0, 0)
-        {
-            self.state.extraflags &= !4;
-            self.state.mip |= 1 << 7; // https://stackoverflow.com/a/61916199/2926815  Fire interrupt.
+    pub fn get_hartid(&self) -> rv32::Word {
+        self.state.mhartid
+    }
+
+    // Move the reset vector, pc is moved with it as we're still in reset
+    pub fn set_reset_vector(&mut self, pc: rv32::Word) {
+        println!("VM > Reset vector set to 0x{:08x}", pc);
+        self.reset_vector = pc;
+        self.state.pc = pc;
+    }
+
+    pub fn add_symbols(&mut self, file: &str, symbols: SymbolTable) {
+        self.symbols.replace(file, symbols);
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    // Whether an access fault stops the VM or is trapped to the guest
+    pub fn set_bus_errors(&mut self, bus_errors: BusErrors) {
+        println!("VM > Bus errors: {:?}", bus_errors);
+        self.bus_errors = bus_errors;
+    }
+
+    // Act as the machine mode firmware, ECALLs from S-mode are handled by
+    // the built in SBI and the hart is dropped into S-mode to run the payload
+    pub fn enable_sbi(&mut self) {
+        println!("VM > Using built in SBI firmware, starting in S-mode");
+        self.sbi = true;
+        self.state.extraflags = (self.state.extraflags & !3) | 1;
+        // supervisor software, timer and external interrupts
+        self.state.mideleg = MIP_SSIP | MIP_STIP | MIP_SEIP;
+        // misaligned fetch, breakpoint, user ecall and page faults, same as OpenSBI
+        self.state.medeleg = (1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15);
+    }
+
+    // Pick the highest priority interrupt we can take right now, returning
+    // its cause. Interrupts delegated to S-mode are only taken below M-mode
+    fn pending_interrupt(&self, pending: rv32::Word) -> Option<rv32::Word> {
+        let privilege = self.state.extraflags & 3;
+        let m_enabled = privilege < 3 || self.state.mstatus & csr::MSTATUS_MIE != 0;
+        let s_enabled =
+            privilege < 1 || (privilege == 1 && self.state.mstatus & csr::MSTATUS_SIE != 0);
+        let m_pending = pending & !self.state.mideleg;
+        let s_pending = pending & self.state.mideleg;
+        let takeable = if m_pending != 0 && m_enabled {
+            m_pending
+        } else if s_pending != 0 && s_enabled {
+            s_pending
+        } else {
+            return None;
+        };
+        // MEI, MSI, MTI, SEI, SSI, STI
+        [11, 3, 7, 9, 1, 5]
+            .into_iter()
+            .find(|cause| takeable & (1 << cause) != 0)
+    }
+
+    // Take a trap, to S-mode if it came from S or U-mode and is delegated
+    // (or there's only the built in firmware), otherwise to M-mode. Vectored
+    // mode only applies to interrupts
+    fn take_trap(&mut self, cause: rv32::Word, tval: rv32::Word) -> Result<(), String> {
+        let privilege = self.state.extraflags & 3;
+        let interrupt = cause & 0x80000000 != 0;
+        let code = cause & 0x7fffffff;
+        let delegated = if interrupt {
+            self.state.mideleg
         } else {
-            self.state.mip &= !(1 << 7);
+            self.state.medeleg
+        };
+        let vector = |tvec: rv32::Word| {
+            if tvec & 3 == 1 && interrupt {
+                (tvec & !3) + 4 * code
+            } else {
+                tvec & !3
+            }
+        };
+
+        // with the built in firmware there's nothing in M-mode to handle
+        // it, like OpenSBI's sbi_trap_redirect it goes to S-mode as if it
+        // had been delegated
+        let redirected = self.sbi && !interrupt;
+        if privilege <= 1 && (delegated & (1 << code) != 0 || redirected) {
+            let mstatus = self.state.mstatus;
+            let sie = (mstatus & csr::MSTATUS_SIE) << 4; // SIE into SPIE
+            let spp = (privilege & 1) << 8;
+            self.state.mstatus =
+                (mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP)) | sie | spp;
+            self.state.sepc = self.state.pc;
+            self.state.scause = cause;
+            self.state.stval = tval;
+            self.state.pc = vector(self.state.stvec);
+            // enter supervisor mode
+            self.state.extraflags = (self.state.extraflags & !3) | 1;
+            return Ok(());
+        }
+
+        if self.sbi {
+            return Err(format!(
+                "Unhandled trap to machine mode (mcause 0x{:08x}, mtval 0x{:08x}) at 0x{:08x}",
+                cause, tval, self.state.pc
+            ));
+        }
+
+        self.state.mepc = self.state.pc; // the kernel may advance mepc on it's own
+        self.state.mcause = cause;
+        self.state.mtval = tval;
+        // on a trap, the system will move MIE into MPIE
+        let mstatus = self.state.mstatus;
+        let mie = (mstatus & csr::MSTATUS_MIE) << 4;
+        let mpp = privilege << 11;
+        self.state.mstatus =
+            (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP)) | mie | mpp;
+        self.state.pc = vector(self.state.mtvec);
+        // enter machine mode
+        self.state.extraflags |= 3;
+        Ok(())
+    }
+
+    // Only RAM and ROM can be executed from, not device registers
+    fn fetch(&self) -> Result<rv32::Word, String> {
+        let mut bus = self.state.bus.borrow_mut();
+        if !bus.executable(self.state.pc) {
+            return Err(format!(
+                "VM > BUS > 0x{:08x} is not executable memory",
+                self.state.pc
+            ));
+        }
+        bus.load_32(self.state.pc)
+    }
+
+    // Everything we know about a refused access, for stopping the VM
+    fn access_fault_report(
+        &mut self,
+        cause: rv32::Word,
+        pc: rv32::Word,
+        address: rv32::Word,
+        inst: Option<rv32::Word>,
+    ) -> String {
+        let kind = match cause {
+            1 => "Instruction access fault",
+            5 => "Load access fault",
+            _ => "Store access fault",
+        };
+        let disassembly = match inst {
+            Some(inst) => format!(
+                "{} (0x{:08x})",
+                self.instruction_decoder.borrow().disassemble(inst),
+                inst
+            ),
+            None => "no instruction".to_string(),
+        };
+        let error = self.state.bus_error.take().unwrap_or_default();
+        format!(
+            "{} at pc 0x{:08x}{}: {}, address 0x{:08x}\n{}",
+            kind,
+            pc,
+            self.symbols.describe(pc),
+            disassembly,
+            address,
+            error
+        )
+    }
+
+    pub fn step(&mut self, elapsed_micros: u128) -> Result<(), String> {
+        // CSR stuff before fetch execute, the timer and the interrupt lines
+        // live on the bus in the CLINT and PLIC
+        let mut lines = {
+            let mut bus = self.state.bus.borrow_mut();
+            bus.tick(elapsed_micros as u64);
+            let mtime = bus.mtime();
+            self.state.timel = mtime as rv32::Word;
+            self.state.timeh = (mtime >> 32) as rv32::Word;
+            bus.interrupts()
+        };
+        // the built in SBI owns the machine timer and forwards it to S-mode
+        if self.sbi && lines & MIP_MTIP != 0 {
+            lines = (lines & !MIP_MTIP) | MIP_STIP;
+        }
+        // STIP is M-mode's to set unless the SBI is forwarding the timer,
+        // SEIP reads as what software wrote or the PLIC's line
+        let hardware = match self.sbi {
+            true => MIP_MSIP | MIP_MTIP | MIP_MEIP | MIP_STIP | MIP_SEIP,
+            false => MIP_MSIP | MIP_MTIP | MIP_MEIP | MIP_SEIP,
+        };
+        self.state.mip = (self.state.mip & !hardware) | lines | self.state.seip;
+
+        // any enabled interrupt wakes us from WFI, even if mstatus.MIE is clear
+        let pending = self.state.mip & self.state.mie;
+        if pending != 0 {
+            self.state.extraflags &= !4;
         }
 
         // if WFI is set, we exit early
@@ -168,55 +478,120 @@ impl CPU {
 
         let mut trap: rv32::Word = 0;
         let mut rval: rv32::Word = 0;
+        let mut fetched: Option<rv32::Word> = None;
         let cycle: rv32::Word = self.state.cyclel;
+        let privilege = self.state.extraflags & 3;
+        let mie = self.state.mstatus & csr::MSTATUS_MIE != 0;
 
-        if (self.state.mip & (1 << 7) != 0)
-            && (self.state.mie & (1 << 7) != 0/*mtie*/)
-            && (self.state.mstatus & 0x8 /*mie*/ != 0)
-        {
+        if let Some(cause) = self.pending_interrupt(pending) {
             // stall
-            trap = 0x80000007;
-            self.state.pc = self.state.pc - rv32::WORD as u32;
+            trap = 0x80000000 | cause;
+        } else if let Some(action) = self.state.triggers.take_pending().or_else(|| {
+            self.state.triggers.match_address(
+                trigger::Access::Execute,
+                self.state.pc,
+                privilege,
+                mie,
+            )
+        }) {
+            // icount and execute triggers fire before the instruction executes
+            self.state.fire_trigger(action, self.state.pc);
+            if self.state.debug_mode {
+                return Ok(());
+            }
+            trap = self.state.trap;
+            rval = self.state.tval;
+            self.state.trap = 0;
         } else {
             // TODO: We can execute multiple instructions per cycle
 
-            // fetch
-            let inst = self.fetch();
-            println!("VM > Fetched 0x{:08x}: 0x{:08x}", self.state.pc, inst);
-            self.state.x[0] = 0x00000000;
-
-            // decode and execute
-            self.instruction_decoder
-                .borrow_mut()
-                .decode_exec_inst(inst, &mut self.state)?;
+            // fetch, a refused fetch is an instruction access fault
+            match self.fetch() {
+                Err(e) => self.state.access_fault(1, self.state.pc, e),
+                Ok(inst) => {
+                    fetched = Some(inst);
+                    if self.trace {
+                        println!(
+                            "VM > Fetched 0x{:08x}{}: 0x{:08x}",
+                            self.state.pc,
+                            self.symbols.describe(self.state.pc),
+                            inst
+                        );
+                    }
+                    self.state.x[0] = 0x00000000;
+
+                    // decode and execute, anything unknown or reserved is
+                    // an illegal instruction
+                    let decoder = self.instruction_decoder.borrow_mut();
+                    if decoder.decode_exec_inst(inst, &mut self.state).is_err() {
+                        self.state.trap = 2 + 1;
+                    }
+
+                    // a load / store trigger may have halted us mid instruction
+                    if self.state.debug_mode {
+                        return Ok(());
+                    }
+                }
+            }
 
-            self.state.pc = self.state.pc + rv32::WORD as u32;
+            if self.sbi && self.state.trap == 9 + 1 {
+                // ecall from S-mode, the firmware handles it without a trap
+                self.state.trap = 0;
+                if let Some(reason) = sbi::handle_ecall(&mut self.state) {
+                    println!("VM > SBI > Powering off: {}", reason);
+                    self.stopped = true;
+                }
+            } else if self.state.trap != 0 {
+                trap = self.state.trap;
+                rval = self.state.tval;
+                self.state.trap = 0;
+            } else {
+                self.state.pc = self.state.pc.wrapping_add(rv32::WORD as u32);
+                self.state.triggers.retire(privilege);
+            }
         }
+        self.state.triggers.clear_suppress();
 
         // handle trap and interrupt
         if trap != 0 {
             if trap & 0x80000000 != 0 {
                 // interrupt
-                self.state.mcause = trap;
-                self.state.mtval = 0;
-                self.state.pc = self.state.pc + rv32::WORD as u32;
+                self.take_trap(trap, 0)?;
             } else {
-                // trap
-                self.state.mcause = trap - 1;
-                self.state.mtval &= if trap > 5 && trap <= 8 {
-                    rval
-                } else {
-                    self.state.pc
+                // trap, instructions set trap to the cause + 1
+                // illegal instructions give the instruction itself
+                let tval = match trap {
+                    3 => fetched.unwrap_or(0),
+                    4..=8 => rval,
+                    _ => self.state.pc,
                 };
+                let cause = trap - 1;
+                let pc = self.state.pc;
+                let access_fault = matches!(cause, 1 | 5 | 7);
+                if access_fault && self.bus_errors == BusErrors::Stop {
+                    return Err(self.access_fault_report(cause, pc, tval, fetched));
+                }
+                self.take_trap(cause, tval)?;
+                // a handler we can't fetch from would fault forever
+                if (access_fault || cause == 2)
+                    && !self.state.bus.borrow().executable(self.state.pc)
+                {
+                    let report = match access_fault {
+                        true => self.access_fault_report(cause, pc, tval, fetched),
+                        false => format!(
+                            "VM > Illegal instruction 0x{:08x} at 0x{:08x}{}",
+                            tval,
+                            pc,
+                            self.symbols.describe(pc)
+                        ),
+                    };
+                    return Err(format!(
+                        "{}\nVM > No trap handler to take it, 0x{:08x} is not executable",
+                        report, self.state.pc
+                    ));
+                }
+                self.state.bus_error = None;
             }
-            self.state.mepc = self.state.pc; // the kernel may advance mepc on it's own
-                                             // on an interrupt, the system will move MIE into MPIE
-            self.state.mstatus =
-                (((self.state.mstatus) & 0x08) << 4) | (((self.state.extraflags) & 3) << 11);
-            self.state.pc = self.state.mtvec - rv32::WORD as u32;
-            // enter machine mode
-            self.state.extraflags |= 3;
-            self.state.pc = self.state.pc + rv32::WORD as u32;
         }
 
         if self.state.cyclel > cycle {
@@ -227,7 +602,21 @@ impl CPU {
         Ok(())
     }
 
+    // Leave debug mode the way a debugger's resume would, continuing from dpc
+    // without re-triggering on the instruction we halted on
+    fn resume(&mut self) {
+        if !self.state.debug_mode {
+            return;
+        }
+        println!("VM > Resuming from debug mode at 0x{:08x}", self.state.dpc);
+        self.state.debug_mode = false;
+        self.state.pc = self.state.dpc;
+        self.state.extraflags = (self.state.extraflags & !3) | (self.state.dcsr & 3);
+        self.state.triggers.suppress_once();
+    }
+
     pub fn exec_step(&mut self) -> Result<(), String> {
+        self.resume();
         let since_epoch = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards");
@@ -241,7 +630,15 @@ impl CPU {
     }
 
     pub fn exec(&mut self) -> Result<(), String> {
-        while self.state.pc - DRAM_BASE < DRAM_SIZE as u32 {
+        self.resume();
+        // leaving memory ends the program, unless the guest is to get a
+        // fault. The console escape stops it wherever it is
+        while (self.bus_errors == BusErrors::Trap
+            || self.state.bus.borrow().executable(self.state.pc))
+            && !self.state.debug_mode
+            && !self.stopped
+            && !console::escaped()
+        {
             let since_epoch = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards");
@@ -256,14 +653,18 @@ impl CPU {
 
     pub fn dump_reg(&mut self) {
         println!("VM > Dumping registers");
-        println!("   > PC : 0x{:08x}", self.state.pc);
+        println!(
+            "   > PC : 0x{:08x}{}",
+            self.state.pc,
+            self.symbols.describe(self.state.pc)
+        );
         for i in 0..8 {
             print!("   > ");
             for j in 0..4 {
                 let coord = (i * 4) + j;
                 print!("x{0: <2}: 0x{1: <08x} ", coord, self.state.x[coord]);
             }
-            println!("");
+            println!();
         }
     }
 }
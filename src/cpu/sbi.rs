@@ -0,0 +1,468 @@
+use crate::cpu::CPUState;
+use crate::system::bus::MIP_SSIP;
+use crate::system::clint;
+use crate::system::rv32;
+use crate::system::uart;
+
+// Supervisor Binary Interface
+//
+// With the built in firmware enabled there is no M-mode code running in the
+// guest, ECALLs made from S-mode are handled here instead of trapping. The
+// extension id is passed in a7, the function id in a6 and arguments in a0-a5.
+// Calls return an error code in a0 and a value in a1, the legacy extensions
+// only return a value in a0.
+//
+// The console is the console UART's, output goes to its backend and input
+// comes from its receive queue, so it's shared with the guest's own driver.
+
+pub const SPEC_VERSION: rv32::Word = 2 << 24; // v2.0
+pub const IMPL_ID: rv32::Word = 0x52525553; // "RRUS", not a registered id
+pub const IMPL_VERSION: rv32::Word = 1;
+
+// Extension IDs
+pub const EXT_LEGACY_SET_TIMER: rv32::Word = 0x00;
+pub const EXT_LEGACY_CONSOLE_PUTCHAR: rv32::Word = 0x01;
+pub const EXT_LEGACY_CONSOLE_GETCHAR: rv32::Word = 0x02;
+pub const EXT_LEGACY_CLEAR_IPI: rv32::Word = 0x03;
+pub const EXT_LEGACY_SEND_IPI: rv32::Word = 0x04;
+pub const EXT_LEGACY_REMOTE_FENCE_I: rv32::Word = 0x05;
+pub const EXT_LEGACY_REMOTE_SFENCE_VMA: rv32::Word = 0x06;
+pub const EXT_LEGACY_REMOTE_SFENCE_VMA_ASID: rv32::Word = 0x07;
+pub const EXT_LEGACY_SHUTDOWN: rv32::Word = 0x08;
+pub const EXT_BASE: rv32::Word = 0x10;
+pub const EXT_TIME: rv32::Word = 0x54494d45; // "TIME"
+pub const EXT_IPI: rv32::Word = 0x735049; // "sPI"
+pub const EXT_RFENCE: rv32::Word = 0x52464e43; // "RFNC"
+pub const EXT_HSM: rv32::Word = 0x48534d; // "HSM"
+pub const EXT_SRST: rv32::Word = 0x53525354; // "SRST"
+pub const EXT_DBCN: rv32::Word = 0x4442434e; // "DBCN"
+
+// Standard return codes
+pub const SUCCESS: i32 = 0;
+pub const ERR_FAILED: i32 = -1;
+pub const ERR_NOT_SUPPORTED: i32 = -2;
+pub const ERR_INVALID_PARAM: i32 = -3;
+pub const ERR_INVALID_ADDRESS: i32 = -5;
+pub const ERR_ALREADY_AVAILABLE: i32 = -6;
+
+// HSM hart states
+const HSM_STARTED: rv32::Word = 0;
+const HSM_SUSPEND_RETENTIVE: rv32::Word = 0x00000000;
+const HSM_SUSPEND_NON_RETENTIVE: rv32::Word = 0x80000000;
+
+pub enum Outcome {
+    Return(i32, rv32::Word), // a0 = error, a1 = value
+    Legacy(rv32::Word),      // a0 = value
+    Resume,                  // the call set up pc and registers itself
+    Shutdown(String),        // stop the machine
+}
+
+fn is_supported(eid: rv32::Word) -> bool {
+    matches!(
+        eid,
+        EXT_LEGACY_SET_TIMER
+            ..=EXT_LEGACY_SHUTDOWN
+                | EXT_BASE
+                | EXT_TIME
+                | EXT_IPI
+                | EXT_RFENCE
+                | EXT_HSM
+                | EXT_SRST
+                | EXT_DBCN
+    )
+}
+
+// Handle an ECALL from S-mode, pc still points at the ECALL
+pub fn handle_ecall(state: &mut CPUState) -> Option<String> {
+    let eid = state.x[17];
+    let fid = state.x[16];
+    let outcome = match eid {
+        EXT_LEGACY_SET_TIMER..=EXT_LEGACY_SHUTDOWN => legacy(state, eid),
+        EXT_BASE => base(state, fid),
+        EXT_TIME => time(state, fid),
+        EXT_IPI => ipi(state, fid),
+        EXT_RFENCE => rfence(fid),
+        EXT_HSM => hsm(state, fid),
+        EXT_SRST => srst(state, fid),
+        EXT_DBCN => dbcn(state, fid),
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    };
+
+    match outcome {
+        Outcome::Return(error, value) => {
+            state.x[10] = error as rv32::Word;
+            state.x[11] = value;
+        }
+        Outcome::Legacy(value) => state.x[10] = value,
+        Outcome::Resume => return None,
+        Outcome::Shutdown(reason) => return Some(reason),
+    }
+    state.pc = state.pc.wrapping_add(rv32::WORD as u32);
+    None
+}
+
+fn set_timer(state: &mut CPUState, low: rv32::Word, high: rv32::Word) {
+    // the supervisor timer interrupt clears itself once mtime < mtimecmp
+    let mut bus = state.bus.borrow_mut();
+    if let Some(clint) = bus.device_mut::<clint::CLINT>() {
+        clint.set_mtimecmp((high as rv32::DoubleWord) << 32 | low as rv32::DoubleWord);
+    }
+}
+
+fn console_write(state: &mut CPUState, bytes: &[u8]) {
+    let mut bus = state.bus.borrow_mut();
+    if let Some(uart) = bus.device_mut::<uart::UART>() {
+        bytes.iter().for_each(|byte| uart.console_write(*byte));
+    }
+}
+
+fn console_read(state: &mut CPUState) -> Option<u8> {
+    let mut bus = state.bus.borrow_mut();
+    bus.device_mut::<uart::UART>()?.console_read()
+}
+
+// We have a single hart, hart 0. A mask base of -1 means every hart
+fn targets_hart0(hart_mask: rv32::Word, hart_mask_base: rv32::Word) -> Result<bool, i32> {
+    if hart_mask_base == rv32::Word::MAX {
+        return Ok(true);
+    }
+    if hart_mask_base != 0 || hart_mask & !1 != 0 {
+        return Err(ERR_INVALID_PARAM);
+    }
+    Ok(hart_mask & 1 != 0)
+}
+
+fn legacy(state: &mut CPUState, eid: rv32::Word) -> Outcome {
+    match eid {
+        EXT_LEGACY_SET_TIMER => {
+            set_timer(state, state.x[10], state.x[11]);
+            Outcome::Legacy(0)
+        }
+        EXT_LEGACY_CONSOLE_PUTCHAR => {
+            console_write(state, &[state.x[10] as u8]);
+            Outcome::Legacy(0)
+        }
+        EXT_LEGACY_CONSOLE_GETCHAR => match console_read(state) {
+            Some(byte) => Outcome::Legacy(byte as rv32::Word),
+            None => Outcome::Legacy(ERR_FAILED as rv32::Word),
+        },
+        EXT_LEGACY_CLEAR_IPI => {
+            state.mip &= !MIP_SSIP;
+            Outcome::Legacy(0)
+        }
+        EXT_LEGACY_SEND_IPI => {
+            // a0 points at the hart mask in memory
+            let hart_mask = state.bus.borrow_mut().load_32(state.x[10]);
+            match hart_mask {
+                Ok(mask) => {
+                    if mask & 1 != 0 {
+                        state.mip |= MIP_SSIP;
+                    }
+                    Outcome::Legacy(0)
+                }
+                Err(_) => Outcome::Legacy(ERR_INVALID_ADDRESS as rv32::Word),
+            }
+        }
+        EXT_LEGACY_REMOTE_FENCE_I
+        | EXT_LEGACY_REMOTE_SFENCE_VMA
+        | EXT_LEGACY_REMOTE_SFENCE_VMA_ASID => Outcome::Legacy(0),
+        _ => Outcome::Shutdown("legacy shutdown".to_string()), // EXT_LEGACY_SHUTDOWN
+    }
+}
+
+fn base(state: &mut CPUState, fid: rv32::Word) -> Outcome {
+    match fid {
+        0 => Outcome::Return(SUCCESS, SPEC_VERSION),
+        1 => Outcome::Return(SUCCESS, IMPL_ID),
+        2 => Outcome::Return(SUCCESS, IMPL_VERSION),
+        3 => Outcome::Return(SUCCESS, is_supported(state.x[10]) as rv32::Word),
+        4 => Outcome::Return(SUCCESS, state.mvendorid),
+        5 => Outcome::Return(SUCCESS, state.marchid),
+        6 => Outcome::Return(SUCCESS, state.mimpid),
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn time(state: &mut CPUState, fid: rv32::Word) -> Outcome {
+    match fid {
+        0 => {
+            // on rv32 the 64 bit stime_value is split across a0 and a1
+            set_timer(state, state.x[10], state.x[11]);
+            Outcome::Return(SUCCESS, 0)
+        }
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn ipi(state: &mut CPUState, fid: rv32::Word) -> Outcome {
+    match fid {
+        0 => match targets_hart0(state.x[10], state.x[11]) {
+            Ok(hart0) => {
+                if hart0 {
+                    state.mip |= MIP_SSIP;
+                }
+                Outcome::Return(SUCCESS, 0)
+            }
+            Err(error) => Outcome::Return(error, 0),
+        },
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+// With one hart, no TLB and no instruction cache every remote fence is a
+// no-op. The hypervisor fences need the H extension, which we don't have
+fn rfence(fid: rv32::Word) -> Outcome {
+    match fid {
+        0..=2 => Outcome::Return(SUCCESS, 0),
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn hsm(state: &mut CPUState, fid: rv32::Word) -> Outcome {
+    let hartid = state.x[10];
+    match fid {
+        // hart_start, the only hart is already running
+        0 if hartid == state.mhartid => Outcome::Return(ERR_ALREADY_AVAILABLE, 0),
+        0 => Outcome::Return(ERR_INVALID_PARAM, 0),
+        // hart_stop, stopping the last hart leaves nothing to run
+        1 => Outcome::Shutdown("the last hart was stopped".to_string()),
+        // hart_get_status
+        2 if hartid == state.mhartid => Outcome::Return(SUCCESS, HSM_STARTED),
+        2 => Outcome::Return(ERR_INVALID_PARAM, 0),
+        // hart_suspend, both kinds wait for an interrupt
+        3 => match state.x[10] {
+            HSM_SUSPEND_RETENTIVE => {
+                state.extraflags |= 4;
+                Outcome::Return(SUCCESS, 0)
+            }
+            HSM_SUSPEND_NON_RETENTIVE => {
+                // resume at resume_addr in S-mode with a0 = hartid, a1 = opaque
+                state.extraflags |= 4;
+                state.mstatus &= !super::csr::MSTATUS_SIE;
+                state.pc = state.x[11];
+                state.x[10] = state.mhartid;
+                state.x[11] = state.x[12];
+                Outcome::Resume
+            }
+            _ => Outcome::Return(ERR_INVALID_PARAM, 0),
+        },
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn srst(state: &mut CPUState, fid: rv32::Word) -> Outcome {
+    if fid != 0 {
+        return Outcome::Return(ERR_NOT_SUPPORTED, 0);
+    }
+    let reason = match state.x[11] {
+        0 => "no reason",
+        1 => "system failure",
+        _ => "vendor reason",
+    };
+    match state.x[10] {
+        0 => Outcome::Shutdown(format!("shutdown ({})", reason)),
+        // we have nothing to reset to, so treat reboots as a shutdown
+        1 => Outcome::Shutdown(format!("cold reboot ({})", reason)),
+        2 => Outcome::Shutdown(format!("warm reboot ({})", reason)),
+        _ => Outcome::Return(ERR_INVALID_PARAM, 0),
+    }
+}
+
+fn dbcn(state: &mut CPUState, fid: rv32::Word) -> Outcome {
+    let num_bytes = state.x[10];
+    let (base_lo, base_hi) = (state.x[11], state.x[12]);
+    match fid {
+        // console_write
+        0 => {
+            if base_hi != 0 || base_lo.checked_add(num_bytes).is_none() {
+                return Outcome::Return(ERR_INVALID_ADDRESS, 0);
+            }
+            let buffer = {
+                let mut bus = state.bus.borrow_mut();
+                // the buffer is almost always in RAM, which we can take directly
+                match bus.ram_slices(base_lo, num_bytes) {
+                    Some(buffer) => buffer.concat(),
+                    None => {
+                        let mut buffer = vec![0; num_bytes as usize];
+                        if bus.read_bytes(base_lo, &mut buffer).is_err() {
+                            return Outcome::Return(ERR_INVALID_ADDRESS, 0);
+                        }
+                        buffer
+                    }
+                }
+            };
+            console_write(state, &buffer);
+            Outcome::Return(SUCCESS, num_bytes)
+        }
+        // console_read, whatever has arrived up to num_bytes, never waits
+        1 => {
+            if base_hi != 0 || base_lo.checked_add(num_bytes).is_none() {
+                return Outcome::Return(ERR_INVALID_ADDRESS, 0);
+            }
+            let mut buffer = Vec::new();
+            while buffer.len() < num_bytes as usize {
+                match console_read(state) {
+                    Some(byte) => buffer.push(byte),
+                    None => break,
+                }
+            }
+            if state
+                .bus
+                .borrow_mut()
+                .write_bytes(base_lo, &buffer)
+                .is_err()
+            {
+                return Outcome::Return(ERR_INVALID_ADDRESS, 0);
+            }
+            Outcome::Return(SUCCESS, buffer.len() as rv32::Word)
+        }
+        // console_write_byte
+        2 => {
+            console_write(state, &[num_bytes as u8]);
+            Outcome::Return(SUCCESS, 0)
+        }
+        _ => Outcome::Return(ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::ext::decode::DecodeCycle;
+    use crate::system::bus::Bus;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn cpu() -> CPU {
+        let bus = Rc::new(RefCell::new(Bus::new(0x80000000, 0x10000).unwrap()));
+        let decoder = Rc::new(RefCell::new(DecodeCycle::new(vec!['i'])));
+        let mut cpu = CPU::new(bus, decoder, vec!['i']);
+        cpu.state.pc = 0x80000000;
+        cpu
+    }
+
+    // Make a call, giving the machine's reason if it stopped
+    fn call(
+        cpu: &mut CPU,
+        eid: rv32::Word,
+        fid: rv32::Word,
+        args: &[rv32::Word],
+    ) -> Option<String> {
+        cpu.state.x[17] = eid;
+        cpu.state.x[16] = fid;
+        cpu.state.x[10..10 + args.len()].copy_from_slice(args);
+        handle_ecall(&mut cpu.state)
+    }
+
+    fn error(cpu: &CPU) -> i32 {
+        cpu.state.x[10] as i32
+    }
+
+    #[test]
+    fn unknown_calls_are_not_supported() {
+        let mut cpu = cpu();
+        assert_eq!(call(&mut cpu, 0x0a000000, 0, &[]), None);
+        assert_eq!(error(&cpu), ERR_NOT_SUPPORTED);
+        // the ECALL is done with
+        assert_eq!(cpu.state.pc, 0x80000004);
+
+        for (eid, fid) in [
+            (EXT_BASE, 7),
+            (EXT_TIME, 1),
+            (EXT_HSM, 4),
+            (EXT_SRST, 1),
+            (EXT_DBCN, 3),
+        ] {
+            call(&mut cpu, eid, fid, &[]);
+            assert_eq!(
+                error(&cpu),
+                ERR_NOT_SUPPORTED,
+                "eid 0x{:x} fid {}",
+                eid,
+                fid
+            );
+        }
+
+        call(&mut cpu, EXT_BASE, 3, &[EXT_HSM]);
+        assert_eq!((error(&cpu), cpu.state.x[11]), (SUCCESS, 1));
+        call(&mut cpu, EXT_BASE, 3, &[0x0a000000]);
+        assert_eq!((error(&cpu), cpu.state.x[11]), (SUCCESS, 0));
+    }
+
+    #[test]
+    fn bad_parameters_are_invalid() {
+        let mut cpu = cpu();
+        // there's only hart 0
+        call(&mut cpu, EXT_IPI, 0, &[1, 1]);
+        assert_eq!(error(&cpu), ERR_INVALID_PARAM);
+        call(&mut cpu, EXT_IPI, 0, &[2, 0]);
+        assert_eq!(error(&cpu), ERR_INVALID_PARAM);
+        call(&mut cpu, EXT_HSM, 0, &[1]);
+        assert_eq!(error(&cpu), ERR_INVALID_PARAM);
+        call(&mut cpu, EXT_HSM, 2, &[1]);
+        assert_eq!(error(&cpu), ERR_INVALID_PARAM);
+        call(&mut cpu, EXT_HSM, 3, &[1]);
+        assert_eq!(error(&cpu), ERR_INVALID_PARAM);
+        call(&mut cpu, EXT_SRST, 0, &[3, 0]);
+        assert_eq!(error(&cpu), ERR_INVALID_PARAM);
+        // a buffer above 4GiB
+        call(&mut cpu, EXT_DBCN, 0, &[1, 0x80000000, 1]);
+        assert_eq!(error(&cpu), ERR_INVALID_ADDRESS);
+
+        // a mask base of -1 is every hart
+        call(&mut cpu, EXT_IPI, 0, &[0, rv32::Word::MAX]);
+        assert_eq!(error(&cpu), SUCCESS);
+        assert_ne!(cpu.state.mip & MIP_SSIP, 0);
+    }
+
+    #[test]
+    fn hart_state_management() {
+        let mut cpu = cpu();
+        call(&mut cpu, EXT_HSM, 0, &[0]);
+        assert_eq!(error(&cpu), ERR_ALREADY_AVAILABLE);
+        call(&mut cpu, EXT_HSM, 2, &[0]);
+        assert_eq!((error(&cpu), cpu.state.x[11]), (SUCCESS, HSM_STARTED));
+
+        // a retentive suspend carries on after the ECALL once woken
+        let pc = cpu.state.pc;
+        call(&mut cpu, EXT_HSM, 3, &[HSM_SUSPEND_RETENTIVE]);
+        assert_eq!(error(&cpu), SUCCESS);
+        assert_ne!(cpu.state.extraflags & 4, 0);
+        assert_eq!(cpu.state.pc, pc + 4);
+
+        // a non retentive one resumes at resume_addr with a1 = opaque
+        cpu.state.extraflags &= !4;
+        call(
+            &mut cpu,
+            EXT_HSM,
+            3,
+            &[HSM_SUSPEND_NON_RETENTIVE, 0x80001000, 0x1234],
+        );
+        assert_ne!(cpu.state.extraflags & 4, 0);
+        assert_eq!(cpu.state.pc, 0x80001000);
+        assert_eq!((cpu.state.x[10], cpu.state.x[11]), (0, 0x1234));
+
+        assert!(call(&mut cpu, EXT_HSM, 1, &[]).is_some());
+    }
+
+    #[test]
+    fn system_reset() {
+        let mut cpu = cpu();
+        assert_eq!(
+            call(&mut cpu, EXT_SRST, 0, &[0, 0]),
+            Some("shutdown (no reason)".to_string())
+        );
+        assert_eq!(
+            call(&mut cpu, EXT_SRST, 0, &[1, 1]),
+            Some("cold reboot (system failure)".to_string())
+        );
+        assert_eq!(
+            call(&mut cpu, EXT_SRST, 0, &[2, 0xf0000000]),
+            Some("warm reboot (vendor reason)".to_string())
+        );
+        assert_eq!(
+            call(&mut cpu, EXT_LEGACY_SHUTDOWN, 0, &[]),
+            Some("legacy shutdown".to_string())
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use crate::system::rv32;
+
+// Sdtrig - Debug trigger module
+//
+// We implement a small bank of triggers which can each be configured
+// (through tdata1) as either an mcontrol6 address / data match trigger
+// or an icount single step trigger. Anything else written to tdata1
+// turns the trigger into the "disabled" type.
+//
+// mcontrol6 (type 6)
+//  31..28 type | 27 dmode | 26 uncertain | 25 hit1 | 24 vs | 23 vu
+//  22 hit0 | 21 select | 18..16 size | 15..12 action | 11 chain
+//  10..7 match | 6 m | 5 uncertainen | 4 s | 3 u | 2 execute | 1 store | 0 load
+//
+// icount (type 3)
+//  31..28 type | 27 dmode | 26 vs | 25 vu | 24 hit | 23..10 count
+//  9 m | 8 pending | 7 s | 6 u | 5..0 action
+
+pub const TRIGGER_COUNT: usize = 4;
+
+pub const TYPE_ICOUNT: rv32::Word = 3;
+pub const TYPE_MCONTROL6: rv32::Word = 6;
+pub const TYPE_DISABLED: rv32::Word = 15;
+
+const TYPE_SHIFT: rv32::Word = 28;
+const DMODE: rv32::Word = 1 << 27;
+
+const MC_HIT0: rv32::Word = 1 << 22;
+const MC_SELECT: rv32::Word = 1 << 21;
+const MC_ACTION_SHIFT: rv32::Word = 12;
+const MC_MATCH_SHIFT: rv32::Word = 7;
+const MC_M: rv32::Word = 1 << 6;
+const MC_S: rv32::Word = 1 << 4;
+const MC_U: rv32::Word = 1 << 3;
+const MC_EXECUTE: rv32::Word = 1 << 2;
+const MC_STORE: rv32::Word = 1 << 1;
+const MC_LOAD: rv32::Word = 1 << 0;
+
+const IC_HIT: rv32::Word = 1 << 24;
+const IC_COUNT_SHIFT: rv32::Word = 10;
+const IC_COUNT_MASK: rv32::Word = 0x3fff;
+const IC_M: rv32::Word = 1 << 9;
+const IC_PENDING: rv32::Word = 1 << 8;
+const IC_S: rv32::Word = 1 << 7;
+const IC_U: rv32::Word = 1 << 6;
+const IC_ACTION_MASK: rv32::Word = 0x3f;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Access {
+    Execute,
+    Load,
+    Store,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TriggerAction {
+    Breakpoint, // raise a breakpoint exception
+    DebugMode,  // halt the hart in debug mode
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Trigger {
+    pub tdata1: rv32::Word,
+    pub tdata2: rv32::Word,
+    pub tdata3: rv32::Word,
+}
+
+impl Trigger {
+    fn new() -> Trigger {
+        Trigger {
+            tdata1: TYPE_DISABLED << TYPE_SHIFT,
+            tdata2: 0,
+            tdata3: 0,
+        }
+    }
+
+    fn kind(&self) -> rv32::Word {
+        self.tdata1 >> TYPE_SHIFT
+    }
+
+    fn action(&self) -> Option<TriggerAction> {
+        let action = match self.kind() {
+            TYPE_MCONTROL6 => (self.tdata1 >> MC_ACTION_SHIFT) & 0xf,
+            TYPE_ICOUNT => self.tdata1 & IC_ACTION_MASK,
+            _ => return None,
+        };
+        match action {
+            0 => Some(TriggerAction::Breakpoint),
+            1 => Some(TriggerAction::DebugMode),
+            _ => None,
+        }
+    }
+
+    // Check the m/s/u enable bits against the privilege level
+    // (Machine = 3, Supervisor = 1, User = 0)
+    fn enabled_in(
+        &self,
+        privilege: rv32::Word,
+        m: rv32::Word,
+        s: rv32::Word,
+        u: rv32::Word,
+    ) -> bool {
+        match privilege {
+            3 => self.tdata1 & m != 0,
+            1 => self.tdata1 & s != 0,
+            0 => self.tdata1 & u != 0,
+            _ => false,
+        }
+    }
+
+    fn compare(&self, value: rv32::Word) -> bool {
+        let tdata2 = self.tdata2;
+        let kind = (self.tdata1 >> MC_MATCH_SHIFT) & 0xf;
+        let hit = match kind & 0x7 {
+            0 => value == tdata2,
+            1 => {
+                // napot, the trailing ones of tdata2 are don't care
+                let care = !(tdata2 ^ tdata2.wrapping_add(1));
+                value & care == tdata2 & care
+            }
+            2 => value >= tdata2,
+            3 => value < tdata2,
+            4 => (value & 0xffff) & (tdata2 >> 16) == tdata2 & 0xffff,
+            5 => (value >> 16) & (tdata2 >> 16) == tdata2 & 0xffff,
+            _ => false,
+        };
+        // match values 8 and up are the negated forms of 0 - 5
+        if kind & 0x8 != 0 {
+            !hit
+        } else {
+            hit
+        }
+    }
+}
+
+pub struct TriggerModule {
+    tselect: usize,
+    triggers: [Trigger; TRIGGER_COUNT],
+    suppress: bool,
+}
+
+impl TriggerModule {
+    pub fn new() -> TriggerModule {
+        TriggerModule {
+            tselect: 0,
+            triggers: [Trigger::new(); TRIGGER_COUNT],
+            suppress: false,
+        }
+    }
+
+    pub fn tselect(&self) -> rv32::Word {
+        self.tselect as rv32::Word
+    }
+
+    pub fn write_tselect(&mut self, value: rv32::Word) {
+        // WARL, selecting a trigger that doesn't exist is ignored
+        if (value as usize) < TRIGGER_COUNT {
+            self.tselect = value as usize;
+        }
+    }
+
+    // Version 1 of the spec, supports icount, mcontrol6 and disabled
+    pub fn tinfo(&self) -> rv32::Word {
+        (1 << 24) | (1 << TYPE_ICOUNT) | (1 << TYPE_MCONTROL6) | (1 << TYPE_DISABLED)
+    }
+
+    pub fn tdata(&self, index: usize) -> rv32::Word {
+        let trigger = &self.triggers[self.tselect];
+        match index {
+            1 => trigger.tdata1,
+            2 => trigger.tdata2,
+            3 => trigger.tdata3,
+            _ => 0,
+        }
+    }
+
+    pub fn write_tdata(&mut self, index: usize, value: rv32::Word, debug_mode: bool) {
+        let trigger = &mut self.triggers[self.tselect];
+        // triggers owned by the debugger are only writeable from debug mode
+        if trigger.tdata1 & DMODE != 0 && !debug_mode {
+            return;
+        }
+        match index {
+            1 => trigger.tdata1 = Self::legalise_tdata1(value, debug_mode),
+            2 => trigger.tdata2 = value,
+            3 => trigger.tdata3 = value,
+            _ => (),
+        }
+    }
+
+    fn legalise_tdata1(value: rv32::Word, debug_mode: bool) -> rv32::Word {
+        let mut value = value;
+        if !debug_mode {
+            value &= !DMODE;
+        }
+        match value >> TYPE_SHIFT {
+            TYPE_MCONTROL6 => {
+                // only action 0 and 1 are supported, and entering debug mode
+                // is only allowed for triggers owned by the debugger
+                let action = (value >> MC_ACTION_SHIFT) & 0xf;
+                if action > 1 || (action == 1 && value & DMODE == 0) {
+                    value &= !(0xf << MC_ACTION_SHIFT);
+                }
+                // we have no chaining, no size matching and no virtualisation
+                value & !((1 << 11) | (0x7 << 16) | (0x3 << 23) | (0x3 << 25))
+            }
+            TYPE_ICOUNT => {
+                let action = value & IC_ACTION_MASK;
+                if action > 1 || (action == 1 && value & DMODE == 0) {
+                    value &= !IC_ACTION_MASK;
+                }
+                value & !(0x3 << 25)
+            }
+            _ => TYPE_DISABLED << TYPE_SHIFT,
+        }
+    }
+
+    // Skip all trigger matching for the next instruction, used when
+    // resuming from debug mode so we don't immediately hit the same trigger
+    pub fn suppress_once(&mut self) {
+        self.suppress = true;
+    }
+
+    pub fn clear_suppress(&mut self) {
+        self.suppress = false;
+    }
+
+    // Match an execute, load or store address against all mcontrol6 triggers.
+    // Breakpoint exceptions are not raised in machine mode while mstatus.MIE
+    // is clear, otherwise a trap handler would trigger itself forever
+    pub fn match_address(
+        &mut self,
+        access: Access,
+        address: rv32::Word,
+        privilege: rv32::Word,
+        mie: bool,
+    ) -> Option<TriggerAction> {
+        self.match_mcontrol6(access, address, false, privilege, mie)
+    }
+
+    pub fn match_data(
+        &mut self,
+        access: Access,
+        data: rv32::Word,
+        privilege: rv32::Word,
+        mie: bool,
+    ) -> Option<TriggerAction> {
+        self.match_mcontrol6(access, data, true, privilege, mie)
+    }
+
+    fn match_mcontrol6(
+        &mut self,
+        access: Access,
+        value: rv32::Word,
+        select: bool,
+        privilege: rv32::Word,
+        mie: bool,
+    ) -> Option<TriggerAction> {
+        if self.suppress {
+            return None;
+        }
+        let access_bit = match access {
+            Access::Execute => MC_EXECUTE,
+            Access::Load => MC_LOAD,
+            Access::Store => MC_STORE,
+        };
+        for trigger in self.triggers.iter_mut() {
+            if trigger.kind() != TYPE_MCONTROL6
+                || trigger.tdata1 & access_bit == 0
+                || (trigger.tdata1 & MC_SELECT != 0) != select
+                || !trigger.enabled_in(privilege, MC_M, MC_S, MC_U)
+                || !trigger.compare(value)
+            {
+                continue;
+            }
+            let action = trigger.action();
+            if action == Some(TriggerAction::Breakpoint) && privilege == 3 && !mie {
+                continue;
+            }
+            trigger.tdata1 |= MC_HIT0;
+            return action;
+        }
+        None
+    }
+
+    // Called after every retired instruction, counts down icount triggers
+    // and marks them pending once they reach zero
+    pub fn retire(&mut self, privilege: rv32::Word) {
+        for trigger in self.triggers.iter_mut() {
+            if trigger.kind() != TYPE_ICOUNT || !trigger.enabled_in(privilege, IC_M, IC_S, IC_U) {
+                continue;
+            }
+            let count = (trigger.tdata1 >> IC_COUNT_SHIFT) & IC_COUNT_MASK;
+            if count == 0 {
+                continue;
+            }
+            trigger.tdata1 &= !(IC_COUNT_MASK << IC_COUNT_SHIFT);
+            trigger.tdata1 |= (count - 1) << IC_COUNT_SHIFT;
+            if count == 1 {
+                trigger.tdata1 |= IC_PENDING;
+            }
+        }
+    }
+
+    // Pending icount triggers fire before the next instruction executes
+    pub fn take_pending(&mut self) -> Option<TriggerAction> {
+        for trigger in self.triggers.iter_mut() {
+            if trigger.kind() == TYPE_ICOUNT && trigger.tdata1 & IC_PENDING != 0 {
+                trigger.tdata1 &= !IC_PENDING;
+                trigger.tdata1 |= IC_HIT;
+                return trigger.action();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mcontrol6(kind: rv32::Word, bits: rv32::Word) -> rv32::Word {
+        (TYPE_MCONTROL6 << TYPE_SHIFT) | (kind << MC_MATCH_SHIFT) | bits
+    }
+
+    fn set(module: &mut TriggerModule, tdata1: rv32::Word, tdata2: rv32::Word) {
+        module.write_tdata(1, tdata1, false);
+        module.write_tdata(2, tdata2, false);
+    }
+
+    fn loads(module: &mut TriggerModule, address: rv32::Word) -> bool {
+        module
+            .match_address(Access::Load, address, 0, false)
+            .is_some()
+    }
+
+    #[test]
+    fn match_kinds() {
+        let mut module = TriggerModule::new();
+        // napot, 8 trailing ones is a 512 byte range
+        set(&mut module, mcontrol6(1, MC_LOAD | MC_U), 0x800000ff);
+        assert!(loads(&mut module, 0x80000000));
+        assert!(loads(&mut module, 0x800001ff));
+        assert!(!loads(&mut module, 0x80000200));
+        assert!(!loads(&mut module, 0x7fffffff));
+
+        set(&mut module, mcontrol6(2, MC_LOAD | MC_U), 0x1000);
+        assert!(loads(&mut module, 0x1000));
+        assert!(!loads(&mut module, 0xfff));
+        set(&mut module, mcontrol6(3, MC_LOAD | MC_U), 0x1000);
+        assert!(loads(&mut module, 0xfff));
+        assert!(!loads(&mut module, 0x1000));
+
+        // the mask is the top half of tdata2, the value the bottom
+        set(&mut module, mcontrol6(4, MC_LOAD | MC_U), 0x00ff0034);
+        assert!(loads(&mut module, 0x12345634));
+        assert!(!loads(&mut module, 0x12345635));
+        set(&mut module, mcontrol6(5, MC_LOAD | MC_U), 0xff001200);
+        assert!(loads(&mut module, 0x12345678));
+        assert!(!loads(&mut module, 0x13345678));
+
+        // negated equal
+        set(&mut module, mcontrol6(8, MC_LOAD | MC_U), 0x1000);
+        assert!(!loads(&mut module, 0x1000));
+        assert!(loads(&mut module, 0x1004));
+        assert_ne!(module.tdata(1) & MC_HIT0, 0);
+
+        // only the enabled access types and privileges
+        set(&mut module, mcontrol6(0, MC_STORE | MC_U), 0x1000);
+        assert!(!loads(&mut module, 0x1000));
+        set(&mut module, mcontrol6(0, MC_LOAD | MC_S), 0x1000);
+        assert!(!loads(&mut module, 0x1000));
+    }
+
+    #[test]
+    fn icount_counts_down() {
+        let mut module = TriggerModule::new();
+        module.write_tdata(
+            1,
+            (TYPE_ICOUNT << TYPE_SHIFT) | (2 << IC_COUNT_SHIFT) | IC_U,
+            false,
+        );
+        // machine mode isn't counted
+        module.retire(3);
+        module.retire(0);
+        assert_eq!(module.take_pending(), None);
+        module.retire(0);
+        assert_eq!(module.take_pending(), Some(TriggerAction::Breakpoint));
+        let tdata1 = module.tdata(1);
+        assert_eq!((tdata1 >> IC_COUNT_SHIFT) & IC_COUNT_MASK, 0);
+        assert_ne!(tdata1 & IC_HIT, 0);
+        // it fires once, then stays at zero
+        module.retire(0);
+        assert_eq!(module.take_pending(), None);
+    }
+
+    #[test]
+    fn no_breakpoints_in_machine_mode_without_mie() {
+        let mut module = TriggerModule::new();
+        set(&mut module, mcontrol6(0, MC_EXECUTE | MC_M), 0x80000000);
+        assert_eq!(
+            module.match_address(Access::Execute, 0x80000000, 3, false),
+            None
+        );
+        assert_eq!(module.tdata(1) & MC_HIT0, 0);
+        assert_eq!(
+            module.match_address(Access::Execute, 0x80000000, 3, true),
+            Some(TriggerAction::Breakpoint)
+        );
+
+        // and nothing at all for the instruction after resuming
+        module.suppress_once();
+        assert_eq!(
+            module.match_address(Access::Execute, 0x80000000, 3, true),
+            None
+        );
+        module.clear_suppress();
+        assert!(module
+            .match_address(Access::Execute, 0x80000000, 3, true)
+            .is_some());
+    }
+}
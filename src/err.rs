@@ -1,3 +1,22 @@
+use std::fmt;
+
+// Why the VM couldn't be set up the way it was asked to be, main prints it
+// once and exits non-zero
+#[derive(Debug)]
+pub enum VMError {
+    Argument(String, String), // the option with its value, and what's wrong with it
+    Setup(String, String),    // what was being done, and why it failed
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMError::Argument(option, e) => write!(f, "Bad {}: {}", option, e),
+            VMError::Setup(what, e) => write!(f, "Failed to {}: {}", what, e),
+        }
+    }
+}
+
 // #[derive(Debug, Copy, Clone)]
 // pub enum Trap {
 //     Exception(Exception),
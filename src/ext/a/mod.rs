@@ -1,5 +1,3 @@
-use std::usize;
-
 use bits::match_mask;
 use enum_dispatch::*;
 use strum::EnumIter;
@@ -7,11 +5,24 @@ use strum::EnumIter;
 use super::encoding::{GenInstruction, Instruction};
 use crate::cpu;
 use crate::helpers::sext;
+use crate::system::pma::AmoClass;
 use crate::system::rv32;
 
 // FOR BRANCH INSTRUCTIONS ITS IMPERATIVE TO REMEMBER
 // THAT WE INCREMENT PC AFTER THE EXECUTION
 
+// The reservation lives in extraflags above the privilege and WFI bits,
+// an SC gives it up whether it succeeds or not. LR/SC are word aligned so
+// an odd address never matches
+const NO_RESERVATION: rv32::Word = 1 << 3;
+
+fn check_aligned(address: rv32::XLen) -> Result<(), String> {
+    match address % 4 {
+        0 => Ok(()),
+        _ => Err(format!("VM > Misaligned LR/SC at 0x{:08x}", address)),
+    }
+}
+
 #[derive(Default, Copy, Clone)]
 pub struct LRW; // LR.W rd, rs1 - Load Reserved Word
                 // Load a word from memory into rd
@@ -29,8 +40,19 @@ impl Instruction for LRW {
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
         let inst = unsafe { inst.R };
         let rs1 = state.x[inst.rs1() as usize];
-        state.extraflags = (state.extraflags & 0x07) | (rs1 << 3);
-        state.x[inst.rd() as usize] = sext(state.bus.borrow_mut().load_32(rs1), 32);
+        let loaded = {
+            let mut bus = state.bus.borrow_mut();
+            check_aligned(rs1)
+                .and_then(|_| bus.check_lrsc(rs1))
+                .and_then(|_| bus.load_32(rs1))
+        };
+        match loaded {
+            Ok(value) => {
+                state.extraflags = (state.extraflags & 0x07) | (rs1 << 3);
+                state.x[inst.rd() as usize] = sext(value, 32);
+            }
+            Err(e) => state.access_fault(5, rs1, e), // load access fault
+        }
     }
 }
 
@@ -51,19 +73,229 @@ impl Instruction for SCW {
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
         let inst = unsafe { inst.R };
         let rs1 = state.x[inst.rs1() as usize];
+        // faults even when the reservation has gone, it couldn't have held one
+        let reservable = check_aligned(rs1).and_then(|_| state.bus.borrow().check_lrsc(rs1));
+        if let Err(e) = reservable {
+            state.access_fault(7, rs1, e); // store access fault
+            return;
+        }
         let write_flag = (state.extraflags >> 3) == (rs1 & 0x1fffffff);
+        state.extraflags = (state.extraflags & 0x07) | NO_RESERVATION;
         if write_flag {
-            state.bus.borrow_mut().store_32(rs1, state.x[inst.rs2() as usize]);
-            state.x[inst.rd() as usize] = 0;
+            let stored = state
+                .bus
+                .borrow_mut()
+                .store_32(rs1, state.x[inst.rs2() as usize]);
+            match stored {
+                Ok(_) => state.x[inst.rd() as usize] = 0,
+                Err(e) => state.access_fault(7, rs1, e), // store access fault
+            }
         } else {
             state.x[inst.rd() as usize] = 1;
         }
     }
 }
 
+// Every AMO is a read, an operation and a write of the result, rd gets
+// the value from before. The region has to support the class of AMO, any
+// failure is a store/AMO access fault, even if it was the read that failed
+fn amo(
+    inst: GenInstruction,
+    state: &mut cpu::CPUState,
+    class: AmoClass,
+    op: fn(rv32::Word, rv32::Word) -> rv32::Word,
+) {
+    let inst = unsafe { inst.R };
+    let address = state.x[inst.rs1() as usize];
+    let rs2 = state.x[inst.rs2() as usize];
+    let result = {
+        let mut bus = state.bus.borrow_mut();
+        match address % 4 {
+            0 => bus.check_amo(address, class),
+            _ => Err(format!("VM > Misaligned AMO at 0x{:08x}", address)),
+        }
+        .and_then(|_| bus.load_32(address))
+        .and_then(|loaded| bus.store_32(address, op(loaded, rs2)).map(|_| loaded))
+    };
+    match result {
+        Ok(loaded) => state.x[inst.rd() as usize] = loaded,
+        Err(e) => state.access_fault(7, address, e), // store/AMO access fault
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOSWAPW; // AMOSWAP.W rd, rs2, (rs1) - Atomic Swap Word
+                     // rd = mem[rs1], mem[rs1] = rs2
+impl Instruction for AMOSWAPW {
+    fn name(&self) -> &'static str {
+        "AMOSWAP.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "00001xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Swap, |_, b| b);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOADDW; // AMOADD.W rd, rs2, (rs1) - Atomic Add Word
+                    // rd = mem[rs1], mem[rs1] += rs2
+impl Instruction for AMOADDW {
+    fn name(&self) -> &'static str {
+        "AMOADD.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "00000xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Arithmetic, |a, b| a.wrapping_add(b));
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOXORW; // AMOXOR.W rd, rs2, (rs1) - Atomic Xor Word
+                    // rd = mem[rs1], mem[rs1] ^= rs2
+impl Instruction for AMOXORW {
+    fn name(&self) -> &'static str {
+        "AMOXOR.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "00100xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Logical, |a, b| a ^ b);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOANDW; // AMOAND.W rd, rs2, (rs1) - Atomic And Word
+                    // rd = mem[rs1], mem[rs1] &= rs2
+impl Instruction for AMOANDW {
+    fn name(&self) -> &'static str {
+        "AMOAND.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "01100xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Logical, |a, b| a & b);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOORW; // AMOOR.W rd, rs2, (rs1) - Atomic Or Word
+                   // rd = mem[rs1], mem[rs1] |= rs2
+impl Instruction for AMOORW {
+    fn name(&self) -> &'static str {
+        "AMOOR.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "01000xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Logical, |a, b| a | b);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOMINW; // AMOMIN.W rd, rs2, (rs1) - Atomic Minimum Word
+                    // rd = mem[rs1], mem[rs1] = min(mem[rs1], rs2)
+impl Instruction for AMOMINW {
+    fn name(&self) -> &'static str {
+        "AMOMIN.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "10000xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Arithmetic, |a, b| {
+            std::cmp::min(a as i32, b as i32) as rv32::Word
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOMAXW; // AMOMAX.W rd, rs2, (rs1) - Atomic Maximum Word
+                    // rd = mem[rs1], mem[rs1] = max(mem[rs1], rs2)
+impl Instruction for AMOMAXW {
+    fn name(&self) -> &'static str {
+        "AMOMAX.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "10100xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Arithmetic, |a, b| {
+            std::cmp::max(a as i32, b as i32) as rv32::Word
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOMINUW; // AMOMINU.W rd, rs2, (rs1) - Atomic Minimum Unsigned Word
+                     // rd = mem[rs1], mem[rs1] = minu(mem[rs1], rs2)
+impl Instruction for AMOMINUW {
+    fn name(&self) -> &'static str {
+        "AMOMINU.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "11000xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Arithmetic, |a, b| {
+            std::cmp::min(a, b)
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct AMOMAXUW; // AMOMAXU.W rd, rs2, (rs1) - Atomic Maximum Unsigned Word
+                     // rd = mem[rs1], mem[rs1] = maxu(mem[rs1], rs2)
+impl Instruction for AMOMAXUW {
+    fn name(&self) -> &'static str {
+        "AMOMAXU.W"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "11100xxxxxxxxxxxx010xxxxx0101111")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        amo(inst, state, AmoClass::Arithmetic, |a, b| {
+            std::cmp::max(a, b)
+        });
+    }
+}
+
 #[derive(EnumIter)]
 #[enum_dispatch(Instruction)]
 pub enum ExtensionA {
     LRW(LRW),
     SCW(SCW),
+    AMOSWAPW(AMOSWAPW),
+    AMOADDW(AMOADDW),
+    AMOXORW(AMOXORW),
+    AMOANDW(AMOANDW),
+    AMOORW(AMOORW),
+    AMOMINW(AMOMINW),
+    AMOMAXW(AMOMAXW),
+    AMOMINUW(AMOMINUW),
+    AMOMAXUW(AMOMAXUW),
 }
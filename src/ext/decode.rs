@@ -1,6 +1,6 @@
 use strum::IntoEnumIterator;
 
-use super::encoding::{GenInstruction, Instruction};
+use super::encoding::{GenInstruction, ImmediateMode, Instruction};
 use crate::cpu;
 use crate::system::rv32;
 
@@ -70,4 +70,74 @@ impl DecodeCycle {
         }
         Err("No instruction found")
     }
+
+    // Just enough disassembly to say what a faulting instruction was, memory
+    // accesses in full and anything else by the name it decodes as
+    pub fn disassemble(&self, inst: rv32::Word) -> String {
+        let geninst = GenInstruction { inst };
+        match inst & 0x7f {
+            0b0000011 => {
+                let i = unsafe { geninst.I };
+                let mnemonic = ["lb", "lh", "lw", "", "lbu", "lhu", "", ""][i.funct3() as usize];
+                let offset = i.sext_imm() as i32;
+                format!("{} x{}, {}(x{})", mnemonic, i.rd(), offset, i.rs1())
+            }
+            0b0100011 => {
+                let s = unsafe { geninst.S };
+                let mnemonic = ["sb", "sh", "sw", "", "", "", "", ""][s.funct3() as usize];
+                let offset = s.sext_imm() as i32;
+                format!("{} x{}, {}(x{})", mnemonic, s.rs2(), offset, s.rs1())
+            }
+            0b0101111 if inst >> 27 == 0b00010 => {
+                let r = unsafe { geninst.R };
+                format!("lr.w x{}, (x{})", r.rd(), r.rs1())
+            }
+            0b0101111 if inst >> 27 == 0b00011 => {
+                let r = unsafe { geninst.R };
+                format!("sc.w x{}, x{}, (x{})", r.rd(), r.rs2(), r.rs1())
+            }
+            0b0101111 if amo_mnemonic(inst).is_some() => {
+                let r = unsafe { geninst.R };
+                let mnemonic = amo_mnemonic(inst).unwrap();
+                format!("{} x{}, x{}, (x{})", mnemonic, r.rd(), r.rs2(), r.rs1())
+            }
+            _ => self.name(inst).unwrap_or("unknown instruction").to_string(),
+        }
+    }
+
+    fn name(&self, inst: rv32::Word) -> Option<&'static str> {
+        fn find<T: IntoEnumIterator + Instruction>(inst: rv32::Word) -> Option<&'static str> {
+            T::iter()
+                .find(|instruction| instruction.match_inst(inst))
+                .map(|instruction| instruction.name())
+        }
+
+        self.extensions
+            .iter()
+            .find_map(|extension| match extension {
+                'i' => find::<i::ExtensionI>(inst),
+                'a' => find::<a::ExtensionA>(inst),
+                'm' => find::<m::ExtensionM>(inst),
+                'z' => find::<z::ExtensionZ>(inst),
+                _ => None,
+            })
+    }
+}
+
+fn amo_mnemonic(inst: rv32::Word) -> Option<&'static str> {
+    if inst & 0x707f != 0x202f {
+        return None;
+    }
+    match inst >> 27 {
+        0b00001 => Some("amoswap.w"),
+        0b00000 => Some("amoadd.w"),
+        0b00100 => Some("amoxor.w"),
+        0b01100 => Some("amoand.w"),
+        0b01000 => Some("amoor.w"),
+        0b10000 => Some("amomin.w"),
+        0b10100 => Some("amomax.w"),
+        0b11000 => Some("amominu.w"),
+        0b11100 => Some("amomaxu.w"),
+        _ => None,
+    }
 }
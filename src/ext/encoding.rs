@@ -1,3 +1,7 @@
+// The bitfield macros generate accessors for every field whether they're
+// used or not, and the union fields are named after the instruction formats
+#![allow(dead_code, non_snake_case, unused_parens)]
+
 use modular_bitfield::prelude::*;
 use enum_dispatch::*;
 
@@ -97,12 +101,12 @@ impl ImmediateMode for BType {
     }
 
     fn full_imm(&self) -> rv32::XLen {
-        // >0b1 << 1 | 0b1 << 6 | 0b111110 << 4 | 0b1110
+        // offset >> 1, bit 0 is always clear
         let imm_12 = self.imm_12() as rv32::XLen;
         let imm_11 = self.imm_11() as rv32::XLen;
         let imm_10_5 = self.imm_10_5() as rv32::XLen;
         let imm_4_1 = self.imm_4_1() as rv32::XLen;
-        (imm_12 << 1) | (imm_11 << 1) | (imm_10_5 << 6) | imm_4_1
+        (imm_12 << 11) | (imm_11 << 10) | (imm_10_5 << 4) | imm_4_1
     }
 }
 
@@ -139,12 +143,12 @@ impl ImmediateMode for JType {
     }
 
     fn full_imm(&self) -> rv32::XLen {
-        // >0b1 << 1 | 0b1 << 6 | 0b111110 << 4 | 0b1110
+        // offset >> 1, bit 0 is always clear
         let imm_20 = self.imm_20() as rv32::XLen;
         let imm_19_12 = self.imm_19_12() as rv32::XLen;
         let imm_11 = self.imm_11() as rv32::XLen;
         let imm_10_1 = self.imm_10_1() as rv32::XLen;
-        (imm_20 << 8) | (imm_19_12 << 1) | (imm_11 << 10) | imm_10_1
+        (imm_20 << 19) | (imm_19_12 << 11) | (imm_11 << 10) | imm_10_1
     }
 }
 
@@ -1,13 +1,12 @@
-use std::usize;
-
 use bits::match_mask;
 use enum_dispatch::*;
 use strum::EnumIter;
 
 use super::encoding::{GenInstruction, Instruction};
 use crate::cpu;
+use crate::cpu::csr;
+use crate::cpu::trigger::Access;
 use crate::ext::encoding::ImmediateMode;
-use crate::helpers::sext;
 use crate::system::rv32;
 
 // FOR BRANCH INSTRUCTIONS ITS IMPERATIVE TO REMEMBER
@@ -68,7 +67,7 @@ impl Instruction for JAL {
 
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
         let inst = unsafe { inst.J };
-        let offset = sext(inst.full_imm() << 1, 32);
+        let offset = inst.sext_imm() << 1;
         let pc = offset.wrapping_add(state.pc);
         state.x[inst.rd() as usize] = state.pc + rv32::WORD as u32;
         state.pc = pc.wrapping_sub(rv32::WORD as u32);
@@ -91,8 +90,8 @@ impl Instruction for JALR {
 
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
         let inst = unsafe { inst.I };
-        let offset = sext(inst.full_imm(), 32);
-        let pc = offset.wrapping_add(state.x[inst.rs1() as usize]);
+        let offset = inst.sext_imm();
+        let pc = offset.wrapping_add(state.x[inst.rs1() as usize]) & !1;
         state.x[inst.rd() as usize] = state.pc + rv32::WORD as u32;
         state.pc = pc.wrapping_sub(rv32::WORD as u32);
     }
@@ -113,45 +112,26 @@ impl Instruction for BRANCH {
 
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
         let inst = unsafe { inst.B };
-        let offset = state.pc + (inst.sext_imm() << 1) - 4;
-        match inst.funct3() {
-            0b000 => {
-                // beq
-                if inst.rs1() == inst.rs2() {
-                    state.pc = offset
-                }
-            }
-            0b001 => {
-                // bne
-                if inst.rs1() != inst.rs2() {
-                    state.pc = offset
-                }
-            }
-            0b100 => {
-                // blt
-                if inst.rs1() < inst.rs2() {
-                    state.pc = offset
-                }
-            }
-            0b101 => {
-                // bge
-                if inst.rs1() >= inst.rs2() {
-                    state.pc = offset
-                }
-            }
-            0b110 => {
-                // bltu
-                if (inst.rs1() as u32) < (inst.rs2() as u32) {
-                    state.pc = offset
-                }
-            }
-            0b111 => {
-                // bgeu
-                if (inst.rs1() as u32) >= (inst.rs2() as u32) {
-                    state.pc = offset
-                }
+        let offset = state
+            .pc
+            .wrapping_add(inst.sext_imm() << 1)
+            .wrapping_sub(rv32::WORD as u32);
+        let rs1 = state.x[inst.rs1() as usize];
+        let rs2 = state.x[inst.rs2() as usize];
+        let taken = match inst.funct3() {
+            0b000 => rs1 == rs2,                   // beq
+            0b001 => rs1 != rs2,                   // bne
+            0b100 => (rs1 as i32) < (rs2 as i32),  // blt
+            0b101 => (rs1 as i32) >= (rs2 as i32), // bge
+            0b110 => rs1 < rs2,                    // bltu
+            0b111 => rs1 >= rs2,                   // bgeu
+            _ => {
+                state.trap = 3;
+                return;
             }
-            _ => state.trap = 3,
+        };
+        if taken {
+            state.pc = offset
         }
     }
 }
@@ -172,25 +152,33 @@ impl Instruction for LOAD {
         let inst = unsafe { inst.I };
         let offset = inst.sext_imm();
         let addr = state.x[inst.rs1() as usize].wrapping_add(offset);
-        match inst.funct3() {
-            0b000 => {
-                // lb
-                state.x[inst.rd() as usize] =
-                    state.bus.borrow_mut().load_8(addr) as i8 as i32 as u32
-            }
-            0b001 => {
-                // lh
-                state.x[inst.rd() as usize] =
-                    state.bus.borrow_mut().load_16(addr) as i16 as i32 as u32
+        if state.check_triggers(Access::Load, addr, None) {
+            return;
+        }
+        let loaded = {
+            let mut bus = state.bus.borrow_mut();
+            match inst.funct3() {
+                0b000 => Some(bus.load_8(addr).map(|v| v as i8 as i32 as u32)), // lb
+                0b001 => Some(bus.load_16(addr).map(|v| v as i16 as i32 as u32)), // lh
+                0b010 => Some(bus.load_32(addr)),                               // lw
+                0b100 => Some(bus.load_8(addr).map(|v| v as u32)),              // lbu
+                0b101 => Some(bus.load_16(addr).map(|v| v as u32)),             // lhu
+                _ => None,
             }
-            0b010 => {
-                // lw
-                state.x[inst.rd() as usize] = state.bus.borrow_mut().load_32(addr) as i32 as u32
+        };
+        let value = match loaded {
+            Some(Ok(value)) => value,
+            Some(Err(e)) => return state.access_fault(5, addr, e), // load access fault
+            None => {
+                state.trap = 3;
+                return;
             }
-            0b100 => state.x[inst.rd() as usize] = state.bus.borrow_mut().load_8(addr) as u32, // lbu
-            0b101 => state.x[inst.rd() as usize] = state.bus.borrow_mut().load_16(addr) as u32, // lhu
-            _ => state.trap = 3,
+        };
+        // data triggers fire before rd is written
+        if state.check_triggers(Access::Load, addr, Some(value)) {
+            return;
         }
+        state.x[inst.rd() as usize] = value;
     }
 }
 
@@ -210,20 +198,28 @@ impl Instruction for STORE {
         let inst = unsafe { inst.S };
         let offset = inst.sext_imm();
         let addr = state.x[inst.rs1() as usize].wrapping_add(offset);
-        match inst.funct3() {
-            0b000 => state // sb
-                .bus
-                .borrow_mut()
-                .store_8(addr, state.x[inst.rs2() as usize] as u8),
-            0b001 => state // sh
-                .bus
-                .borrow_mut()
-                .store_16(addr, state.x[inst.rs2() as usize] as u16),
-            0b010 => state // sw
-                .bus
-                .borrow_mut()
-                .store_32(addr, state.x[inst.rs2() as usize] as u32),
-            _ => state.trap = 3,
+        // data triggers only see the bytes that are stored
+        let value = match inst.funct3() {
+            0b000 => state.x[inst.rs2() as usize] & 0xff,
+            0b001 => state.x[inst.rs2() as usize] & 0xffff,
+            _ => state.x[inst.rs2() as usize],
+        };
+        if state.check_triggers(Access::Store, addr, None)
+            || state.check_triggers(Access::Store, addr, Some(value))
+        {
+            return;
+        }
+        let stored = match inst.funct3() {
+            0b000 => state.bus.borrow_mut().store_8(addr, value as u8), // sb
+            0b001 => state.bus.borrow_mut().store_16(addr, value as u16), // sh
+            0b010 => state.bus.borrow_mut().store_32(addr, value),      // sw
+            _ => {
+                state.trap = 3;
+                return;
+            }
+        };
+        if let Err(e) = stored {
+            state.access_fault(7, addr, e); // store access fault
         }
     }
 }
@@ -266,14 +262,16 @@ impl Instruction for IMM {
         match inst.funct3() {
             0b000 => retval = rs1.wrapping_add(inst.sext_imm()), // addi
             0b010 => retval = ((rs1 as i32) < (inst.sext_imm() as i32)) as u32, // slti
-            0b011 => retval = ((rs1 as u32) < (inst.sext_imm() as u32)) as u32, // sltiu
+            0b011 => retval = (rs1 < inst.sext_imm()) as u32,    // sltiu
             0b100 => retval = rs1 ^ inst.sext_imm(),             // xori
             0b110 => retval = rs1 | inst.sext_imm(),             // ori
             0b111 => retval = rs1 & inst.sext_imm(),             // andi
             _ => state.trap = 3,
         }
 
-        state.x[inst.rd() as usize] = retval;
+        if state.trap == 0 {
+            state.x[inst.rd() as usize] = retval;
+        }
     }
 }
 
@@ -304,7 +302,7 @@ impl Instruction for SHIFTI {
         let rs1 = state.x[inst.rs1() as usize];
 
         match inst.funct3() {
-            0b001 => retval = rs1.wrapping_shl(shamt), //slli
+            0b001 if inst.funct7() == 0 => retval = rs1.wrapping_shl(shamt), //slli
             0b101 => match inst.funct7() {
                 0b0000000 => retval = rs1.wrapping_shr(shamt), // srli
                 0b0100000 => retval = (rs1 as i32).wrapping_shr(shamt) as u32, // srai
@@ -313,7 +311,9 @@ impl Instruction for SHIFTI {
             _ => state.trap = 3,
         }
 
-        state.x[inst.rd() as usize] = retval;
+        if state.trap == 0 {
+            state.x[inst.rd() as usize] = retval;
+        }
     }
 }
 
@@ -344,7 +344,7 @@ impl Instruction for OP {
             },
             0b001 => retval = rs1.wrapping_shl(rs2 & 0x1F), // sll
             0b010 => retval = ((rs1 as i32) < (rs2 as i32)) as u32, // slt
-            0b011 => retval = ((rs1 as u32) < (rs2 as u32)) as u32, // sltu
+            0b011 => retval = (rs1 < rs2) as u32,           // sltu
             0b100 => retval = rs1 ^ rs2,                    // xor
             0b101 => match inst.funct7() {
                 0b0000000 => retval = rs1.wrapping_shr(rs2 & 0x1F), // srl
@@ -356,7 +356,81 @@ impl Instruction for OP {
             _ => state.trap = 3,
         }
 
-        state.x[inst.rd() as usize] = retval;
+        if state.trap == 0 {
+            state.x[inst.rd() as usize] = retval;
+        }
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct FENCE; // FENCE and FENCE.I (Zifencei), we execute in order
+                  // and have no instruction cache so both are no-ops
+impl Instruction for FENCE {
+    fn name(&self) -> &'static str {
+        "FENCE, FENCE.I"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        if match_mask!(inst, "xxxxxxxxxxxxxxxxx000xxxxx0001111") {
+            return true;
+        }
+        if match_mask!(inst, "xxxxxxxxxxxxxxxxx001xxxxx0001111") {
+            return true;
+        }
+        false
+    }
+
+    fn step(&self, _inst: GenInstruction, _state: &mut cpu::CPUState) {}
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct SYSTEM; // Catchall for the SYSTEM instructions that aren't CSR
+                   // accesses, ECALL, EBREAK and the privileged MRET, SRET, WFI
+                   // and SFENCE.VMA (which we have no TLB for)
+impl Instruction for SYSTEM {
+    fn name(&self) -> &'static str {
+        "ECALL, EBREAK, MRET, SRET, WFI, SFENCE.VMA"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx000xxxxx1110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        let inst = unsafe { inst.R };
+        let privilege = state.extraflags & 3;
+        match (inst.funct7(), inst.rs2()) {
+            (0b0000000, 0b00000) => state.trap = 8 + privilege + 1, // ecall from U, S or M
+            (0b0000000, 0b00001) => {
+                // ebreak
+                state.trap = 3 + 1;
+                state.tval = state.pc;
+            }
+            (0b0011000, 0b00010) if privilege == 3 => {
+                // mret, MIE = MPIE, MPIE = 1, back to the privilege in MPP
+                let mstatus = state.mstatus;
+                let mie = (mstatus & csr::MSTATUS_MPIE) >> 4;
+                state.mstatus =
+                    (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP)) | mie | csr::MSTATUS_MPIE;
+                state.extraflags = (state.extraflags & !3) | ((mstatus >> 11) & 3);
+                state.pc = state.mepc.wrapping_sub(rv32::WORD as u32);
+            }
+            (0b0001000, 0b00010) if privilege >= 1 => {
+                // sret, SIE = SPIE, SPIE = 1, back to the privilege in SPP
+                let mstatus = state.mstatus;
+                let sie = (mstatus & csr::MSTATUS_SPIE) >> 4;
+                state.mstatus =
+                    (mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP)) | sie | csr::MSTATUS_SPIE;
+                state.extraflags = (state.extraflags & !3) | ((mstatus >> 8) & 1);
+                state.pc = state.sepc.wrapping_sub(rv32::WORD as u32);
+            }
+            (0b0001000, 0b00101) => {
+                // wfi, sleep until an interrupt is pending
+                state.extraflags |= 4;
+            }
+            (0b0001001, _) => (), // sfence.vma
+            _ => state.trap = 3,
+        }
     }
 }
 
@@ -373,4 +447,6 @@ pub enum ExtensionI {
     IMM(IMM),
     SHIFTI(SHIFTI),
     OP(OP),
+    FENCE(FENCE),
+    SYSTEM(SYSTEM),
 }
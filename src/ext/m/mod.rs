@@ -1,40 +1,184 @@
-use std::usize;
-
 use bits::match_mask;
 use enum_dispatch::*;
 use strum::EnumIter;
 
 use super::encoding::{GenInstruction, Instruction};
 use crate::cpu;
-use crate::ext::encoding::ImmediateMode;
-use crate::helpers::sext;
 use crate::system::rv32;
 
 // FOR BRANCH INSTRUCTIONS ITS IMPERATIVE TO REMEMBER
 // THAT WE INCREMENT PC AFTER THE EXECUTION
 
+// Every M instruction is R type, rd = op(rs1, rs2). Nothing here traps,
+// dividing by zero or overflowing gives the results the spec fixes
+fn mul_div(
+    inst: GenInstruction,
+    state: &mut cpu::CPUState,
+    op: fn(rv32::Word, rv32::Word) -> rv32::Word,
+) {
+    let inst = unsafe { inst.R };
+    let rs1 = state.x[inst.rs1() as usize];
+    let rs2 = state.x[inst.rs2() as usize];
+    state.x[inst.rd() as usize] = op(rs1, rs2);
+}
+
 #[derive(Default, Copy, Clone)]
-pub struct MULW; // MULW rd, rs1, rs2 - Multiply Word
-                 // Multiply rs1 and rs2 and store the lower 32 bits in rd
-impl Instruction for MULW {
+pub struct MUL; // MUL rd, rs1, rs2 - Multiply
+                // rd = lower 32 bits of rs1 * rs2
+impl Instruction for MUL {
     fn name(&self) -> &'static str {
-        "MULW"
+        "MUL"
     }
 
     fn match_inst(&self, inst: rv32::Word) -> bool {
-        match_mask!(inst, "xxxxxxxxxxxxxxxxxxxxxxxxx0110111")
+        match_mask!(inst, "0000001xxxxxxxxxx000xxxxx0110011")
     }
 
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
-        let inst = unsafe { inst.U };
-        let val = inst.full_imm() << 12;
-        state.x[inst.rd() as usize] = val;
+        mul_div(inst, state, |a, b| a.wrapping_mul(b));
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct MULH; // MULH rd, rs1, rs2 - Multiply High
+                 // rd = upper 32 bits of signed rs1 * signed rs2
+impl Instruction for MULH {
+    fn name(&self) -> &'static str {
+        "MULH"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx001xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| {
+            ((a as i32 as i64 * b as i32 as i64) >> 32) as rv32::Word
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct MULHSU; // MULHSU rd, rs1, rs2 - Multiply High Signed Unsigned
+                   // rd = upper 32 bits of signed rs1 * unsigned rs2
+impl Instruction for MULHSU {
+    fn name(&self) -> &'static str {
+        "MULHSU"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx010xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| {
+            ((a as i32 as i64).wrapping_mul(b as i64) >> 32) as rv32::Word
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct MULHU; // MULHU rd, rs1, rs2 - Multiply High Unsigned
+                  // rd = upper 32 bits of unsigned rs1 * unsigned rs2
+impl Instruction for MULHU {
+    fn name(&self) -> &'static str {
+        "MULHU"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx011xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| ((a as u64 * b as u64) >> 32) as rv32::Word);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct DIV; // DIV rd, rs1, rs2 - Divide
+                // rd = signed rs1 / signed rs2, rounding towards zero
+                // by zero is -1, MIN / -1 is MIN
+impl Instruction for DIV {
+    fn name(&self) -> &'static str {
+        "DIV"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx100xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| match b {
+            0 => rv32::Word::MAX,
+            _ => (a as i32).wrapping_div(b as i32) as rv32::Word,
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct DIVU; // DIVU rd, rs1, rs2 - Divide Unsigned
+                 // rd = unsigned rs1 / unsigned rs2, by zero is all ones
+impl Instruction for DIVU {
+    fn name(&self) -> &'static str {
+        "DIVU"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx101xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| a.checked_div(b).unwrap_or(rv32::Word::MAX));
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct REM; // REM rd, rs1, rs2 - Remainder
+                // rd = signed rs1 % signed rs2, with the sign of rs1
+                // by zero is rs1, MIN % -1 is 0
+impl Instruction for REM {
+    fn name(&self) -> &'static str {
+        "REM"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx110xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| match b {
+            0 => a,
+            _ => (a as i32).wrapping_rem(b as i32) as rv32::Word,
+        });
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct REMU; // REMU rd, rs1, rs2 - Remainder Unsigned
+                 // rd = unsigned rs1 % unsigned rs2, by zero is rs1
+impl Instruction for REMU {
+    fn name(&self) -> &'static str {
+        "REMU"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "0000001xxxxxxxxxx111xxxxx0110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        mul_div(inst, state, |a, b| a.checked_rem(b).unwrap_or(a));
     }
 }
 
 #[derive(EnumIter)]
 #[enum_dispatch(Instruction)]
 pub enum ExtensionM {
-    MULW(MULW),
+    MUL(MUL),
+    MULH(MULH),
+    MULHSU(MULHSU),
+    MULHU(MULHU),
+    DIV(DIV),
+    DIVU(DIVU),
+    REM(REM),
+    REMU(REMU),
 }
-
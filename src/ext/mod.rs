@@ -1,4 +1,3 @@
-use crate::system::rv32;
 
 pub mod encoding;
 pub mod decode;
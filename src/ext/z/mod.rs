@@ -1,13 +1,10 @@
-use std::usize;
-
 use bits::match_mask;
 use enum_dispatch::*;
 use strum::EnumIter;
 
 use super::encoding::{GenInstruction, Instruction};
 use crate::cpu;
-use crate::ext::encoding::ImmediateMode;
-use crate::helpers::sext;
+use crate::ext::encoding::{IType, ImmediateMode};
 use crate::system::rv32;
 
 // ZiCSR - Control and Status Register Instructions
@@ -17,6 +14,31 @@ use crate::system::rv32;
 // FOR BRANCH INSTRUCTIONS ITS IMPERATIVE TO REMEMBER
 // THAT WE INCREMENT PC AFTER THE EXECUTION
 
+// Shared by every CSR instruction, reads the old value of the CSR into rd
+// and writes back op(old, operand) when write is set. Accessing a CSR that
+// doesn't exist, or that we don't have the privilege for, is illegal
+fn csr_access(
+    state: &mut cpu::CPUState,
+    inst: &IType,
+    operand: rv32::Word,
+    write: bool,
+    op: fn(rv32::Word, rv32::Word) -> rv32::Word,
+) {
+    let csr = inst.full_imm();
+    let old = match state.read_csr(csr) {
+        Some(value) => value,
+        None => {
+            state.trap = 3;
+            return;
+        }
+    };
+    if write && state.write_csr(csr, op(old, operand)).is_none() {
+        state.trap = 3;
+        return;
+    }
+    state.x[inst.rd() as usize] = old;
+}
+
 #[derive(Default, Copy, Clone)]
 pub struct CSRRW; // CSRRW rd, rs1, csr - Atomic Read/Write CSR
                   // Read the CSR into rd, then write rs1 into the CSR
@@ -28,13 +50,108 @@ impl Instruction for CSRRW {
     }
 
     fn match_inst(&self, inst: rv32::Word) -> bool {
-        match_mask!(inst, "xxxxxxxxxxxxxxxxxxxxxxxxx0110111")
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx001xxxxx1110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        let inst = unsafe { inst.I };
+        let rs1 = state.x[inst.rs1() as usize];
+        csr_access(state, &inst, rs1, true, |_, new| new);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct CSRRS; // CSRRS rd, rs1, csr - Atomic Read and Set Bits in CSR
+                  // rd = csr
+                  // csr = csr | rs1 (only written if rs1 is not x0)
+impl Instruction for CSRRS {
+    fn name(&self) -> &'static str {
+        "CSRRS"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx010xxxxx1110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        let inst = unsafe { inst.I };
+        let rs1 = state.x[inst.rs1() as usize];
+        csr_access(state, &inst, rs1, inst.rs1() != 0, |old, bits| old | bits);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct CSRRC; // CSRRC rd, rs1, csr - Atomic Read and Clear Bits in CSR
+                  // rd = csr
+                  // csr = csr & !rs1 (only written if rs1 is not x0)
+impl Instruction for CSRRC {
+    fn name(&self) -> &'static str {
+        "CSRRC"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx011xxxxx1110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        let inst = unsafe { inst.I };
+        let rs1 = state.x[inst.rs1() as usize];
+        csr_access(state, &inst, rs1, inst.rs1() != 0, |old, bits| old & !bits);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct CSRRWI; // CSRRWI rd, uimm, csr - CSRRW with the rs1 field as a
+                   // 5 bit zero extended immediate
+impl Instruction for CSRRWI {
+    fn name(&self) -> &'static str {
+        "CSRRWI"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx101xxxxx1110011")
     }
 
     fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
-        let inst = unsafe { inst.U };
-        let val = inst.full_imm() << 12;
-        state.x[inst.rd() as usize] = val;
+        let inst = unsafe { inst.I };
+        let uimm = inst.rs1() as rv32::Word;
+        csr_access(state, &inst, uimm, true, |_, new| new);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct CSRRSI; // CSRRSI rd, uimm, csr - CSRRS with an immediate
+impl Instruction for CSRRSI {
+    fn name(&self) -> &'static str {
+        "CSRRSI"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx110xxxxx1110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        let inst = unsafe { inst.I };
+        let uimm = inst.rs1() as rv32::Word;
+        csr_access(state, &inst, uimm, uimm != 0, |old, bits| old | bits);
+    }
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct CSRRCI; // CSRRCI rd, uimm, csr - CSRRC with an immediate
+impl Instruction for CSRRCI {
+    fn name(&self) -> &'static str {
+        "CSRRCI"
+    }
+
+    fn match_inst(&self, inst: rv32::Word) -> bool {
+        match_mask!(inst, "xxxxxxxxxxxxxxxxx111xxxxx1110011")
+    }
+
+    fn step(&self, inst: GenInstruction, state: &mut cpu::CPUState) {
+        let inst = unsafe { inst.I };
+        let uimm = inst.rs1() as rv32::Word;
+        csr_access(state, &inst, uimm, uimm != 0, |old, bits| old & !bits);
     }
 }
 
@@ -42,5 +159,9 @@ impl Instruction for CSRRW {
 #[enum_dispatch(Instruction)]
 pub enum ExtensionZ {
     CSRRW(CSRRW),
+    CSRRS(CSRRS),
+    CSRRC(CSRRC),
+    CSRRWI(CSRRWI),
+    CSRRSI(CSRRSI),
+    CSRRCI(CSRRCI),
 }
-
@@ -5,11 +5,37 @@ pub fn sext(value: u32, len: usize) -> u32 {
         return value;
     }
     let sign = value >> (len - 1) as u32 & 0x1;
-    let mask = ((1 as u32) << (len as u32)) - 1 as u32;
+    let mask = (1u32 << (len as u32)) - 1;
     if sign == 0 {
         value & mask
     } else {
-        let high = (((1 as u32) << (bit_len as u32 - len as u32)) - 1 as u32) << (len as u32);
+        let high = ((1u32 << (bit_len as u32 - len as u32)) - 1) << (len as u32);
         value & mask | high
     }
 }
+
+// Parse a guest address given on the command line, hex with a 0x prefix or
+// decimal
+pub fn parse_address(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.replace('_', "").parse::<u32>(),
+    };
+    parsed.map_err(|e| format!("invalid address {}: {}", value, e))
+}
+
+// Parse a size given on the command line, an address style number with an
+// optional K, M or G suffix
+pub fn parse_size(value: &str) -> Result<u32, String> {
+    let (number, shift) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 30),
+        _ => (value, 0),
+    };
+    let size = parse_address(number).map_err(|_| format!("invalid size {}", value))?;
+    match (size as u64) << shift {
+        size if size <= u32::MAX as u64 => Ok(size as u32),
+        _ => Err(format!("size {} does not fit in 32 bits", value)),
+    }
+}
@@ -0,0 +1,225 @@
+use crate::loader::symbols::{Symbol, SymbolTable};
+use crate::loader::Segment;
+
+// ELF32 executables
+//
+// Only what's needed to run a statically linked RISC-V program, the
+// PT_LOAD segments are placed at their physical addresses with anything
+// past the end of the file data (.bss) zeroed. The symbol table is kept
+// so we can name addresses.
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(ELF_MAGIC)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(format!("truncated at offset 0x{:x}", offset))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(format!("truncated at offset 0x{:x}", offset))
+}
+
+fn slice(bytes: &[u8], offset: u32, size: u32) -> Result<&[u8], String> {
+    let (start, end) = (offset as usize, offset as usize + size as usize);
+    bytes.get(start..end).ok_or(format!(
+        "0x{:x} bytes at offset 0x{:x} run past the end of the file",
+        size, offset
+    ))
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Elf, String> {
+    if !is_elf(bytes) || bytes.len() < ELF_HEADER_SIZE {
+        return Err("not an ELF file".to_string());
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(format!("EI_CLASS is {}, only ELF32 is supported", bytes[4]));
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err("only little endian ELF files are supported".to_string());
+    }
+    let e_type = u16_at(bytes, 16)?;
+    if e_type != ET_EXEC {
+        return Err(format!("e_type is {}, expected an executable", e_type));
+    }
+    let e_machine = u16_at(bytes, 18)?;
+    if e_machine != EM_RISCV {
+        return Err(format!(
+            "e_machine is {}, expected RISC-V ({})",
+            e_machine, EM_RISCV
+        ));
+    }
+
+    let entry = u32_at(bytes, 24)?;
+    let phoff = u32_at(bytes, 28)? as usize;
+    let shoff = u32_at(bytes, 32)? as usize;
+    let phnum = u16_at(bytes, 44)? as usize;
+    let shnum = u16_at(bytes, 48)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * PROGRAM_HEADER_SIZE;
+        if u32_at(bytes, ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = u32_at(bytes, ph + 4)?;
+        let paddr = u32_at(bytes, ph + 12)?;
+        let filesz = u32_at(bytes, ph + 16)?;
+        let memsz = u32_at(bytes, ph + 20)?;
+        if filesz > memsz {
+            return Err(format!("segment {} has more file data than memory", i));
+        }
+        if paddr.checked_add(memsz).is_none() {
+            return Err(format!("segment {} runs off the end of memory", i));
+        }
+        let mut data = slice(bytes, offset, filesz)?.to_vec();
+        data.resize(memsz as usize, 0); // .bss, zero filled after the file data
+        segments.push(Segment {
+            address: paddr,
+            data,
+        });
+    }
+
+    // the symbol table is optional, stripped binaries still run
+    let mut symbols = SymbolTable::new();
+    for i in 0..shnum {
+        let sh = shoff + i * SECTION_HEADER_SIZE;
+        if u32_at(bytes, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let symtab = slice(bytes, u32_at(bytes, sh + 16)?, u32_at(bytes, sh + 20)?)?;
+        // sh_link points at the string table the names live in
+        let strsh = shoff + u32_at(bytes, sh + 24)? as usize * SECTION_HEADER_SIZE;
+        let strtab = slice(
+            bytes,
+            u32_at(bytes, strsh + 16)?,
+            u32_at(bytes, strsh + 20)?,
+        )?;
+
+        for sym in symtab.chunks_exact(SYMBOL_SIZE) {
+            let name = u32_at(sym, 0)? as usize;
+            let value = u32_at(sym, 4)?;
+            let size = u32_at(sym, 8)?;
+            let kind = sym[12] & 0xf;
+            let shndx = u16_at(sym, 14)?;
+            // defined STT_NOTYPE, STT_OBJECT and STT_FUNC, not sections or files
+            if kind > 2 || shndx == 0 || name == 0 || name >= strtab.len() {
+                continue;
+            }
+            let end = strtab[name..]
+                .iter()
+                .position(|b| *b == 0)
+                .map_or(strtab.len(), |len| name + len);
+            let name = String::from_utf8_lossy(&strtab[name..end]).to_string();
+            // $x / $d mapping symbols only mark code and data
+            if name.starts_with('$') {
+                continue;
+            }
+            symbols.insert(Symbol {
+                name,
+                address: value,
+                size,
+            });
+        }
+    }
+
+    Ok(Elf {
+        entry,
+        segments,
+        symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An executable with one PT_LOAD segment of 4 bytes of code followed by
+    // 8 bytes of .bss, loaded at 0x80000000
+    fn executable() -> Vec<u8> {
+        let mut elf = vec![0; ELF_HEADER_SIZE];
+        elf[..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = ELFCLASS32;
+        elf[5] = ELFDATA2LSB;
+        elf[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        elf[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        elf[24..28].copy_from_slice(&0x80000000u32.to_le_bytes()); // e_entry
+        elf[28..32].copy_from_slice(&(ELF_HEADER_SIZE as u32).to_le_bytes()); // e_phoff
+        elf[44..46].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+
+        let data_offset = (ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE) as u32;
+        for field in [PT_LOAD, data_offset, 0x80000000, 0x80000000, 4, 12] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.resize(data_offset as usize, 0);
+        elf.extend_from_slice(&[0x13, 0x00, 0x00, 0x00]); // nop
+        elf
+    }
+
+    #[test]
+    fn loads_segments_and_zeroes_bss() {
+        let elf = parse(&executable()).unwrap();
+        assert_eq!(elf.entry, 0x80000000);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].address, 0x80000000);
+        assert_eq!(
+            elf.segments[0].data,
+            [0x13, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let elf = executable();
+        // the segment's file data is cut short
+        assert!(parse(&elf[..elf.len() - 1]).is_err());
+        // the program header is cut short
+        assert!(parse(&elf[..ELF_HEADER_SIZE + 8]).is_err());
+        // the ELF header itself is cut short
+        assert!(parse(&elf[..ELF_HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_elf64() {
+        let mut elf = executable();
+        elf[4] = 2; // ELFCLASS64
+        match parse(&elf) {
+            Err(e) => assert!(e.contains("only ELF32"), "{}", e),
+            Ok(_) => panic!("ELF64 was accepted"),
+        }
+    }
+
+    #[test]
+    fn rejects_more_file_data_than_memory() {
+        let mut elf = executable();
+        let memsz = ELF_HEADER_SIZE + 20;
+        elf[memsz..memsz + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(parse(&elf).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::loader::{hex_bytes, push_data, Image};
+
+// Intel HEX
+//
+// Each line is a record, ":LLAAAATT<data>CC", a byte count, a 16 bit
+// address, the record type, the data and a checksum that makes every byte
+// of the record sum to zero. The extended address records supply the upper
+// bits of the address for the data records that follow.
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+pub fn parse(text: &str) -> Result<Image, String> {
+    let mut image = Image {
+        segments: Vec::new(),
+        entry: None,
+    };
+    let mut base: u32 = 0;
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or(format!("line {}: record does not start with ':'", number))?;
+        let bytes = hex_bytes(record).map_err(|e| format!("line {}: {}", number, e))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(format!(
+                "line {}: record length does not match its byte count",
+                number
+            ));
+        }
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != 0 {
+            return Err(format!(
+                "line {}: checksum mismatch, expected 0x{:02x}",
+                number,
+                bytes[bytes.len() - 1].wrapping_sub(sum)
+            ));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        let word = |len: usize| -> Result<u32, String> {
+            if data.len() != len {
+                return Err(format!("line {}: expected {} bytes of data", number, len));
+            }
+            Ok(data.iter().fold(0, |value, b| value << 8 | *b as u32))
+        };
+        match bytes[3] {
+            DATA => push_data(&mut image.segments, base.wrapping_add(offset), data),
+            END_OF_FILE => return Ok(image),
+            EXTENDED_SEGMENT_ADDRESS => base = word(2)? << 4,
+            EXTENDED_LINEAR_ADDRESS => base = word(2)? << 16,
+            START_SEGMENT_ADDRESS => {
+                // CS:IP, real mode style
+                let start = word(4)?;
+                image.entry = Some((start >> 16) * 16 + (start & 0xffff));
+            }
+            START_LINEAR_ADDRESS => image.entry = Some(word(4)?),
+            kind => {
+                return Err(format!(
+                    "line {}: unknown record type 0x{:02x}",
+                    number, kind
+                ))
+            }
+        }
+    }
+    Err("missing end of file record".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_linear_address_and_start() {
+        let image = parse(
+            ":0200000480007A\n\
+             :0400100013000000D9\n\
+             :040000058000001067\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0x80000010);
+        assert_eq!(image.segments[0].data, [0x13, 0x00, 0x00, 0x00]);
+        assert_eq!(image.entry, Some(0x80000010));
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let image = parse(":020000021000EC\n:03002000AABBCCAC\n:00000001FF\n").unwrap();
+        assert_eq!(image.segments[0].address, 0x10020);
+        assert_eq!(image.segments[0].data, [0xaa, 0xbb, 0xcc]);
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let e = parse(":0400100013000000D8\n:00000001FF\n").err().unwrap();
+        assert!(
+            e.contains("line 1: checksum mismatch, expected 0xd9"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_end_of_file() {
+        assert!(parse(":0400100013000000D9\n").is_err());
+    }
+}
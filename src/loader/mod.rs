@@ -0,0 +1,73 @@
+pub mod elf;
+pub mod ihex;
+pub mod srec;
+pub mod symbols;
+
+// A run of bytes to place in guest memory
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+// What the text formats (Intel HEX, S-record) describe, the start address
+// record is optional
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u32>,
+}
+
+pub enum Format {
+    Elf,
+    IntelHex,
+    SRecord,
+    Raw,
+}
+
+// Work out what a program file is from its contents, anything we don't
+// recognise is a raw binary for the base of DRAM
+pub fn detect(bytes: &[u8]) -> Format {
+    if elf::is_elf(bytes) {
+        return Format::Elf;
+    }
+    let text = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map_or(&bytes[..0], |start| &bytes[start..]);
+    match text {
+        [b':', next, ..] if next.is_ascii_hexdigit() => Format::IntelHex,
+        [b'S', next, ..] if next.is_ascii_digit() => Format::SRecord,
+        _ => Format::Raw,
+    }
+}
+
+// Append data to the last segment if it follows on from it, the text
+// formats split images into many small records
+fn push_data(segments: &mut Vec<Segment>, address: u32, data: &[u8]) {
+    if let Some(last) = segments.last_mut() {
+        if last.address.wrapping_add(last.data.len() as u32) == address {
+            last.data.extend_from_slice(data);
+            return;
+        }
+    }
+    segments.push(Segment {
+        address,
+        data: data.to_vec(),
+    });
+}
+
+// Decode a line of hex digit pairs into bytes
+fn hex_bytes(line: &str) -> Result<Vec<u8>, String> {
+    if !line.is_ascii() {
+        return Err("invalid characters".to_string());
+    }
+    if !line.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    (0..line.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&line[i..i + 2], 16)
+                .map_err(|_| format!("invalid hex digits '{}'", &line[i..i + 2]))
+        })
+        .collect()
+}
@@ -0,0 +1,138 @@
+use crate::loader::{hex_bytes, push_data, Image};
+
+// Motorola S-record
+//
+// Each line is a record, "S<type><count><address><data><checksum>". The
+// count covers the address, data and checksum bytes, the address is 2, 3
+// or 4 bytes depending on the type and the checksum is the ones' complement
+// of the sum of the count, address and data. S1-S3 hold data, S7-S9 the
+// start address, S0 is a header and S5/S6 a record count.
+
+fn address_size(kind: u8) -> Option<usize> {
+    match kind {
+        b'0' | b'1' | b'5' | b'9' => Some(2),
+        b'2' | b'6' | b'8' => Some(3),
+        b'3' | b'7' => Some(4),
+        _ => None,
+    }
+}
+
+pub fn parse(text: &str) -> Result<Image, String> {
+    let mut image = Image {
+        segments: Vec::new(),
+        entry: None,
+    };
+    let mut records = 0;
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let kind = match line.as_bytes() {
+            [b'S', kind, ..] => *kind,
+            _ => return Err(format!("line {}: record does not start with 'S'", number)),
+        };
+        let size = address_size(kind).ok_or(format!(
+            "line {}: unknown record type S{}",
+            number, kind as char
+        ))?;
+        let bytes = hex_bytes(&line[2..]).map_err(|e| format!("line {}: {}", number, e))?;
+        if bytes.len() < size + 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(format!(
+                "line {}: record length does not match its byte count",
+                number
+            ));
+        }
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != 0xff {
+            let checksum = bytes[bytes.len() - 1];
+            return Err(format!(
+                "line {}: checksum mismatch, expected 0x{:02x}",
+                number,
+                !sum.wrapping_sub(checksum)
+            ));
+        }
+
+        let address = bytes[1..=size]
+            .iter()
+            .fold(0, |value, b| value << 8 | *b as u32);
+        let data = &bytes[size + 1..bytes.len() - 1];
+        match kind {
+            b'0' => (), // header, usually the file name
+            b'1' | b'2' | b'3' => {
+                push_data(&mut image.segments, address, data);
+                records += 1;
+            }
+            b'5' | b'6' => {
+                if address != records {
+                    return Err(format!(
+                        "line {}: record count is {} but {} data records were read",
+                        number, address, records
+                    ));
+                }
+            }
+            _ => {
+                // S7, S8 or S9 terminate the file, tools write 0 when
+                // there is no start address
+                if address != 0 {
+                    image.entry = Some(address);
+                }
+                return Ok(image);
+            }
+        }
+    }
+    // the termination record is optional in practice
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_and_start_address() {
+        let image = parse(
+            "S0050000484969\n\
+             S309800000101300000053\n\
+             S705800000106A\n",
+        )
+        .unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0x80000010);
+        assert_eq!(image.segments[0].data, [0x13, 0x00, 0x00, 0x00]);
+        assert_eq!(image.entry, Some(0x80000010));
+    }
+
+    #[test]
+    fn address_sizes_and_record_count() {
+        let image = parse(
+            "S206010020AABB73\n\
+             S1040030CCFF\n\
+             S5030002FA\n\
+             S9030000FC\n",
+        )
+        .unwrap();
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].address, 0x010020);
+        assert_eq!(image.segments[1].address, 0x0030);
+        // a zero start address means there isn't one
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn rejects_a_wrong_record_count() {
+        assert!(parse("S1040030CCFF\nS5030002FA\n").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let e = parse("S1040030CCFE\n").err().unwrap();
+        assert!(
+            e.contains("line 1: checksum mismatch, expected 0xff"),
+            "{}",
+            e
+        );
+    }
+}
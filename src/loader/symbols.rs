@@ -0,0 +1,110 @@
+// Symbols from a loaded program, used to name addresses in traces, the
+// REPL and crash reports
+
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+pub struct SymbolTable {
+    symbols: Vec<(Option<usize>, Symbol)>, // sorted by address
+    files: Vec<String>,                    // the file each symbol came from
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn insert(&mut self, symbol: Symbol) {
+        self.insert_from(None, symbol);
+    }
+
+    fn insert_from(&mut self, file: Option<usize>, symbol: Symbol) {
+        let index = self
+            .symbols
+            .partition_point(|(_, s)| s.address <= symbol.address);
+        self.symbols.insert(index, (file, symbol));
+    }
+
+    // Merge in the symbols of an image, dropping any from an earlier load
+    // of the same file so a reload doesn't leave stale addresses behind
+    pub fn replace(&mut self, file: &str, other: SymbolTable) {
+        let index = match self.files.iter().position(|f| f == file) {
+            Some(index) => index,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        };
+        self.symbols.retain(|(f, _)| *f != Some(index));
+        for (_, symbol) in other.symbols {
+            self.insert_from(Some(index), symbol);
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().map(|(_, s)| s).find(|s| s.name == name)
+    }
+
+    // The symbol containing an address and the offset into it. Symbols
+    // without a size (labels in assembly) cover everything up to the next
+    pub fn lookup(&self, address: u32) -> Option<(&Symbol, u32)> {
+        let index = self.symbols.partition_point(|(_, s)| s.address <= address);
+        let (_, symbol) = self.symbols[..index].last()?;
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    // " <name+0x10>" for use after an address, or nothing if it's unknown
+    pub fn describe(&self, address: u32) -> String {
+        match self.lookup(address) {
+            Some((symbol, 0)) => format!(" <{}>", symbol.name),
+            Some((symbol, offset)) => format!(" <{}+0x{:x}>", symbol.name, offset),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(symbols: &[(&str, u32)]) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for &(name, address) in symbols {
+            table.insert(Symbol {
+                name: name.to_string(),
+                address,
+                size: 4,
+            });
+        }
+        table
+    }
+
+    #[test]
+    fn reloading_a_file_replaces_its_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.replace("a.elf", table(&[("main", 0x100), ("helper", 0x200)]));
+        symbols.replace("b.elf", table(&[("other", 0x300)]));
+        symbols.replace("a.elf", table(&[("main", 0x400)]));
+
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.find("main").unwrap().address, 0x400);
+        assert!(symbols.find("helper").is_none());
+        assert_eq!(symbols.find("other").unwrap().address, 0x300);
+        assert_eq!(symbols.describe(0x402), " <main+0x2>");
+        assert_eq!(symbols.describe(0x100), "");
+    }
+}
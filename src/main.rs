@@ -1,8 +1,8 @@
 #![feature(type_alias_impl_trait)]
-#![feature(return_position_impl_trait_in_trait)]
+// instructions and devices are named the way the specs name them
+#![allow(clippy::upper_case_acronyms)]
 
 use std::fs::File;
-use std::io::BufReader;
 use std::io::Read;
 use std::{cell::RefCell, rc::Rc};
 
@@ -10,24 +10,54 @@ mod cpu;
 mod err;
 mod ext;
 mod helpers;
+mod loader;
 mod management;
 mod system;
 
 use crate::cpu::*;
+use crate::err::VMError;
 use crate::ext::decode;
+use crate::loader::{elf, ihex, srec, Format, Image, Segment};
+use crate::system::boot;
 use crate::system::bus;
+use crate::system::console;
+use crate::system::device::Device;
+use crate::system::dtb;
+use crate::system::file_memory::FileMemory;
+use crate::system::flash::Flash;
+use crate::system::framebuffer::Framebuffer;
+use crate::system::ram;
+use crate::system::rom;
+use crate::system::rtc;
+use crate::system::serial;
+use crate::system::uart;
+use crate::system::virtio::{VirtioDevice, VirtioMmio};
+use crate::system::virtio_9p::Share;
+use crate::system::virtio_blk::Block;
+use crate::system::virtio_console::Console;
+use crate::system::virtio_net::Net;
+use crate::system::virtio_rng::Rng;
 
 struct VMRV32I {
     bus: Rc<RefCell<bus::Bus>>,
     cpu: cpu::CPU,
+    #[allow(dead_code)]
     instruction_decoder: Rc<RefCell<decode::DecodeCycle>>,
+    extensions: Vec<char>,
+    firmware: Option<u32>, // size of the firmware at the base of DRAM
+    dtb: Option<Vec<u8>>,
+    handoff: (u32, u32, u32), // entry point, a1 and a2 the payload expects
+    rom_dtb: bool,
+    images: Vec<(String, u32, u32)>, // name, start and end of everything loaded
 }
 
 impl VMRV32I {
-    fn new() -> VMRV32I {
+    fn new(ram_base: u32, ram_size: u32) -> Result<VMRV32I, VMError> {
         let extensions = vec!['i', 'm', 'a', 'z'];
 
-        let bus = Rc::new(RefCell::new(bus::Bus::new()));
+        let bus = bus::Bus::new(ram_base, ram_size)
+            .map_err(|e| VMError::Setup("set up RAM".to_string(), e))?;
+        let bus = Rc::new(RefCell::new(bus));
         let instruction_decoder =
             Rc::new(RefCell::new(decode::DecodeCycle::new(extensions.clone())));
         let mut cpu = CPU::new(
@@ -37,48 +67,557 @@ impl VMRV32I {
         );
 
         cpu.init();
-        VMRV32I {
+        Ok(VMRV32I {
             cpu,
             bus,
             instruction_decoder,
-        }
+            extensions,
+            firmware: None,
+            dtb: None,
+            handoff: (ram_base, 0, 0),
+            rom_dtb: false,
+            images: Vec::new(),
+        })
+    }
+
+    fn read_file(file: &str) -> Result<Vec<u8>, String> {
+        std::fs::read(file).map_err(|e| e.to_string())
+    }
+
+    fn write_memory(&mut self, address: u32, buffer: &[u8]) -> Result<(), String> {
+        self.bus.borrow_mut().write_bytes(address, buffer)
     }
 
-    fn load_prog(&mut self, file: &str) -> u32 {
+    // Load "path" or "path@address", the address only applies to raw
+    // binaries, which otherwise go at the base of DRAM. The other formats
+    // carry their own addresses
+    fn load_prog(&mut self, arg: &str) -> Result<u32, String> {
+        let (file, address) = match arg.rsplit_once('@') {
+            Some((file, address)) => (file, Some(helpers::parse_address(address)?)),
+            None => (arg, None),
+        };
         println!("VM > Loading program: {}", file);
 
-        let f = File::open(file).expect("file not found");
-        let mut reader = BufReader::new(f);
-        let mut buffer = Vec::new();
-        reader.read_to_end(&mut buffer).expect("error reading file");
+        let buffer = Self::read_file(file)?;
+        let format = loader::detect(&buffer);
+        if address.is_some() && !matches!(format, Format::Raw) {
+            return Err("only raw binaries can be placed at an address".to_string());
+        }
+        match format {
+            Format::Elf => return self.load_elf(file, &buffer),
+            Format::IntelHex => return self.load_image(file, &buffer, ihex::parse),
+            Format::SRecord => return self.load_image(file, &buffer, srec::parse),
+            Format::Raw => (),
+        }
 
         println!("VM > Program size: {} bytes", buffer.len());
 
-        // put program at the base of DRAM
-        for i in 0..buffer.len() {
+        let ram_base = self.bus.borrow().ram_base();
+        let segment = Segment {
+            address: address.unwrap_or(ram_base),
+            data: buffer,
+        };
+        let size = self.load_segments(file, &[segment])?;
+        println!("VM > Program loaded, pc is 0x{:08x}", self.cpu.get_pc());
+        Ok(size)
+    }
+
+    // Check every segment lands in writable memory and doesn't overlap
+    // anything loaded before placing any of them, returning the number of
+    // bytes loaded. Loading the same image again replaces it
+    fn load_segments(&mut self, name: &str, segments: &[Segment]) -> Result<u32, String> {
+        for segment in segments {
+            self.check_placement(name, segment.address, segment.data.len() as u32)?;
+        }
+
+        self.images.retain(|image| image.0 != name);
+        let mut size = 0;
+        for segment in segments {
+            let start = segment.address;
+            let end = start + segment.data.len() as u32;
+            self.write_memory(start, &segment.data)?;
+            println!("VM > Loaded {} to 0x{:08x}-0x{:08x}", name, start, end);
+            self.images.push((name.to_string(), start, end));
+            size += segment.data.len() as u32;
+        }
+        Ok(size)
+    }
+
+    fn check_placement(&self, name: &str, start: u32, size: u32) -> Result<(), String> {
+        if !self.bus.borrow().writable(start, size) {
+            return Err(format!(
+                "0x{:08x}-0x{:08x} is not writable memory",
+                start,
+                start as u64 + size as u64
+            ));
+        }
+        let end = start + size;
+        let overlap = self
+            .images
+            .iter()
+            .find(|image| image.0 != name && start < image.2 && image.1 < end);
+        if let Some(image) = overlap {
+            return Err(format!(
+                "0x{:08x}-0x{:08x} overlaps {} at 0x{:08x}-0x{:08x}",
+                start, end, image.0, image.1, image.2
+            ));
+        }
+        Ok(())
+    }
+
+    // Read a file straight into guest RAM, kernels and initrds are big
+    // enough that the extra copy through a buffer is noticeable
+    fn place_file(&mut self, file: &str, address: u32) -> Result<u32, VMError> {
+        let failed = |e: String| VMError::Setup(format!("place {}", file), e);
+        let mut f = File::open(file).map_err(|e| failed(e.to_string()))?;
+        let size = f.metadata().map_err(|e| failed(e.to_string()))?.len() as u32;
+        self.check_placement(file, address, size).map_err(failed)?;
+        for memory in self.bus.borrow_mut().ram_slices_mut(address, size).unwrap() {
+            f.read_exact(memory).map_err(|e| failed(e.to_string()))?;
+        }
+
+        let end = address + size;
+        println!("VM > Loaded {} to 0x{:08x}-0x{:08x}", file, address, end);
+        self.images.retain(|image| image.0 != file);
+        self.images.push((file.to_string(), address, end));
+        Ok(size)
+    }
+
+    // Place something the VM generated or was told to boot, there's no
+    // sensible way to carry on if it doesn't fit
+    fn place(&mut self, name: &str, address: u32, data: Vec<u8>) -> Result<(), VMError> {
+        self.load_segments(name, &[Segment { address, data }])
+            .map(|_| ())
+            .map_err(|e| VMError::Setup(format!("place {}", name), e))
+    }
+
+    // Override the entry point with an address or a symbol name
+    fn set_entry_arg(&mut self, arg: &str) {
+        let entry = match self.cpu.symbols().find(arg) {
+            Some(symbol) => symbol.address,
+            None => match helpers::parse_address(arg) {
+                Ok(address) => address,
+                Err(e) => {
+                    println!("VM > Bad entry point: {}", e);
+                    return;
+                }
+            },
+        };
+        self.set_entry(entry);
+    }
+
+    fn set_entry(&mut self, entry: u32) {
+        self.cpu.set_pc(entry);
+        self.handoff.0 = entry;
+        println!(
+            "VM > Entry point 0x{:08x}{}",
+            entry,
+            self.cpu.symbols().describe(entry)
+        );
+    }
+
+    // Place every loadable segment and start at the entry point, the
+    // symbols are kept for naming addresses later
+    fn load_elf(&mut self, file: &str, buffer: &[u8]) -> Result<u32, String> {
+        let elf = elf::parse(buffer).map_err(|e| format!("bad ELF: {}", e))?;
+        let size = self.load_segments(file, &elf.segments)?;
+
+        println!("VM > Loaded {} symbols from {}", elf.symbols.len(), file);
+        self.cpu.add_symbols(file, elf.symbols);
+        self.set_entry(elf.entry);
+        Ok(size)
+    }
+
+    // Intel HEX and S-record files, the start address record (if there is
+    // one) becomes the entry point
+    fn load_image(
+        &mut self,
+        file: &str,
+        buffer: &[u8],
+        parse: fn(&str) -> Result<Image, String>,
+    ) -> Result<u32, String> {
+        let image = std::str::from_utf8(buffer)
+            .map_err(|e| e.to_string())
+            .and_then(parse)?;
+        let size = self.load_segments(file, &image.segments)?;
+        if let Some(entry) = image.entry {
+            self.set_entry(entry);
+        }
+        Ok(size)
+    }
+
+    // Print the address of a symbol, or the symbol at an address
+    fn lookup_symbol(&self, query: &str) {
+        let symbols = self.cpu.symbols();
+        if let Some(symbol) = symbols.find(query) {
+            println!(
+                "VM > {} is at 0x{:08x} ({} bytes)",
+                symbol.name, symbol.address, symbol.size
+            );
+            return;
+        }
+        match helpers::parse_address(query) {
+            Ok(address) if symbols.lookup(address).is_some() => {
+                println!("VM > 0x{:08x} is{}", address, symbols.describe(address));
+            }
+            _ => println!("VM > No symbol matches {}", query),
+        }
+    }
+
+    fn load_firmware(&mut self, file: &str) -> Result<(), VMError> {
+        println!("VM > Loading firmware: {}", file);
+        let size = self
+            .load_prog(file)
+            .map_err(|e| VMError::Setup(format!("load firmware {}", file), e))?;
+        self.firmware = Some(size);
+        Ok(())
+    }
+
+    // Set the machine up the way Linux expects to be entered, a0 = hart id
+    // and a1 = the device tree. Without firmware the kernel sits at the base
+    // of DRAM and is entered directly, with firmware it's the next stage and
+    // a2 points at the fw_dynamic_info telling the firmware where it is
+    fn boot_linux(
+        &mut self,
+        kernel: Option<&str>,
+        initrd: Option<&str>,
+        bootargs: &str,
+    ) -> Result<(), VMError> {
+        let (ram_base, ram_size, ram_top) = {
+            let bus = self.bus.borrow();
+            (bus.ram_base(), bus.ram_size(), bus.ram_top())
+        };
+        let kernel_base = match self.firmware {
+            Some(size) => boot::payload_address(ram_base, size),
+            None => ram_base,
+        };
+
+        let mut kernel_end = kernel_base;
+        if let Some(file) = kernel {
+            println!("VM > Booting kernel: {}", file);
+            kernel_end += self.place_file(file, kernel_base)?;
+        }
+
+        let initrd_range = match initrd {
+            Some(file) => {
+                let failed = |e: String| VMError::Setup(format!("place initrd {}", file), e);
+                let size = std::fs::metadata(file)
+                    .map_err(|e| failed(e.to_string()))?
+                    .len() as u32;
+                let start = boot::initrd_address(ram_size, ram_top, kernel_base, kernel_end, size)
+                    .ok_or_else(|| failed(format!("{} bytes don't fit in RAM", size)))?;
+                let end = start + self.place_file(file, start)?;
+                Some((start, end))
+            }
+            None => None,
+        };
+
+        let dtb = self.generate_dtb(bootargs, initrd_range);
+        let dtb_address = boot::dtb_address(ram_top, dtb.len() as u32);
+        self.place("device tree", dtb_address, dtb.clone())?;
+        println!("VM > Kernel command line: {}", bootargs);
+
+        let hartid = self.cpu.get_hartid();
+        self.cpu.set_reg(10, hartid); // a0
+        self.cpu.set_reg(11, dtb_address); // a1
+
+        if self.firmware.is_some() {
+            // fw_jump ignores a2 and jumps to its built in address, which
+            // for the generic platform is where payload_address puts us
+            let info = boot::fw_dynamic_info(kernel_base, boot::NEXT_MODE_S, hartid);
+            let info_address = boot::fw_dynamic_info_address(dtb_address);
+            self.place("fw_dynamic_info", info_address, info)?;
+            println!("VM > Firmware next stage at 0x{:08x}", kernel_base);
+            self.cpu.set_pc(ram_base);
+            self.cpu.set_reg(12, info_address); // a2
+            self.handoff = (ram_base, dtb_address, info_address);
+        } else {
+            self.cpu.set_pc(kernel_base);
+            self.handoff = (kernel_base, dtb_address, 0);
+        }
+
+        // tracing every instruction would bury the kernel's console
+        self.cpu.set_trace(false);
+        self.dtb = Some(dtb);
+        Ok(())
+    }
+
+    // Start from a boot ROM the way hardware does, the trampoline sets up
+    // the registers and jumps to whatever would otherwise be entered directly
+    fn install_boot_rom(&mut self, base: u32) -> Result<(), VMError> {
+        let (entry, a1, a2) = self.handoff;
+        let data = if self.rom_dtb {
+            let dtb = match &self.dtb {
+                Some(dtb) => dtb.clone(),
+                None => self.generate_dtb(boot::DEFAULT_BOOTARGS, None),
+            };
+            // hand the payload the ROM copy instead
+            let a1 = base + rom::ROM_DTB as u32;
+            let mut data = rom::trampoline(entry, a1, a2);
+            data.resize(rom::ROM_DTB, 0);
+            data.extend_from_slice(&dtb);
+            println!(
+                "VM > Device tree ({} bytes) copied to ROM at 0x{:08x}",
+                dtb.len(),
+                a1
+            );
+            data
+        } else {
+            rom::trampoline(entry, a1, a2)
+        };
+
+        self.bus
+            .borrow_mut()
+            .map_rom(rom::ROM::new(base, data))
+            .map_err(|e| VMError::Setup("map the boot ROM".to_string(), e))?;
+        println!("VM > Boot ROM jumps to 0x{:08x}", entry);
+        self.cpu.set_reset_vector(base);
+        Ok(())
+    }
+
+    fn map_file_memory(&mut self, spec: &str) -> Result<(), VMError> {
+        let mapped = FileMemory::from_spec(spec).and_then(|(base, memory)| {
+            let size = memory.size();
+            self.bus.borrow_mut().register(base, size, Box::new(memory))
+        });
+        mapped.map_err(|e| VMError::Setup(format!("map {}", spec), e))
+    }
+
+    fn map_flash(&mut self, spec: &str) -> Result<(), VMError> {
+        let mapped = Flash::from_spec(spec).and_then(|(base, flash)| {
+            let size = flash.size();
+            self.bus.borrow_mut().register(base, size, Box::new(flash))
+        });
+        mapped.map_err(|e| VMError::Setup(format!("map flash {}", spec), e))
+    }
+
+    // The first port is the console UART that's always there, the others
+    // are added as they're asked for
+    fn attach_serial(&mut self, index: usize, spec: &str) -> Result<(), VMError> {
+        let failed = |e: String| VMError::Setup(format!("add serial port {}", spec), e);
+        if index >= bus::MAX_UARTS {
+            return Err(failed(format!(
+                "at most {} serial ports are supported",
+                bus::MAX_UARTS
+            )));
+        }
+        let backend = serial::from_spec(spec).map_err(failed)?;
+        let (base, irq) = bus::Bus::uart_slot(index);
+        let mut bus = self.bus.borrow_mut();
+        let attached = match bus.device_at_mut::<uart::UART>(base) {
+            Some(uart) => {
+                uart.set_backend(backend);
+                Ok(())
+            }
+            None => bus.register(
+                base,
+                bus::UART_SIZE,
+                Box::new(uart::UART::new(irq, backend)),
+            ),
+        };
+        attached.map_err(failed)
+    }
+
+    // virtio devices go in the first free virtio-mmio slot
+    fn attach_virtio(&mut self, device: Box<dyn VirtioDevice>) -> Result<(), String> {
+        let mut bus = self.bus.borrow_mut();
+        let (base, irq) = bus
+            .virtio_slot()
+            .ok_or("all the virtio-mmio slots are taken".to_string())?;
+        bus.register(
+            base,
+            bus::VIRTIO_SIZE,
+            Box::new(VirtioMmio::new(device, irq)),
+        )
+    }
+
+    fn attach_drive(&mut self, spec: &str) -> Result<(), VMError> {
+        let attached = Block::from_spec(spec).and_then(|block| self.attach_virtio(Box::new(block)));
+        attached.map_err(|e| VMError::Setup(format!("attach drive {}", spec), e))
+    }
+
+    fn attach_nic(&mut self, index: usize, spec: &str) -> Result<(), VMError> {
+        let attached =
+            Net::from_spec(spec, index).and_then(|nic| self.attach_virtio(Box::new(nic)));
+        attached.map_err(|e| VMError::Setup(format!("attach nic {}", spec), e))
+    }
+
+    fn attach_share(&mut self, spec: &str) -> Result<(), VMError> {
+        let attached = Share::from_spec(spec).and_then(|share| self.attach_virtio(Box::new(share)));
+        attached.map_err(|e| VMError::Setup(format!("share {}", spec), e))
+    }
+
+    fn set_rtc_epoch(&mut self, seconds: u64) {
+        if let Some(rtc) = self.bus.borrow_mut().device_mut::<rtc::RTC>() {
+            rtc.set_epoch(seconds);
+        }
+    }
+
+    fn attach_rng(&mut self, source: &str) -> Result<(), VMError> {
+        let attached = Rng::from_spec(source).and_then(|rng| self.attach_virtio(Box::new(rng)));
+        attached.map_err(|e| VMError::Setup(format!("add entropy from {}", source), e))
+    }
+
+    // Ports after the first join the console that's already there
+    fn attach_vport(&mut self, spec: &str) -> Result<(), VMError> {
+        let mut added = None;
+        for virtio in self.bus.borrow_mut().devices_mut::<VirtioMmio>() {
+            if let Some(console) = virtio.device_mut().as_any_mut().downcast_mut::<Console>() {
+                added = Some(console.add_port(spec));
+                virtio.reset();
+                break;
+            }
+        }
+        let attached = match added {
+            Some(added) => added,
+            None => {
+                Console::from_spec(spec).and_then(|console| self.attach_virtio(Box::new(console)))
+            }
+        };
+        attached.map_err(|e| VMError::Setup(format!("add console port {}", spec), e))
+    }
+
+    fn attach_framebuffer(&mut self, spec: &str) -> Result<(), VMError> {
+        let mapped = Framebuffer::from_spec(spec).and_then(|(base, framebuffer)| {
+            let size = framebuffer.size();
+            let base = base.unwrap_or(bus::FRAMEBUFFER_BASE);
             self.bus
                 .borrow_mut()
-                .store_8(i as u32 + bus::DRAM_BASE, buffer[i]);
+                .register(base, size, Box::new(framebuffer))
+        });
+        mapped.map_err(|e| VMError::Setup(format!("map framebuffer {}", spec), e))
+    }
+
+    fn screenshot_at_exit(&mut self, file: &str) {
+        if let Some(framebuffer) = self.bus.borrow_mut().device_mut::<Framebuffer>() {
+            framebuffer.screenshot_at_exit(file);
         }
+    }
 
-        println!("VM > Program loaded to 0x{:08x}", self.cpu.get_pc());
-        buffer.len() as u32
+    fn dump_frames(&mut self, spec: &str) -> Result<(), VMError> {
+        match self.bus.borrow_mut().device_mut::<Framebuffer>() {
+            Some(framebuffer) => framebuffer
+                .dump_frames(spec)
+                .map_err(|e| VMError::Setup(format!("dump frames to {}", spec), e)),
+            None => Ok(()),
+        }
+    }
+
+    fn screenshot(&self, file: &str) {
+        let bus = self.bus.borrow();
+        let framebuffer = match bus.device::<Framebuffer>() {
+            Some(framebuffer) => framebuffer,
+            None => {
+                println!("VM > No framebuffer to take a screenshot of");
+                return;
+            }
+        };
+        match framebuffer.screenshot(file) {
+            Ok(()) => println!("VM > Screenshot written to {}", file),
+            Err(e) => println!("VM > Failed to write screenshot {}", e),
+        }
+    }
+
+    // Every NIC's link goes up or down together
+    fn set_link(&mut self, state: &str) {
+        let up = match state {
+            "up" => true,
+            "down" => false,
+            _ => {
+                println!("VM > Link can be up or down");
+                return;
+            }
+        };
+        for virtio in self.bus.borrow_mut().devices_mut::<VirtioMmio>() {
+            if let Some(nic) = virtio.device_mut().as_any_mut().downcast_mut::<Net>() {
+                nic.set_link(up);
+                virtio.config_changed();
+            }
+        }
+    }
+
+    fn print_map(&self) {
+        for region in self.bus.borrow().regions() {
+            // RAM only costs the pages that have been touched
+            let resident = match region.device.as_any().downcast_ref::<ram::RAM>() {
+                Some(ram) => format!(", {} pages resident", ram.resident_pages()),
+                None => String::new(),
+            };
+            println!(
+                "VM > 0x{:08x}-0x{:08x} {}{}",
+                region.base,
+                region.base as u64 + region.size as u64 - 1,
+                region.device.name(),
+                resident
+            );
+            println!("   > {}", region.pma);
+        }
+    }
+
+    // Pages written since the last time we asked, then start counting again
+    fn print_dirty(&mut self) {
+        let mut bus = self.bus.borrow_mut();
+        let pages = bus.dirty_pages();
+        println!("VM > {} dirty pages", pages.len());
+        for page in pages.iter() {
+            println!("VM > 0x{:08x}", page);
+        }
+        bus.clear_dirty();
+    }
+
+    // Devices go back to their power on state, memory keeps what was
+    // loaded so the program can be run again
+    fn reset(&mut self) {
+        self.bus.borrow_mut().reset();
+        self.cpu.init();
+        // the boot ROM sets the registers up itself, otherwise we do it
+        if self.bus.borrow().device::<rom::ROM>().is_none() {
+            let (entry, a1, a2) = self.handoff;
+            self.cpu.set_pc(entry);
+            self.cpu.set_reg(10, 0); // a0, the hart id
+            self.cpu.set_reg(11, a1);
+            self.cpu.set_reg(12, a2);
+        }
+    }
+
+    fn generate_dtb(&self, bootargs: &str, initrd: Option<(u32, u32)>) -> Vec<u8> {
+        let chosen = dtb::Chosen {
+            bootargs: bootargs.to_string(),
+            initrd,
+        };
+        dtb::generate(&self.bus.borrow(), &self.extensions, &chosen)
+    }
+
+    // Write out the device tree the guest was given, or the one it would be
+    // given if nothing has been booted yet
+    fn dump_dtb(&self, file: &str) {
+        let dtb = match &self.dtb {
+            Some(dtb) => dtb.clone(),
+            None => self.generate_dtb(boot::DEFAULT_BOOTARGS, None),
+        };
+        match std::fs::write(file, &dtb) {
+            Ok(_) => println!("VM > Device tree ({} bytes) written to {}", dtb.len(), file),
+            Err(e) => println!("VM > Failed to write device tree to {}: {}", file, e),
+        }
     }
 
     fn has_load_prog(&self) -> bool {
-        self.bus.borrow_mut().load_32(bus::DRAM_BASE) != 0
+        !self.images.is_empty()
     }
 
     fn dump_prog(&mut self, size: u32) {
         println!("VM > Dumping program (virtual addresses)");
-        for i in 0..size {
-            if i % 4 == 0 {
-                println!(
-                    "VM > 0x{:08x}: 0x{:08x}",
-                    i,
-                    self.bus.borrow_mut().load_32(i + bus::DRAM_BASE)
-                );
-            }
+        let mut buffer = vec![0; size as usize];
+        let mut bus = self.bus.borrow_mut();
+        let ram_base = bus.ram_base();
+        if let Err(e) = bus.read_bytes(ram_base, &mut buffer) {
+            println!("{}", e);
+            return;
+        }
+        for (i, word) in buffer.chunks(4).enumerate() {
+            let mut bytes = [0; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            println!("VM > 0x{:08x}: 0x{:08x}", i * 4, u32::from_le_bytes(bytes));
         }
     }
 
@@ -95,50 +634,145 @@ impl VMRV32I {
         self.cpu.dump_reg();
     }
 
-    fn dispatch(&mut self) {
-        match self.cpu.exec() {
-            Ok(_) => println!("VM > Program exited peacefully"),
-            Err(e) => println!("VM > Program exited violently with error: {}", e),
+    // Run with the console attached, returns how the user escaped if they
+    // did. Going to the prompt leaves the program where it is so it can be
+    // run again
+    fn dispatch(&mut self) -> Option<console::Escape> {
+        let result = {
+            let _console = console::attach();
+            self.cpu.exec()
+        };
+        let escape = console::take_escape();
+        match (result, escape) {
+            (Ok(_), Some(console::Escape::Monitor)) => {
+                println!(
+                    "VM > Stopped at 0x{:08x}, run to continue",
+                    self.cpu.get_pc()
+                );
+                return escape;
+            }
+            (Ok(_), Some(console::Escape::Quit)) => (),
+            (Ok(_), None) => println!("VM > Program exited peacefully"),
+            (Err(e), _) => println!("VM > Program exited violently with error: {}", e),
         }
 
         self.cpu.dump_reg();
 
         println!("VM > CPU has stalled");
+        escape
     }
 }
 
+// An address given on the command line
+fn address_arg(option: &str, arg: &str) -> Result<u32, VMError> {
+    helpers::parse_address(arg).map_err(|e| VMError::Argument(format!("{} {}", option, arg), e))
+}
+
 fn main() {
+    if let Err(e) = run() {
+        println!("VM > {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), VMError> {
     println!("VM > Loading CPU Management Engine");
     let manager = management::Management::new();
     println!("VM > Starting Up");
-    let mut vm = VMRV32I::new();
+    let params = manager.vm_params();
+    let mut ram_base = bus::DEFAULT_RAM_BASE;
+    let mut ram_size = bus::DEFAULT_RAM_SIZE;
+    for action in params.iter() {
+        match action.action {
+            management::Action::RamBase => ram_base = address_arg("--ram-base", &action.arg)?,
+            management::Action::RamSize => ram_size = address_arg("--memory", &action.arg)?,
+            _ => (),
+        }
+    }
+    let mut vm = VMRV32I::new(ram_base, ram_size)?;
 
     let mut should_run = false;
-    manager
-        .vm_params()
-        .iter()
-        .for_each(|action| match action.action {
+    let mut serial_ports = 0;
+    let mut nics = 0;
+    let mut kernel: Option<String> = None;
+    let mut initrd: Option<String> = None;
+    let mut bootargs = boot::DEFAULT_BOOTARGS.to_string();
+    for action in params.iter() {
+        match action.action {
             management::Action::Load => {
                 println!("VM > Loading file: {}", action.arg);
-                vm.load_prog(&action.arg);
+                vm.load_prog(&action.arg)
+                    .map_err(|e| VMError::Setup(format!("load {}", action.arg), e))?;
+            }
+            management::Action::MemFile => vm.map_file_memory(&action.arg)?,
+            management::Action::Flash => vm.map_flash(&action.arg)?,
+            management::Action::Serial => {
+                vm.attach_serial(serial_ports, &action.arg)?;
+                serial_ports += 1;
+            }
+            management::Action::Drive => vm.attach_drive(&action.arg)?,
+            management::Action::Share => vm.attach_share(&action.arg)?,
+            management::Action::RtcEpoch => {
+                let seconds = action.arg.parse().map_err(|e: std::num::ParseIntError| {
+                    VMError::Argument(format!("--rtc-epoch {}", action.arg), e.to_string())
+                })?;
+                vm.set_rtc_epoch(seconds);
+            }
+            management::Action::Rng => vm.attach_rng(&action.arg)?,
+            management::Action::Vport => vm.attach_vport(&action.arg)?,
+            management::Action::Framebuffer => vm.attach_framebuffer(&action.arg)?,
+            management::Action::ExitScreenshot => vm.screenshot_at_exit(&action.arg),
+            management::Action::FrameDump => vm.dump_frames(&action.arg)?,
+            management::Action::Nic => {
+                vm.attach_nic(nics, &action.arg)?;
+                nics += 1;
+            }
+            management::Action::Sbi => vm.cpu.enable_sbi(),
+            management::Action::BusErrors => vm.cpu.set_bus_errors(match action.arg.as_str() {
+                "trap" => BusErrors::Trap,
+                _ => BusErrors::Stop,
+            }),
+            management::Action::Append => bootargs = action.arg.clone(),
+            management::Action::Initrd => initrd = Some(action.arg.clone()),
+            management::Action::Bios => vm.load_firmware(&action.arg)?,
+            management::Action::Kernel => kernel = Some(action.arg.clone()),
+            management::Action::Boot => {
+                vm.boot_linux(kernel.as_deref(), initrd.as_deref(), &bootargs)?;
+            }
+            management::Action::Entry => vm.set_entry_arg(&action.arg),
+            management::Action::RomDtb => vm.rom_dtb = true,
+            management::Action::BootRom => {
+                vm.install_boot_rom(address_arg("--boot-rom", &action.arg)?)?;
             }
+            management::Action::ResetPc => {
+                vm.cpu
+                    .set_reset_vector(address_arg("--reset-pc", &action.arg)?);
+            }
+            management::Action::DumpDtb => vm.dump_dtb(&action.arg),
             management::Action::Run => {
                 println!("VM > Running program");
                 should_run = true;
             }
             _ => (),
-        });
+        }
+    }
 
     if should_run && vm.has_load_prog() {
-        vm.dispatch();
-        vm.dump_relavent_memory();
-        return;
+        match vm.dispatch() {
+            Some(console::Escape::Monitor) => (),
+            _ => {
+                vm.dump_relavent_memory();
+                return Ok(());
+            }
+        }
     } else if should_run {
         println!("VM > CPU has stalled");
-        return;
+        return Ok(());
     }
 
-    println!("VM > No program loaded");
+    if !vm.has_load_prog() {
+        println!("VM > No program loaded");
+    }
 
     // event loop for interactive mode
     loop {
@@ -146,7 +780,9 @@ fn main() {
         match action.action {
             management::Action::Load => {
                 println!("VM > Loading file: {}", action.arg);
-                vm.load_prog(&action.arg);
+                if let Err(e) = vm.load_prog(&action.arg) {
+                    println!("VM > Failed to load {}: {}", action.arg, e);
+                }
             }
             management::Action::Run => {
                 println!("VM > Running program");
@@ -154,7 +790,10 @@ fn main() {
                     println!("VM > No program loaded");
                     continue;
                 }
-                vm.dispatch();
+                if vm.dispatch() == Some(console::Escape::Quit) {
+                    println!("VM > Quitting");
+                    break;
+                }
             }
             management::Action::Step => {
                 println!("VM > Stepping program");
@@ -168,6 +807,14 @@ fn main() {
                 println!("VM > Dumping program");
                 vm.dump_prog(0x100);
             }
+            management::Action::DumpDtb => vm.dump_dtb(&action.arg),
+            management::Action::Symbol => vm.lookup_symbol(&action.arg),
+            management::Action::Entry => vm.set_entry_arg(&action.arg),
+            management::Action::Map => vm.print_map(),
+            management::Action::Link => vm.set_link(&action.arg),
+            management::Action::Screenshot => vm.screenshot(&action.arg),
+            management::Action::Dirty => vm.print_dirty(),
+            management::Action::Reset => vm.reset(),
             management::Action::Quit => {
                 println!("VM > Quitting");
                 break;
@@ -175,4 +822,5 @@ fn main() {
             _ => (),
         }
     }
+    Ok(())
 }
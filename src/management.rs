@@ -1,7 +1,10 @@
-use std::io::{stdin, Write};
+use std::io::Write;
 
 use clap::Parser;
 
+use crate::helpers::{parse_address, parse_size};
+use crate::system::console;
+
 #[derive(Debug)]
 pub struct VMAction {
     pub action: Action,
@@ -10,16 +13,52 @@ pub struct VMAction {
 
 #[derive(Debug)]
 pub enum Action {
+    RamBase,
+    RamSize,
+    MemFile,
+    Flash,
+    Serial,
+    Drive,
+    Nic,
+    Share,
+    RtcEpoch,
+    Rng,
+    Vport,
+    Framebuffer,
+    ExitScreenshot,
+    FrameDump,
     Load,
+    Bios,
+    Kernel,
+    Boot,
+    Entry,
+    RomDtb,
+    BootRom,
+    ResetPc,
+    Initrd,
+    Append,
+    Sbi,
+    BusErrors,
     Step,
     Run,
+    #[allow(dead_code)]
     Inspect,
+    #[allow(dead_code)]
     Dump,
+    DumpDtb,
+    Symbol,
+    Map,
+    Link,
+    Screenshot,
+    Dirty,
+    Reset,
+    #[allow(dead_code)]
     Reg,
     Quit,
 }
 
 pub struct Management {
+    #[allow(dead_code)]
     pause: bool,
 }
 
@@ -28,8 +67,114 @@ pub struct Management {
 #[command(version = "0.1.0")]
 #[command(author = "Ben Kyd <benjaminkyd@gmail>")]
 struct Cli {
+    /// RAM size, with a K, M or G suffix. Pages are only allocated once
+    /// the guest writes to them
+    #[arg(short, long, value_name = "SIZE", value_parser = parse_size)]
+    memory: Option<u32>,
+    /// Where RAM starts in the physical address space
+    #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+    ram_base: Option<u32>,
+    /// Map memory backed by a host file, so what the guest writes is there
+    /// next run. Options are size=SIZE, ro and write-through (the default
+    /// writes back on exit). Can be repeated
+    #[arg(long, value_name = "FILE@ADDR[,OPTIONS]")]
+    mem_file: Vec<String>,
+    /// Map a CFI NOR flash chip backed by a host image, programmed and
+    /// erased through its command interface. Options are size=SIZE,
+    /// block=SIZE, width=1|2|4 and intel or amd for the command set. Can be
+    /// repeated
+    #[arg(long, value_name = "FILE@ADDR[,OPTIONS]")]
+    flash: Vec<String>,
+    /// Host side of a UART: stdio, file:OUT[,in=IN], unix:PATH, tcp:PORT
+    /// or pty. The first is the console UART, each one after that adds
+    /// another UART
+    #[arg(long, value_name = "BACKEND")]
+    serial: Vec<String>,
+    /// Attach a raw disk image as a virtio-blk device, the first is
+    /// /dev/vda. Options are ro, and cow to keep writes in memory so the
+    /// image is never changed. Can be repeated
+    #[arg(long, value_name = "FILE[,OPTIONS]")]
+    drive: Vec<String>,
+    /// Add a virtio-net NIC on a host-local backend: loopback, hub:NAME,
+    /// unix:PATH,peer=PATH or pcap:FILE. Options are mac=MAC, link=up|down
+    /// and pcap=FILE to capture its traffic. Can be repeated
+    #[arg(long, value_name = "BACKEND[,OPTIONS]")]
+    nic: Vec<String>,
+    /// Share a host directory over virtio-9p, the guest mounts it with
+    /// mount -t 9p -o trans=virtio,version=9p2000.L TAG DIR. Options are
+    /// tag=TAG (hostshare unless given) and ro. Can be repeated
+    #[arg(long, value_name = "DIR[,OPTIONS]")]
+    share: Vec<String>,
+    /// Start the RTC this many seconds after 1970 rather than at the
+    /// host's time, so every run sees the same date
+    #[arg(long, value_name = "SECONDS")]
+    rtc_epoch: Option<u64>,
+    /// Add a virtio-rng device, its entropy from the host or from a
+    /// generator seeded with seed=N so every run gets the same
+    #[arg(long, value_name = "host|seed=N", num_args = 0..=1, default_missing_value = "host")]
+    rng: Option<String>,
+    /// Add a port to the virtio-console, on any of the --serial backends.
+    /// Ports without a name are consoles (hvc0 onwards), a named port is
+    /// /dev/virtio-ports/NAME in the guest. Can be repeated
+    #[arg(long, value_name = "BACKEND[,name=NAME]")]
+    vport: Vec<String>,
+    /// Add a linear framebuffer, described to the guest as a
+    /// simple-framebuffer. Formats are r5g6b5, r8g8b8, x8r8g8b8 (the
+    /// default), a8r8g8b8, x8b8g8r8 and a8b8g8r8
+    #[arg(long, value_name = "WIDTHxHEIGHT[@ADDR][,format=FORMAT]")]
+    framebuffer: Option<String>,
+    /// Write what's on the framebuffer to a PNG or PPM file when the VM
+    /// exits
+    #[arg(long, value_name = "FILE", requires = "framebuffer")]
+    screenshot: Option<String>,
+    /// Write the framebuffer to DIR every MS milliseconds (1000 unless
+    /// given) as frame-NNNNN.png, or .ppm with the ppm option. Frames that
+    /// haven't changed aren't written again
+    #[arg(long, value_name = "DIR[,every=MS][,ppm]", requires = "framebuffer")]
+    frame_dump: Option<String>,
+    /// Program to load, raw binaries go at the base of DRAM unless an
+    /// address is given. Can be repeated
+    #[arg(short, long, value_name = "FILE[@ADDR]")]
+    load: Vec<String>,
+    /// Start executing here instead of the loaded program's entry point,
+    /// an address or a symbol
+    #[arg(long, visible_alias = "pc", value_name = "ADDR|SYMBOL")]
+    entry: Option<String>,
+    /// Firmware (e.g. OpenSBI fw_dynamic or fw_jump) run in M-mode from the
+    /// base of DRAM, the kernel becomes its next stage payload
+    #[arg(short, long, value_name = "FILE", conflicts_with = "sbi")]
+    bios: Option<String>,
+    /// Linux kernel image to boot, placed at the base of DRAM or after the
+    /// firmware
     #[arg(short, long, value_name = "FILE")]
-    load: Option<String>,
+    kernel: Option<String>,
+    /// initramfs to hand to the kernel
+    #[arg(short, long, value_name = "FILE", requires = "kernel")]
+    initrd: Option<String>,
+    /// Kernel command line
+    #[arg(short, long, value_name = "ARGS", requires = "kernel")]
+    append: Option<String>,
+    /// Map a boot ROM holding the reset vector trampoline and start there,
+    /// at 0x1000 unless an address is given
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "0x1000",
+          value_parser = parse_address)]
+    boot_rom: Option<u32>,
+    /// Put a copy of the device tree in the boot ROM
+    #[arg(long, requires = "boot_rom")]
+    rom_dtb: bool,
+    /// Address the hart starts executing from out of reset
+    #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+    reset_pc: Option<u32>,
+    /// Write the generated device tree blob to a file
+    #[arg(long, value_name = "FILE")]
+    dump_dtb: Option<String>,
+    /// Handle SBI calls in the VM and start the payload in S-mode
+    #[arg(long)]
+    sbi: bool,
+    /// What an access nothing on the bus will take does, stop the VM with a
+    /// diagnostic (the default) or raise an access fault in the guest
+    #[arg(long, value_name = "stop|trap", value_parser = ["stop", "trap"])]
+    bus_errors: Option<String>,
     #[arg(short, long)]
     run: bool,
 }
@@ -43,12 +188,200 @@ impl Management {
         let cli = Cli::parse();
         let mut actions = Vec::new();
 
-        match cli.load {
-            Some(file) => actions.push(VMAction {
+        // the machine has to be built before anything can be loaded into it
+        if let Some(base) = cli.ram_base {
+            actions.push(VMAction {
+                action: Action::RamBase,
+                arg: format!("0x{:08x}", base),
+            });
+        }
+
+        if let Some(size) = cli.memory {
+            actions.push(VMAction {
+                action: Action::RamSize,
+                arg: format!("0x{:08x}", size),
+            });
+        }
+
+        for spec in cli.mem_file {
+            actions.push(VMAction {
+                action: Action::MemFile,
+                arg: spec,
+            });
+        }
+
+        for spec in cli.flash {
+            actions.push(VMAction {
+                action: Action::Flash,
+                arg: spec,
+            });
+        }
+
+        for spec in cli.serial {
+            actions.push(VMAction {
+                action: Action::Serial,
+                arg: spec,
+            });
+        }
+
+        for spec in cli.drive {
+            actions.push(VMAction {
+                action: Action::Drive,
+                arg: spec,
+            });
+        }
+
+        for spec in cli.nic {
+            actions.push(VMAction {
+                action: Action::Nic,
+                arg: spec,
+            });
+        }
+
+        for spec in cli.share {
+            actions.push(VMAction {
+                action: Action::Share,
+                arg: spec,
+            });
+        }
+
+        if let Some(seconds) = cli.rtc_epoch {
+            actions.push(VMAction {
+                action: Action::RtcEpoch,
+                arg: seconds.to_string(),
+            });
+        }
+
+        if let Some(source) = cli.rng {
+            actions.push(VMAction {
+                action: Action::Rng,
+                arg: source,
+            });
+        }
+
+        for spec in cli.vport {
+            actions.push(VMAction {
+                action: Action::Vport,
+                arg: spec,
+            });
+        }
+
+        if let Some(spec) = cli.framebuffer {
+            actions.push(VMAction {
+                action: Action::Framebuffer,
+                arg: spec,
+            });
+        }
+
+        if let Some(file) = cli.screenshot {
+            actions.push(VMAction {
+                action: Action::ExitScreenshot,
+                arg: file,
+            });
+        }
+
+        if let Some(spec) = cli.frame_dump {
+            actions.push(VMAction {
+                action: Action::FrameDump,
+                arg: spec,
+            });
+        }
+
+        for file in cli.load {
+            actions.push(VMAction {
                 action: Action::Load,
                 arg: file,
-            }),
-            None => (),
+            });
+        }
+
+        if cli.sbi {
+            actions.push(VMAction {
+                action: Action::Sbi,
+                arg: String::new(),
+            });
+        }
+
+        if let Some(mode) = cli.bus_errors {
+            actions.push(VMAction {
+                action: Action::BusErrors,
+                arg: mode,
+            });
+        }
+
+        // the kernel's boot arguments have to be known before it is placed
+        if let Some(args) = cli.append {
+            actions.push(VMAction {
+                action: Action::Append,
+                arg: args,
+            });
+        }
+
+        if let Some(file) = cli.initrd {
+            actions.push(VMAction {
+                action: Action::Initrd,
+                arg: file,
+            });
+        }
+
+        // the firmware decides where the kernel goes, so it's loaded first
+        let boot = cli.bios.is_some() || cli.kernel.is_some();
+        if let Some(file) = cli.bios {
+            actions.push(VMAction {
+                action: Action::Bios,
+                arg: file,
+            });
+        }
+
+        if let Some(file) = cli.kernel {
+            actions.push(VMAction {
+                action: Action::Kernel,
+                arg: file,
+            });
+        }
+
+        // a firmware with an embedded payload boots without a kernel
+        if boot {
+            actions.push(VMAction {
+                action: Action::Boot,
+                arg: String::new(),
+            });
+        }
+
+        if let Some(entry) = cli.entry {
+            actions.push(VMAction {
+                action: Action::Entry,
+                arg: entry,
+            });
+        }
+
+        // the ROM hands over to whatever was booted
+        if cli.rom_dtb {
+            actions.push(VMAction {
+                action: Action::RomDtb,
+                arg: String::new(),
+            });
+        }
+
+        if let Some(address) = cli.boot_rom {
+            actions.push(VMAction {
+                action: Action::BootRom,
+                arg: format!("0x{:08x}", address),
+            });
+        }
+
+        if let Some(address) = cli.reset_pc {
+            actions.push(VMAction {
+                action: Action::ResetPc,
+                arg: format!("0x{:08x}", address),
+            });
+        }
+
+        // after booting, so the dump has its initrd and bootargs
+        if let Some(file) = cli.dump_dtb {
+            actions.push(VMAction {
+                action: Action::DumpDtb,
+                arg: file,
+            });
         }
 
         if cli.run {
@@ -64,17 +397,72 @@ impl Management {
         print!("VM >> ");
         std::io::stdout().flush().unwrap();
 
-        let mut input = String::new();
-        stdin().read_line(&mut input).unwrap();
+        let input = console::read_line();
 
-        let mut parts = input.trim().split_whitespace();
+        let mut parts = input.split_whitespace();
         let command = parts.next().unwrap();
         let mut args = parts;
 
         match command {
-            "load" => VMAction {
-                action: Action::Load,
-                arg: args.next().unwrap().to_string(),
+            "load" => match args.next() {
+                Some(arg) => VMAction {
+                    action: Action::Load,
+                    arg: arg.to_string(),
+                },
+                None => {
+                    println!("VM > Usage: load <file>[@addr]");
+                    self.prompt()
+                }
+            },
+            "dtb" => match args.next() {
+                Some(arg) => VMAction {
+                    action: Action::DumpDtb,
+                    arg: arg.to_string(),
+                },
+                None => {
+                    println!("VM > Usage: dtb <file>");
+                    self.prompt()
+                }
+            },
+            "entry" => match args.next() {
+                Some(arg) => VMAction {
+                    action: Action::Entry,
+                    arg: arg.to_string(),
+                },
+                None => {
+                    println!("VM > Usage: entry <addr|symbol>");
+                    self.prompt()
+                }
+            },
+            "sym" => match args.next() {
+                Some(arg) => VMAction {
+                    action: Action::Symbol,
+                    arg: arg.to_string(),
+                },
+                None => {
+                    println!("VM > Usage: sym <name|address>");
+                    self.prompt()
+                }
+            },
+            "map" => VMAction {
+                action: Action::Map,
+                arg: String::new(),
+            },
+            "link" => VMAction {
+                action: Action::Link,
+                arg: args.next().unwrap_or("").to_string(),
+            },
+            "screenshot" => VMAction {
+                action: Action::Screenshot,
+                arg: args.next().unwrap_or("").to_string(),
+            },
+            "dirty" => VMAction {
+                action: Action::Dirty,
+                arg: String::new(),
+            },
+            "reset" => VMAction {
+                action: Action::Reset,
+                arg: String::new(),
             },
             "step" => VMAction {
                 action: Action::Step,
@@ -90,7 +478,15 @@ impl Management {
             },
             "help" | "h" => {
                 println!("VM > Commands:");
-                println!("VM > load <file> - load a program into memory");
+                println!("VM > load <file>[@addr] - load a binary, ELF, HEX or S-record file");
+                println!("VM > entry <addr|symbol> - set where execution starts");
+                println!("VM > dtb <file> - write the device tree blob to a file");
+                println!("VM > sym <name|address> - look up a symbol from the loaded ELF");
+                println!("VM > map - show what is mapped where on the bus");
+                println!("VM > link <up|down> - take the network links up or down");
+                println!("VM > screenshot <file> - write the framebuffer to a PNG or PPM file");
+                println!("VM > dirty - list the RAM pages written since the last dirty");
+                println!("VM > reset - reset the devices and restart the hart");
                 println!("VM > step - step through the program");
                 println!("VM > run - run the program");
                 println!("VM > quit - quit the program");
@@ -0,0 +1,77 @@
+// Linux boot protocol
+//
+// The kernel image goes at the base of RAM, the initramfs far enough above
+// it that the kernel won't clobber it while setting itself up and the device
+// tree right at the top of RAM. The hart id is passed in a0 and the address
+// of the device tree in a1.
+//
+// When booting through firmware such as OpenSBI the firmware takes the base
+// of RAM and the kernel becomes its next stage. fw_dynamic builds find the
+// kernel through the fw_dynamic_info structure pointed to by a2, fw_jump
+// builds jump to a fixed address, the same one we load the kernel at.
+
+pub const DEFAULT_BOOTARGS: &str = "earlycon=uart8250,mmio,0x10000000 console=ttyS0";
+
+// OpenSBI's struct fw_dynamic_info, version 2 added boot_hart
+pub const FW_DYNAMIC_INFO_MAGIC: u32 = 0x4942534f; // "OSBI"
+pub const FW_DYNAMIC_INFO_VERSION: u32 = 2;
+pub const FW_DYNAMIC_INFO_SIZE: u32 = 6 * 4;
+
+// privilege the firmware drops to when entering the next stage (U = 0, M = 3)
+pub const NEXT_MODE_S: u32 = 1;
+
+// rv32 kernels need 4MiB alignment, this also matches the generic
+// platform's FW_JUMP_OFFSET of 0x400000 for any firmware under 4MiB
+const PAYLOAD_ALIGN: u32 = 4 * 1024 * 1024;
+
+pub fn payload_address(ram_base: u32, firmware_size: u32) -> u32 {
+    align_up(ram_base + firmware_size, PAYLOAD_ALIGN)
+}
+
+pub fn fw_dynamic_info(next_addr: u32, next_mode: u32, boot_hart: u32) -> Vec<u8> {
+    let fields = [
+        FW_DYNAMIC_INFO_MAGIC,
+        FW_DYNAMIC_INFO_VERSION,
+        next_addr,
+        next_mode,
+        0, // options, we want the boot banner
+        boot_hart,
+    ];
+    fields
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect()
+}
+
+pub fn fw_dynamic_info_address(dtb_address: u32) -> u32 {
+    // just below the device tree, the firmware is done with it long before
+    // the kernel gets to reuse that memory
+    (dtb_address - FW_DYNAMIC_INFO_SIZE) & !0x7
+}
+
+pub fn initrd_address(
+    ram_size: u32,
+    ram_top: u32,
+    kernel_base: u32,
+    kernel_end: u32,
+    initrd_size: u32,
+) -> Option<u32> {
+    // same rule as QEMU, half of RAM or 128MiB above the kernel, whichever
+    // is smaller, but never overlapping the end of the kernel
+    let offset = std::cmp::min(ram_size / 2, 128 * 1024 * 1024);
+    let start = std::cmp::max(kernel_base + offset, align_up(kernel_end, 4096));
+    if start.checked_add(initrd_size)? > dtb_address(ram_top, 0) {
+        return None;
+    }
+    Some(start)
+}
+
+pub fn dtb_address(ram_top: u32, dtb_size: u32) -> u32 {
+    // leave a page between the device tree and the top of RAM for the
+    // initial stack pointer
+    (ram_top - 4096 - dtb_size) & !0xfff
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    (value + align - 1) & !(align - 1)
+}
@@ -1,99 +1,651 @@
-pub const DRAM_BASE: u32 = 0x80000000;
-pub const DRAM_SIZE: u32 = 1 * 1024 * 1024 * 1024; // 1GBram
-pub const DRAM_TOP: u32 = DRAM_BASE + DRAM_SIZE;
+// RAM goes where QEMU's virt machine puts it unless told otherwise
+pub const DEFAULT_RAM_BASE: u32 = 0x80000000;
+pub const DEFAULT_RAM_SIZE: u32 = 1024 * 1024 * 1024; // 1GBram
+
+pub const CLINT_BASE: u32 = 0x02000000;
+pub const CLINT_SIZE: u32 = 0x10000;
+
+pub const PLIC_BASE: u32 = 0x0c000000;
+pub const PLIC_SIZE: u32 = 0x600000;
+
+// Goldfish RTC, where QEMU's virt machine has it
+pub const RTC_BASE: u32 = 0x00101000;
+pub const RTC_SIZE: u32 = 0x1000;
+pub const RTC_IRQ: usize = 11;
 
 pub const UART_BASE: u32 = 0x10000000;
 pub const UART_SIZE: u32 = 0x100;
-pub const UART_TOP: u32 = UART_BASE + UART_SIZE;
+pub const UART_IRQ: usize = 10;
+pub const MAX_UARTS: usize = 4;
+
+// virtio-mmio slots, as on QEMU's virt machine
+pub const VIRTIO_BASE: u32 = 0x10001000;
+pub const VIRTIO_SIZE: u32 = 0x1000;
+pub const VIRTIO_SLOTS: usize = 8;
+pub const VIRTIO_IRQ: usize = 1;
 
+pub const FRAMEBUFFER_BASE: u32 = 0x40000000;
+
+use crate::system::clint;
+use crate::system::device::{Device, DeviceKind};
+use crate::system::fdt::FdtWriter;
+use crate::system::plic;
+use crate::system::pma::{AmoClass, Pma};
 use crate::system::ram;
+use crate::system::rom;
+use crate::system::rtc;
 use crate::system::rv32;
+use crate::system::serial;
 use crate::system::uart;
 
+// mip bits driven by devices on the bus
+pub const MIP_MSIP: rv32::Word = 1 << 3;
+pub const MIP_MTIP: rv32::Word = 1 << 7;
+pub const MIP_MEIP: rv32::Word = 1 << 11;
+pub const MIP_SSIP: rv32::Word = 1 << 1;
+pub const MIP_STIP: rv32::Word = 1 << 5;
+pub const MIP_SEIP: rv32::Word = 1 << 9;
+
+// A device mapped at [base, base + size)
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+    pub device: Box<dyn Device>,
+    pub pma: Pma,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.base as u64 + self.size as u64
+    }
+}
+
+// The memory map, regions are kept sorted by base and never overlap
 pub struct Bus {
-    memory: ram::RAM,
-    uart: uart::UART,
+    regions: Vec<Region>,
+    ram_base: u32, // main memory, where programs and kernels are loaded
+    ram_size: u32,
 }
 
 impl Bus {
-    pub fn new() -> Bus {
-        Bus {
-            memory: ram::RAM::new(),
-            uart: uart::UART::new(),
+    pub fn new(ram_base: u32, ram_size: u32) -> Result<Bus, String> {
+        if ram_size == 0 || !ram_size.is_multiple_of(ram::PAGE_SIZE) {
+            return Err(format!(
+                "RAM size 0x{:x} is not a whole number of pages",
+                ram_size
+            ));
+        }
+        // the top of RAM has to be addressable, it's the initial stack pointer
+        if ram_base as u64 + ram_size as u64 >= 1 << 32 {
+            return Err(format!(
+                "RAM at 0x{:08x} with size 0x{:x} runs off the end of memory",
+                ram_base, ram_size
+            ));
         }
+        let mut bus = Bus {
+            regions: Vec::new(),
+            ram_base,
+            ram_size,
+        };
+        let devices: [(u32, u32, Box<dyn Device>); 5] = [
+            (RTC_BASE, RTC_SIZE, Box::new(rtc::RTC::new(RTC_IRQ))),
+            (CLINT_BASE, CLINT_SIZE, Box::new(clint::CLINT::new())),
+            (PLIC_BASE, PLIC_SIZE, Box::new(plic::PLIC::new())),
+            (
+                UART_BASE,
+                UART_SIZE,
+                Box::new(uart::UART::new(UART_IRQ, Box::new(serial::Stdio()))),
+            ),
+            (ram_base, ram_size, Box::new(ram::RAM::new(ram_size))),
+        ];
+        for (base, size, device) in devices {
+            bus.register(base, size, device)?;
+        }
+        Ok(bus)
+    }
+
+    pub fn ram_base(&self) -> u32 {
+        self.ram_base
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        self.ram_size
+    }
+
+    pub fn ram_top(&self) -> u32 {
+        self.ram_base + self.ram_size
     }
 
-    pub fn load_8(&mut self, address: rv32::XLen) -> rv32::Byte {
-        match address {
-            DRAM_BASE..=DRAM_TOP => self.memory.read_8(address),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // Map a device at [base, base + size), anywhere that doesn't overlap
+    // a device that's already mapped
+    pub fn register(
+        &mut self,
+        base: u32,
+        size: u32,
+        device: Box<dyn Device>,
+    ) -> Result<(), String> {
+        let start = base as u64;
+        let end = start + size as u64;
+        if size == 0 {
+            return Err(format!("{} at 0x{:08x} has no size", device.name(), base));
+        }
+        if end > 1 << 32 {
+            return Err(format!(
+                "{} at 0x{:08x} runs off the end of memory",
+                device.name(),
+                base
+            ));
+        }
+        let index = self.regions.partition_point(|r| r.base < base);
+        let neighbours = [index.checked_sub(1), Some(index)];
+        for region in neighbours
+            .iter()
+            .flatten()
+            .filter_map(|i| self.regions.get(*i))
+        {
+            if start < region.end() && (region.base as u64) < end {
+                return Err(format!(
+                    "{} at 0x{:08x}-0x{:08x} overlaps the {} at 0x{:08x}",
+                    device.name(),
+                    start,
+                    end,
+                    region.device.name(),
+                    region.base
+                ));
             }
         }
+        println!(
+            "VM > BUS > Mapped {} at 0x{:08x}-0x{:08x}",
+            device.name(),
+            start,
+            end
+        );
+        let pma = device.pma();
+        self.regions.insert(
+            index,
+            Region {
+                base,
+                size,
+                device,
+                pma,
+            },
+        );
+        Ok(())
+    }
+
+    // Map the boot ROM, it can go anywhere that doesn't overlap a device
+    pub fn map_rom(&mut self, rom: rom::ROM) -> Result<(), String> {
+        self.register(rom.base(), rom.size(), Box::new(rom))
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    fn region_index(&self, address: rv32::XLen) -> Option<usize> {
+        let index = self.regions.partition_point(|r| r.base <= address);
+        let index = index.checked_sub(1)?;
+        if (address as u64) < self.regions[index].end() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn region(&self, address: rv32::XLen) -> Option<&Region> {
+        self.region_index(address).map(|i| &self.regions[i])
+    }
+
+    fn region_mut(&mut self, address: rv32::XLen) -> Option<&mut Region> {
+        self.region_index(address).map(|i| &mut self.regions[i])
     }
 
-    pub fn load_16(&mut self, address: rv32::XLen) -> rv32::HalfWord {
-        match address {
-            DRAM_BASE..=DRAM_TOP => self.memory.read_16(address),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // The first device of a type, for the few places that need to talk to
+    // a device directly rather than through its registers
+    pub fn device<T: Device + 'static>(&self) -> Option<&T> {
+        self.regions
+            .iter()
+            .find_map(|r| r.device.as_any().downcast_ref::<T>())
+    }
+
+    pub fn device_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.regions
+            .iter_mut()
+            .find_map(|r| r.device.as_any_mut().downcast_mut::<T>())
+    }
+
+    // Every device of a type
+    pub fn devices_mut<T: Device + 'static>(&mut self) -> impl Iterator<Item = &mut T> {
+        self.regions
+            .iter_mut()
+            .filter_map(|r| r.device.as_any_mut().downcast_mut::<T>())
+    }
+
+    // The device mapped at address, when there's more than one of a kind
+    pub fn device_at_mut<T: Device + 'static>(&mut self, address: rv32::XLen) -> Option<&mut T> {
+        self.region_mut(address)?
+            .device
+            .as_any_mut()
+            .downcast_mut::<T>()
+    }
+
+    // Where the nth UART goes, the first is the console and the rest follow
+    // it, each with an interrupt of its own
+    pub fn uart_slot(index: usize) -> (u32, usize) {
+        let base = UART_BASE + index as u32 * UART_SIZE;
+        let irq = if index == 0 {
+            UART_IRQ
+        } else {
+            UART_IRQ + 1 + index
+        };
+        (base, irq)
+    }
+
+    // The first virtio-mmio slot nothing is using yet
+    pub fn virtio_slot(&self) -> Option<(u32, usize)> {
+        (0..VIRTIO_SLOTS)
+            .map(|n| (VIRTIO_BASE + n as u32 * VIRTIO_SIZE, VIRTIO_IRQ + n))
+            .find(|(base, _)| self.region(*base).is_none())
+    }
+
+    pub fn reset(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.reset();
+        }
+    }
+
+    // Whether a loader can place size bytes at address, only memory is
+    // writable, the ROM and devices aren't
+    pub fn writable(&self, address: rv32::XLen, size: u32) -> bool {
+        match self.region(address) {
+            Some(r) if r.device.kind() == DeviceKind::Ram => {
+                address as u64 + size as u64 <= r.end()
             }
+            _ => false,
         }
     }
 
-    pub fn load_32(&mut self, address: rv32::XLen) -> rv32::Word {
-        match address {
-            UART_BASE..=UART_TOP => self.uart.read_kb(address),
-            DRAM_BASE..=DRAM_TOP => self.memory.read_32(address),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // Whether the hart can fetch from this address
+    pub fn executable(&self, address: rv32::XLen) -> bool {
+        self.region(address).is_some_and(|r| r.pma.executable)
+    }
+
+    // LR/SC need somewhere a reservation can be held
+    pub fn check_lrsc(&self, address: rv32::XLen) -> Result<(), String> {
+        let region = self.region(address).ok_or(unmapped(address))?;
+        match region.pma.lrsc {
+            true => Ok(()),
+            false => Err(pma_error(region, address, "LR/SC")),
+        }
+    }
+
+    pub fn check_amo(&self, address: rv32::XLen, class: AmoClass) -> Result<(), String> {
+        let region = self.region(address).ok_or(unmapped(address))?;
+        match region.pma.amo >= class {
+            true => Ok(()),
+            false => Err(pma_error(region, address, &format!("AMO {:?}", class))),
+        }
+    }
+
+    // RAM regions as (base, size), for the device tree's memory nodes. File
+    // backed memory isn't for the kernel to allocate from so isn't included
+    pub fn memory_regions(&self) -> Vec<(u32, u32)> {
+        self.regions
+            .iter()
+            .filter(|r| r.device.as_any().is::<ram::RAM>())
+            .map(|r| (r.base, r.size))
+            .collect()
+    }
+
+    // Device tree path of the console
+    pub fn stdout_path(&self) -> Option<String> {
+        self.regions
+            .iter()
+            .find(|r| r.device.is_console())
+            .and_then(|r| Some(format!("/soc/{}@{:x}", r.device.fdt_name()?, r.base)))
+    }
+
+    // Write a device tree node for every device on the bus
+    pub fn describe(&self, fdt: &mut FdtWriter) {
+        for region in self.regions.iter() {
+            if let Some(name) = region.device.fdt_name() {
+                fdt.begin_node(&format!("{}@{:x}", name, region.base));
+                region.device.fdt_properties(fdt, region.base, region.size);
+                fdt.end_node();
             }
         }
     }
 
-    pub fn load_64(&mut self, address: rv32::XLen) -> rv32::DoubleWord {
-        match address {
-            DRAM_BASE..=DRAM_TOP => self.memory.read_64(address),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // Advance device time, called by the CPU before every step
+    pub fn tick(&mut self, elapsed_micros: u64) {
+        let (mut driven, mut asserted) = (0u64, 0u64);
+        for index in 0..self.regions.len() {
+            // a device reaches memory through every region but its own
+            let (before, rest) = self.regions.split_at_mut(index);
+            let (region, after) = rest.split_first_mut().unwrap();
+            region.device.tick(elapsed_micros);
+            if region.device.dma_pending() {
+                region.device.dma(&mut GuestMemory { before, after });
+            }
+            if let Some((irq, level)) = region.device.irq() {
+                driven |= 1 << irq;
+                asserted |= (level as u64) << irq;
+            }
+        }
+        // device interrupt lines go to the PLIC
+        if driven == 0 {
+            return;
+        }
+        if let Some(plic) = self.device_mut::<plic::PLIC>() {
+            for irq in (0..64).filter(|irq| driven & (1 << irq) != 0) {
+                plic.set_irq(irq, asserted & (1 << irq) != 0);
             }
         }
     }
 
-    pub fn store_8(&mut self, address: rv32::XLen, data: rv32::Byte) {
-        match address {
-            DRAM_BASE..=DRAM_TOP => self.memory.write_8(address, data),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    pub fn mtime(&self) -> rv32::DoubleWord {
+        self.device::<clint::CLINT>()
+            .map_or(0, |clint| clint.mtime())
+    }
+
+    // The interrupt lines into the hart, as mip bits
+    pub fn interrupts(&self) -> rv32::Word {
+        self.regions
+            .iter()
+            .fold(0, |mip, r| mip | r.device.interrupts())
+    }
+
+    // Pages of RAM written since the last clear_dirty, by address
+    pub fn dirty_pages(&self) -> Vec<rv32::XLen> {
+        self.regions
+            .iter()
+            .filter_map(|r| Some((r.base, r.device.as_any().downcast_ref::<ram::RAM>()?)))
+            .flat_map(|(base, ram)| ram.dirty_pages().into_iter().map(move |o| base + o))
+            .collect()
+    }
+
+    pub fn clear_dirty(&mut self) {
+        for region in self.regions.iter_mut() {
+            if let Some(ram) = region.device.as_any_mut().downcast_mut::<ram::RAM>() {
+                ram.clear_dirty();
             }
         }
     }
 
-    pub fn store_16(&mut self, address: rv32::XLen, data: rv32::HalfWord) {
-        match address {
-            DRAM_BASE..=DRAM_TOP => self.memory.write_16(address, data),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // Zero-copy access to a range that lies entirely in RAM, as one slice
+    // per page
+    pub fn ram_slices(&self, address: rv32::XLen, len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        if !self.writable(address, len) {
+            return None;
+        }
+        let region = self.region(address)?;
+        region.device.memory(address - region.base, len)
+    }
+
+    pub fn ram_slices_mut(
+        &mut self,
+        address: rv32::XLen,
+        len: u32,
+    ) -> Option<Vec<&mut [rv32::Byte]>> {
+        if !self.writable(address, len) {
+            return None;
+        }
+        let region = self.region_mut(address)?;
+        region.device.memory_mut(address - region.base, len)
+    }
+
+    // Bulk reads, RAM and ROM are copied a region at a time and devices
+    // are read a register at a time, so a range can span several regions
+    pub fn read_bytes(
+        &mut self,
+        address: rv32::XLen,
+        buffer: &mut [rv32::Byte],
+    ) -> Result<(), String> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let current = address
+                .checked_add(done as u32)
+                .ok_or("VM > BUS > Read runs off the end of memory".to_string())?;
+            let remaining = buffer.len() - done;
+            let region = self.region_mut(current).ok_or(unmapped(current))?;
+            let offset = current - region.base;
+            let run = std::cmp::min((region.size - offset) as usize, remaining);
+            // memory can be copied directly unless it's in a state where reads
+            // go to registers, flash answering a query for instance
+            let memory = match region.device.kind() {
+                DeviceKind::Mmio => None,
+                _ => region.device.memory(offset, run as u32),
+            };
+            if let Some(memory) = memory {
+                for chunk in memory {
+                    buffer[done..done + chunk.len()].copy_from_slice(chunk);
+                    done += chunk.len();
+                }
+            } else {
+                let width = mmio_width(&region.pma, offset, run);
+                let value = region
+                    .device
+                    .read(offset, width as u32)
+                    .map_err(|e| device_error(region, current, e))?;
+                buffer[done..done + width].copy_from_slice(&value.to_le_bytes()[..width]);
+                done += width;
             }
         }
+        Ok(())
     }
 
-    pub fn store_32(&mut self, address: rv32::XLen, data: rv32::Word) {
-        match address {
-            UART_BASE..=UART_TOP => self.uart.write(address, data),
-            DRAM_BASE..=DRAM_TOP => self.memory.write_32(address, data),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // Bulk writes, the ROM is read only so only RAM and devices are written
+    pub fn write_bytes(&mut self, address: rv32::XLen, data: &[rv32::Byte]) -> Result<(), String> {
+        let mut done = 0;
+        while done < data.len() {
+            let current = address
+                .checked_add(done as u32)
+                .ok_or("VM > BUS > Write runs off the end of memory".to_string())?;
+            let remaining = data.len() - done;
+            let region = self.region_mut(current).ok_or(unmapped(current))?;
+            let offset = current - region.base;
+            let run = std::cmp::min((region.size - offset) as usize, remaining);
+            match region.device.kind() {
+                DeviceKind::Rom => {
+                    return Err(format!(
+                        "VM > BUS > Write to read only memory at 0x{:08x}",
+                        current
+                    ));
+                }
+                DeviceKind::Ram => {
+                    let memory = region.device.memory_mut(offset, run as u32).unwrap();
+                    for chunk in memory {
+                        chunk.copy_from_slice(&data[done..done + chunk.len()]);
+                        done += chunk.len();
+                    }
+                }
+                DeviceKind::Mmio => {
+                    let width = mmio_width(&region.pma, offset, run);
+                    let mut bytes = [0; 8];
+                    bytes[..width].copy_from_slice(&data[done..done + width]);
+                    region
+                        .device
+                        .write(offset, width as u32, u64::from_le_bytes(bytes))
+                        .map_err(|e| device_error(region, current, e))?;
+                    done += width;
+                }
             }
         }
+        Ok(())
     }
 
-    pub fn store_64(&mut self, address: rv32::XLen, data: rv32::DoubleWord) {
-        match address {
-            DRAM_BASE..=DRAM_TOP => self.memory.write_64(address, data),
-            _ => {
-                panic!("VM > BUS > Peripheral at 0x{:08x} does not exist", address);
+    // Every access width goes the same way, find the region and hand the
+    // device the offset into it. Unmapped addresses and accesses a device
+    // refuses are errors, the hart raises an access fault for them
+    fn read(&mut self, address: rv32::XLen, size: u32) -> Result<rv32::DoubleWord, String> {
+        match self.region_mut(address) {
+            Some(r) if !r.pma.supports_width(size) => {
+                Err(pma_error(r, address, &format!("{} bit access", size * 8)))
             }
+            Some(r) if address as u64 + size as u64 <= r.end() => {
+                let offset = address - r.base;
+                r.device
+                    .read(offset, size)
+                    .map_err(|e| device_error(r, address, e))
+            }
+            _ => Err(unmapped(address)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: rv32::XLen,
+        size: u32,
+        data: rv32::DoubleWord,
+    ) -> Result<(), String> {
+        match self.region_mut(address) {
+            Some(r) if !r.pma.supports_width(size) => {
+                Err(pma_error(r, address, &format!("{} bit access", size * 8)))
+            }
+            Some(r) if address as u64 + size as u64 <= r.end() => {
+                let offset = address - r.base;
+                r.device
+                    .write(offset, size, data)
+                    .map_err(|e| device_error(r, address, e))
+            }
+            _ => Err(unmapped(address)),
+        }
+    }
+
+    pub fn load_8(&mut self, address: rv32::XLen) -> Result<rv32::Byte, String> {
+        Ok(self.read(address, 1)? as rv32::Byte)
+    }
+
+    pub fn load_16(&mut self, address: rv32::XLen) -> Result<rv32::HalfWord, String> {
+        Ok(self.read(address, 2)? as rv32::HalfWord)
+    }
+
+    pub fn load_32(&mut self, address: rv32::XLen) -> Result<rv32::Word, String> {
+        Ok(self.read(address, 4)? as rv32::Word)
+    }
+
+    pub fn store_8(&mut self, address: rv32::XLen, data: rv32::Byte) -> Result<(), String> {
+        self.write(address, 1, data as rv32::DoubleWord)
+    }
+
+    pub fn store_16(&mut self, address: rv32::XLen, data: rv32::HalfWord) -> Result<(), String> {
+        self.write(address, 2, data as rv32::DoubleWord)
+    }
+
+    pub fn store_32(&mut self, address: rv32::XLen, data: rv32::Word) -> Result<(), String> {
+        self.write(address, 4, data as rv32::DoubleWord)
+    }
+
+    // Memory the way a device doing DMA sees it, for testing devices
+    // without mapping them
+    #[cfg(test)]
+    pub fn guest_memory(&mut self) -> GuestMemory<'_> {
+        GuestMemory {
+            before: &mut self.regions,
+            after: &mut [],
+        }
+    }
+}
+
+// Memory as a device doing DMA sees it. Only RAM can be reached, that's
+// where drivers put the buffers they hand to devices
+pub struct GuestMemory<'a> {
+    before: &'a mut [Region],
+    after: &'a mut [Region],
+}
+
+impl GuestMemory<'_> {
+    // The RAM region holding all of [address, address + len)
+    fn ram(&mut self, address: u64, len: usize) -> Result<(&mut Region, u32), String> {
+        let end = address.saturating_add(len as u64);
+        self.before
+            .iter_mut()
+            .chain(self.after.iter_mut())
+            .find(|r| r.base as u64 <= address && end <= r.end())
+            .filter(|r| r.device.kind() == DeviceKind::Ram)
+            .map(|r| {
+                let offset = (address - r.base as u64) as u32;
+                (r, offset)
+            })
+            .ok_or(format!(
+                "DMA to 0x{:x}-0x{:x} isn't within RAM",
+                address, end
+            ))
+    }
+
+    pub fn read(&mut self, address: u64, buffer: &mut [rv32::Byte]) -> Result<(), String> {
+        let (region, offset) = self.ram(address, buffer.len())?;
+        let mut done = 0;
+        for chunk in region.device.memory(offset, buffer.len() as u32).unwrap() {
+            buffer[done..done + chunk.len()].copy_from_slice(chunk);
+            done += chunk.len();
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, address: u64, data: &[rv32::Byte]) -> Result<(), String> {
+        let (region, offset) = self.ram(address, data.len())?;
+        let mut done = 0;
+        for chunk in region.device.memory_mut(offset, data.len() as u32).unwrap() {
+            chunk.copy_from_slice(&data[done..done + chunk.len()]);
+            done += chunk.len();
         }
+        Ok(())
+    }
+
+    pub fn read_u16(&mut self, address: u64) -> Result<u16, String> {
+        let mut bytes = [0; 2];
+        self.read(address, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
     }
+
+    pub fn read_u32(&mut self, address: u64) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        self.read(address, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self, address: u64) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        self.read(address, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn write_u16(&mut self, address: u64, value: u16) -> Result<(), String> {
+        self.write(address, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, address: u64, value: u32) -> Result<(), String> {
+        self.write(address, &value.to_le_bytes())
+    }
+}
+
+fn unmapped(address: rv32::XLen) -> String {
+    format!("VM > BUS > Peripheral at 0x{:08x} does not exist", address)
+}
+
+fn pma_error(region: &Region, address: rv32::XLen, access: &str) -> String {
+    format!(
+        "VM > BUS > {} at 0x{:08x} doesn't support {} ({})",
+        region.device.name(),
+        address,
+        access,
+        region.pma
+    )
+}
+
+fn device_error(region: &Region, address: rv32::XLen, error: String) -> String {
+    format!(
+        "VM > BUS > {} at 0x{:08x} refused the access: {}",
+        region.device.name(),
+        address,
+        error
+    )
+}
+
+// Width of one bulk access to a device, the widest the region supports
+// that the alignment and length allow
+fn mmio_width(pma: &Pma, offset: u32, remaining: usize) -> usize {
+    let limit = 1 << std::cmp::min(offset.trailing_zeros(), 3);
+    pma.widest(std::cmp::min(limit, remaining))
 }
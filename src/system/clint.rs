@@ -0,0 +1,129 @@
+use std::any::Any;
+
+use crate::system::bus;
+use crate::system::device::{self, Device};
+use crate::system::fdt::{FdtWriter, PHANDLE_CPU_INTC};
+use crate::system::pma::{self, Pma};
+use crate::system::rv32;
+
+// Core Local Interruptor, provides the machine timer and software
+// interrupts for our single hart. The layout matches the SiFive CLINT
+// so the kernel's riscv,clint0 driver can find everything
+const MSIP: u32 = 0x0000;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xbff8;
+
+// mtime ticks once per microsecond
+pub const TIMEBASE_FREQUENCY: u32 = 1_000_000;
+
+pub struct CLINT {
+    msip: rv32::Word,
+    mtimecmp: rv32::DoubleWord,
+    mtime: rv32::DoubleWord,
+}
+
+impl CLINT {
+    pub fn new() -> CLINT {
+        println!("VM > Initialised CLINT");
+        CLINT {
+            msip: 0,
+            mtimecmp: rv32::DoubleWord::MAX,
+            mtime: 0,
+        }
+    }
+
+    pub fn mtime(&self) -> rv32::DoubleWord {
+        self.mtime
+    }
+
+    // For the built in SBI, which programs the timer on the kernel's behalf
+    pub fn set_mtimecmp(&mut self, value: rv32::DoubleWord) {
+        self.mtimecmp = value;
+    }
+
+    fn read_word(&self, offset: u32) -> rv32::Word {
+        match offset {
+            MSIP => self.msip,
+            MTIMECMP => self.mtimecmp as rv32::Word,
+            o if o == MTIMECMP + 4 => (self.mtimecmp >> 32) as rv32::Word,
+            MTIME => self.mtime as rv32::Word,
+            o if o == MTIME + 4 => (self.mtime >> 32) as rv32::Word,
+            _ => 0,
+        }
+    }
+
+    fn write_word(&mut self, offset: u32, value: rv32::Word) {
+        let low = |reg: u64| (reg & 0xffffffff_00000000) | value as u64;
+        let high = |reg: u64| (reg & 0x00000000_ffffffff) | (value as u64) << 32;
+        match offset {
+            MSIP => self.msip = value & 1,
+            MTIMECMP => self.mtimecmp = low(self.mtimecmp),
+            o if o == MTIMECMP + 4 => self.mtimecmp = high(self.mtimecmp),
+            MTIME => self.mtime = low(self.mtime),
+            o if o == MTIME + 4 => self.mtime = high(self.mtime),
+            _ => (),
+        }
+    }
+}
+
+impl Device for CLINT {
+    fn name(&self) -> &'static str {
+        "CLINT"
+    }
+
+    // 32 bit registers, mtime and mtimecmp are read and written in halves
+    fn pma(&self) -> Pma {
+        Pma::io(pma::WIDTH_32)
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        device::read_words(offset, size, |o| self.read_word(o))
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        device::write_words(offset, size, value, |o, v| self.write_word(o, v))
+    }
+
+    // mtime keeps counting across a reset
+    fn reset(&mut self) {
+        self.msip = 0;
+        self.mtimecmp = rv32::DoubleWord::MAX;
+    }
+
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.mtime = self.mtime.wrapping_add(elapsed_micros);
+    }
+
+    fn interrupts(&self) -> rv32::Word {
+        let mut mip = 0;
+        if self.msip & 1 != 0 {
+            mip |= bus::MIP_MSIP;
+        }
+        if self.mtime >= self.mtimecmp {
+            mip |= bus::MIP_MTIP;
+        }
+        mip
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("clint")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        // machine software and machine timer interrupts
+        fdt.property_cells(
+            "interrupts-extended",
+            &[PHANDLE_CPU_INTC, 3, PHANDLE_CPU_INTC, 7],
+        );
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
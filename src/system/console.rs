@@ -0,0 +1,187 @@
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Mutex, Once, OnceLock};
+
+// Host keyboard input
+//
+// Stdin is read on a thread of its own and handed over a byte at a time,
+// so a guest polling its UART never blocks the VM waiting for a key. The
+// management prompt reads its lines from here as well, once the thread has
+// started anything read straight from stdin could have been taken by it.
+//
+// While the guest console is attached the terminal is put in raw mode, so
+// keys reach the guest as they're pressed and Ctrl-C goes to the guest
+// rather than killing us. Ctrl-A is the escape:
+//
+//   C-a x    quit
+//   C-a c    stop the guest and go to the management prompt
+//   C-a h    list these
+//   C-a C-a  send the guest a C-a
+
+pub const ESCAPE_KEY: u8 = 0x01; // Ctrl-A
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Escape {
+    Quit,
+    Monitor,
+}
+
+static INPUT: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+static ESCAPE: AtomicU8 = AtomicU8::new(0); // 0 or an Escape + 1
+
+// Terminal settings from before we went raw, put back when detaching
+static SAVED_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+fn input() -> &'static Mutex<Receiver<u8>> {
+    INPUT.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || read_stdin(sender));
+        Mutex::new(receiver)
+    })
+}
+
+// Ends when stdin closes or nobody is listening anymore
+fn read_stdin(sender: Sender<u8>) {
+    let mut stdin = std::io::stdin();
+    let mut buffer = [0; 1];
+    let mut escaping = false;
+    while stdin.read_exact(&mut buffer).is_ok() {
+        let byte = buffer[0];
+        let attached = ATTACHED.load(Ordering::Relaxed);
+        if attached && escaping {
+            escaping = false;
+            match byte {
+                b'x' | b'X' => request(Escape::Quit),
+                b'c' | b'C' => request(Escape::Monitor),
+                b'h' | b'H' | b'?' => print_help(),
+                // a second C-a goes to the guest
+                ESCAPE_KEY | b'a' if sender.send(ESCAPE_KEY).is_err() => break,
+                _ => (), // unknown escapes are dropped
+            }
+            continue;
+        }
+        if attached && byte == ESCAPE_KEY {
+            escaping = true;
+            continue;
+        }
+        if sender.send(byte).is_err() {
+            break;
+        }
+    }
+}
+
+fn request(escape: Escape) {
+    ESCAPE.store(escape as u8 + 1, Ordering::Relaxed);
+}
+
+fn print_help() {
+    // the terminal is raw, lines need their own carriage returns
+    print!(
+        "\r\nC-a x    quit\r\n\
+         C-a c    go to the management prompt\r\n\
+         C-a h    this help\r\n\
+         C-a C-a  send C-a to the guest\r\n"
+    );
+    std::io::stdout().flush().unwrap();
+}
+
+// A key if one has been pressed, never waits
+pub fn try_read() -> Option<u8> {
+    match input().lock().unwrap().try_recv() {
+        Ok(byte) => Some(byte),
+        Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+    }
+}
+
+// Wait for a whole line, without the newline. Whatever is left when stdin
+// closes counts as the last line
+pub fn read_line() -> String {
+    let input = input().lock().unwrap();
+    let mut line = Vec::new();
+    while let Ok(byte) = input.recv() {
+        if byte == b'\n' {
+            break;
+        }
+        line.push(byte);
+    }
+    String::from_utf8_lossy(&line).into_owned()
+}
+
+// Cheap enough to check before every instruction
+pub fn escaped() -> bool {
+    ESCAPE.load(Ordering::Relaxed) != 0
+}
+
+pub fn take_escape() -> Option<Escape> {
+    match ESCAPE.swap(0, Ordering::Relaxed) {
+        1 => Some(Escape::Quit),
+        2 => Some(Escape::Monitor),
+        _ => None,
+    }
+}
+
+// Hand the terminal to the guest until the returned guard is dropped
+pub fn attach() -> Attached {
+    input();
+    static RESTORE_ON_PANIC: Once = Once::new();
+    RESTORE_ON_PANIC.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            previous(info);
+        }));
+    });
+    if raw_terminal() {
+        println!("VM > Console attached, C-a h for help");
+    }
+    ATTACHED.store(true, Ordering::Relaxed);
+    Attached()
+}
+
+pub struct Attached();
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        ATTACHED.store(false, Ordering::Relaxed);
+        restore_terminal();
+    }
+}
+
+// Raw input with output processing left on, so our own messages still get
+// carriage returns. Does nothing if stdin isn't a terminal
+fn raw_terminal() -> bool {
+    let mut saved = SAVED_TERMIOS.lock().unwrap();
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) != 1 {
+            return false;
+        }
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return false;
+        }
+        let mut raw = termios;
+        libc::cfmakeraw(&mut raw);
+        raw.c_oflag |= libc::OPOST;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+            return false;
+        }
+        saved.get_or_insert(termios);
+    }
+    true
+}
+
+fn restore_terminal() {
+    // a panic while holding the lock still gets the terminal back
+    let mut saved = match SAVED_TERMIOS.lock() {
+        Ok(saved) => saved,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Some(termios) = saved.take() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        }
+    }
+}
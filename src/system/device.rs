@@ -0,0 +1,143 @@
+use std::any::Any;
+
+use crate::system::bus::GuestMemory;
+use crate::system::fdt::FdtWriter;
+use crate::system::pma::{self, Pma};
+use crate::system::rv32;
+
+// Everything on the bus is a device mapped at an address range. Offsets
+// passed to a device are from the base it's mapped at, so the same device
+// can be mapped anywhere, and accesses are 1, 2, 4 or 8 bytes wide. A
+// device can refuse an access, the hart sees that as an access fault.
+
+// RAM and ROM can be executed from and accessed in bulk, MMIO registers
+// only through sized reads and writes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeviceKind {
+    Ram,
+    Rom,
+    Mmio,
+}
+
+pub trait Device {
+    fn name(&self) -> &'static str;
+
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Mmio
+    }
+
+    // What the region it's mapped at allows, registers up to 32 bits wide
+    // unless the device says otherwise
+    fn pma(&self) -> Pma {
+        match self.kind() {
+            DeviceKind::Ram => Pma::MEMORY,
+            DeviceKind::Rom => Pma::ROM,
+            DeviceKind::Mmio => Pma::io(pma::WIDTH_8 | pma::WIDTH_16 | pma::WIDTH_32),
+        }
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String>;
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String>;
+
+    // Back to the power on state
+    fn reset(&mut self) {}
+
+    // Advance device time, called before every instruction
+    fn tick(&mut self, _elapsed_micros: u64) {}
+
+    // Devices reading and writing memory themselves, rather than through
+    // their registers, do it here. Checked by the bus after every tick
+    fn dma_pending(&self) -> bool {
+        false
+    }
+
+    fn dma(&mut self, _memory: &mut GuestMemory) {}
+
+    // mip bits driven by this device
+    fn interrupts(&self) -> rv32::Word {
+        0
+    }
+
+    // PLIC source this device drives and whether it's asserted, sampled by
+    // the bus after every tick
+    fn irq(&self) -> Option<(usize, bool)> {
+        None
+    }
+
+    // Zero-copy access to the backing store of RAM and ROM, as the slices
+    // making up [offset, offset + len). Paged RAM hands out one per page
+    fn memory(&self, _offset: u32, _len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        None
+    }
+
+    fn memory_mut(&mut self, _offset: u32, _len: u32) -> Option<Vec<&mut [rv32::Byte]>> {
+        None
+    }
+
+    // Device tree node name, devices without one aren't described
+    fn fdt_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Properties of the device tree node, the bus opens and closes it
+    fn fdt_properties(&self, _fdt: &mut FdtWriter, _base: u32, _size: u32) {}
+
+    // Whether this is where the kernel console should go
+    fn is_console(&self) -> bool {
+        false
+    }
+
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+// Little endian access to a byte backed device
+pub fn read_le(memory: &[rv32::Byte], offset: u32, size: u32) -> rv32::DoubleWord {
+    let offset = offset as usize;
+    memory[offset..offset + size as usize]
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as rv32::DoubleWord)
+}
+
+pub fn write_le(memory: &mut [rv32::Byte], offset: u32, size: u32, value: rv32::DoubleWord) {
+    let offset = offset as usize;
+    let bytes = value.to_le_bytes();
+    memory[offset..offset + size as usize].copy_from_slice(&bytes[..size as usize]);
+}
+
+// For devices with 32 bit registers, narrower reads take the bytes out of
+// the register and 64 bit reads are two registers
+pub fn read_words(
+    offset: u32,
+    size: u32,
+    mut read: impl FnMut(u32) -> rv32::Word,
+) -> Result<rv32::DoubleWord, String> {
+    Ok(match size {
+        8 => read(offset) as rv32::DoubleWord | (read(offset + 4) as rv32::DoubleWord) << 32,
+        4 => read(offset) as rv32::DoubleWord,
+        _ => {
+            let shift = (offset & 3) * 8;
+            let mask = (1 << (size * 8)) - 1;
+            (read(offset & !3) >> shift & mask) as rv32::DoubleWord
+        }
+    })
+}
+
+// Only whole registers can be written, narrower writes are dropped
+pub fn write_words(
+    offset: u32,
+    size: u32,
+    value: rv32::DoubleWord,
+    mut write: impl FnMut(u32, rv32::Word),
+) -> Result<(), String> {
+    match size {
+        8 => {
+            write(offset, value as rv32::Word);
+            write(offset + 4, (value >> 32) as rv32::Word);
+        }
+        4 => write(offset, value as rv32::Word),
+        _ => (),
+    }
+    Ok(())
+}
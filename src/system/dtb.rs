@@ -0,0 +1,107 @@
+use crate::system::bus::Bus;
+use crate::system::clint;
+use crate::system::fdt::{FdtWriter, PHANDLE_CPU_INTC};
+
+// Device tree generation
+//
+// Rather than shipping a hand written dts that can drift from what we
+// emulate, the tree is built from the machine itself. The memory and soc
+// nodes come from the devices mapped on the bus and the cpu node from the
+// extensions the decoder has enabled.
+
+pub struct Chosen {
+    pub bootargs: String,
+    pub initrd: Option<(u32, u32)>,
+}
+
+// 'z' is our catchall for the Zicsr / Zifencei instructions, the rest are
+// single letter extensions. We also implement the Sdtrig trigger module
+pub fn isa_extensions(extensions: &[char]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for ext in extensions {
+        match ext {
+            'z' => {
+                names.push("zicsr".to_string());
+                names.push("zifencei".to_string());
+            }
+            ext => names.push(ext.to_string()),
+        }
+    }
+    names.push("sdtrig".to_string());
+    names
+}
+
+pub fn isa_string(extensions: &[char]) -> String {
+    let mut isa = "rv32".to_string();
+    for name in isa_extensions(extensions) {
+        if name.len() > 1 {
+            isa.push('_');
+        }
+        isa.push_str(&name);
+    }
+    isa
+}
+
+pub fn generate(bus: &Bus, extensions: &[char], chosen: &Chosen) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscy-rust");
+    fdt.property_string("model", "riscy-rust,rv32");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", &chosen.bootargs);
+    if let Some(path) = bus.stdout_path() {
+        fdt.property_string("stdout-path", &path);
+    }
+    if let Some((start, end)) = chosen.initrd {
+        fdt.property_u64("linux,initrd-start", start as u64);
+        fdt.property_u64("linux,initrd-end", end as u64);
+    }
+    fdt.end_node();
+
+    for (base, size) in bus.memory_regions() {
+        fdt.begin_node(&format!("memory@{:x}", base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.end_node();
+    }
+
+    let isa_extensions = isa_extensions(extensions);
+    let isa_extensions: Vec<&str> = isa_extensions.iter().map(|name| name.as_str()).collect();
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", clint::TIMEBASE_FREQUENCY);
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", &isa_string(extensions));
+    fdt.property_string("riscv,isa-base", "rv32i");
+    fdt.property_strings("riscv,isa-extensions", &isa_extensions);
+    fdt.property_string("mmu-type", "riscv,none");
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", PHANDLE_CPU_INTC);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+    bus.describe(&mut fdt);
+    fdt.end_node();
+
+    fdt.end_node();
+
+    fdt.finish(0)
+}
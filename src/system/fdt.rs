@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+// Flattened Device Tree (DTB) writer
+//
+// The blob is laid out as
+//  header | memory reservation block | structure block | strings block
+// with every field big endian. Nodes and properties are appended to the
+// structure block as tokens, property names are deduplicated into the
+// strings block.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// phandles for the nodes other nodes refer to
+pub const PHANDLE_CPU_INTC: u32 = 1;
+pub const PHANDLE_PLIC: u32 = 2;
+
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> FdtWriter {
+        FdtWriter {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    // The root node has an empty name
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(
+            self.depth > 0,
+            "FDT > end_node without a matching begin_node"
+        );
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert!(self.depth == 0, "FDT > finish with unclosed nodes");
+        self.push_u32(FDT_END);
+
+        // a single empty entry terminates the memory reservation block
+        let reservations = [0u8; 16];
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + reservations.len();
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&reservations);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_describes_the_blocks() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.end_node();
+        let blob = fdt.finish(3);
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 16) as usize, FDT_HEADER_SIZE); // off_mem_rsvmap
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+        assert_eq!(be32(&blob, 24), FDT_LAST_COMP_VERSION);
+        assert_eq!(be32(&blob, 28), 3); // boot_cpuid_phys
+
+        let (off_struct, off_strings) = (be32(&blob, 8) as usize, be32(&blob, 12) as usize);
+        let (size_strings, size_struct) = (be32(&blob, 32) as usize, be32(&blob, 36) as usize);
+        assert_eq!(off_struct, FDT_HEADER_SIZE + 16);
+        assert_eq!(off_struct + size_struct, off_strings);
+        assert_eq!(off_strings + size_strings, blob.len());
+        // the reservation block is just its terminator
+        assert!(blob[FDT_HEADER_SIZE..off_struct].iter().all(|b| *b == 0));
+        assert_eq!(be32(&blob, off_strings - 4), FDT_END);
+    }
+
+    #[test]
+    fn property_names_are_deduplicated() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("reg", 1);
+        fdt.property_null("ranges");
+        fdt.begin_node("child");
+        fdt.property_u32("reg", 2);
+        fdt.end_node();
+        fdt.end_node();
+        assert_eq!(fdt.strings, b"reg\0ranges\0");
+        assert_eq!(fdt.string_offset("ranges"), 4);
+    }
+
+    #[test]
+    fn tokens_stay_aligned() {
+        let mut fdt = FdtWriter::new();
+        // names and values that aren't a multiple of 4 bytes long
+        fdt.begin_node("cpu@0");
+        assert_eq!(fdt.structure.len(), 4 + 8);
+        fdt.property_string("status", "okay");
+        assert_eq!(fdt.structure.len(), 12 + 12 + 8);
+        fdt.property("odd", &[1, 2, 3]);
+        assert_eq!(fdt.structure.len(), 32 + 12 + 4);
+        fdt.end_node();
+        assert_eq!(&fdt.structure[44..48], &[1, 2, 3, 0]);
+        assert_eq!(
+            u32::from_be_bytes(fdt.structure[48..52].try_into().unwrap()),
+            FDT_END_NODE
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::any::Any;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::helpers::{parse_address, parse_size};
+use crate::system::device::{self, Device, DeviceKind};
+use crate::system::rv32;
+
+// Memory backed by a host file, for battery backed SRAM and the like whose
+// contents have to outlive the VM. The file is read in when the region is
+// mapped and written back either as the guest writes (write through) or
+// when the VM goes away (flush on exit). Read only regions never touch the
+// file after loading it.
+//
+// On the command line a region is FILE@ADDR followed by comma separated
+// options, size=SIZE to map more (or less) than the file holds, ro for read
+// only and write-through to write back on every store.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlushPolicy {
+    OnExit,
+    WriteThrough,
+}
+
+pub struct FileMemory {
+    path: String,
+    file: Option<File>, // only kept open when the region is writable
+    data: Vec<rv32::Byte>,
+    policy: FlushPolicy,
+    dirty: Option<(usize, usize)>, // range written since the last flush
+}
+
+impl FileMemory {
+    // Past the end of the file memory reads as fill, which is zero for SRAM
+    // and 0xff for erased flash
+    pub fn open(
+        path: &str,
+        size: Option<u32>,
+        fill: rv32::Byte,
+        writable: bool,
+        policy: FlushPolicy,
+    ) -> Result<FileMemory, String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let file_size = data.len();
+        let size = size.unwrap_or(file_size as u32);
+        if size == 0 {
+            return Err(format!("{} is empty and no size was given", path));
+        }
+        data.resize(size as usize, fill);
+        println!(
+            "VM > Opened {} as {} memory with size: {} bytes",
+            path,
+            if writable { "read-write" } else { "read only" },
+            size
+        );
+        let mut memory = FileMemory {
+            path: path.to_string(),
+            file: if writable { Some(file) } else { None },
+            data,
+            policy,
+            dirty: None,
+        };
+        // a short file is grown to the size asked for
+        if writable && file_size < size as usize {
+            memory.mark_dirty(file_size, size as usize - file_size);
+        }
+        Ok(memory)
+    }
+
+    // FILE@ADDR[,size=SIZE][,ro][,write-through], returning where to map it
+    pub fn from_spec(spec: &str) -> Result<(u32, FileMemory), String> {
+        let mut parts = spec.split(',');
+        let (path, base) = parts
+            .next()
+            .and_then(|region| region.rsplit_once('@'))
+            .ok_or(format!("{} should be FILE@ADDR", spec))?;
+        let base = parse_address(base)?;
+        let (mut size, mut writable, mut policy) = (None, true, FlushPolicy::OnExit);
+        for option in parts {
+            match option.split_once('=') {
+                Some(("size", value)) => size = Some(parse_size(value)?),
+                None if option == "ro" => writable = false,
+                None if option == "rw" => writable = true,
+                None if option == "write-through" => policy = FlushPolicy::WriteThrough,
+                None if option == "flush-on-exit" => policy = FlushPolicy::OnExit,
+                _ => return Err(format!("unknown memory file option {}", option)),
+            }
+        }
+        Ok((base, FileMemory::open(path, size, 0, writable, policy)?))
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn bytes(&self, offset: u32, len: u32) -> &[rv32::Byte] {
+        &self.data[offset as usize..offset as usize + len as usize]
+    }
+
+    // Anything handed out this way is written back at the next flush
+    pub fn bytes_mut(&mut self, offset: u32, len: u32) -> &mut [rv32::Byte] {
+        self.mark_dirty(offset as usize, len as usize);
+        &mut self.data[offset as usize..offset as usize + len as usize]
+    }
+
+    fn mark_dirty(&mut self, offset: usize, len: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((start, end)) => (start.min(offset), end.max(offset + len)),
+            None => (offset, offset + len),
+        });
+    }
+
+    // Write what changed back to the file
+    pub fn flush(&mut self) -> Result<(), String> {
+        let (file, (start, end)) = match (self.file.as_mut(), self.dirty) {
+            (Some(file), Some(range)) => (file, range),
+            _ => return Ok(()),
+        };
+        file.seek(SeekFrom::Start(start as u64))
+            .and_then(|_| file.write_all(&self.data[start..end]))
+            .map_err(|e| format!("{}: {}", self.path, e))?;
+        self.dirty = None;
+        Ok(())
+    }
+
+    fn flush_if(&mut self, policy: FlushPolicy) {
+        if self.policy == policy {
+            if let Err(e) = self.flush() {
+                println!("VM > Failed to write back {}", e);
+            }
+        }
+    }
+}
+
+// Whatever hasn't been written through yet goes out with the VM
+impl Drop for FileMemory {
+    fn drop(&mut self) {
+        self.flush_if(FlushPolicy::OnExit);
+    }
+}
+
+impl Device for FileMemory {
+    fn name(&self) -> &'static str {
+        "file backed memory"
+    }
+
+    // Read only regions act like a ROM, they can be executed from but the
+    // guest and loaders can't write to them
+    fn kind(&self) -> DeviceKind {
+        match self.file {
+            Some(_) => DeviceKind::Ram,
+            None => DeviceKind::Rom,
+        }
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        Ok(device::read_le(&self.data, offset, size))
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        if self.file.is_none() {
+            return Err("write to read only memory".to_string());
+        }
+        device::write_le(&mut self.data, offset, size, value);
+        self.mark_dirty(offset as usize, size as usize);
+        self.flush_if(FlushPolicy::WriteThrough);
+        Ok(())
+    }
+
+    // Bulk writes land before the next instruction, which is as soon as
+    // anyone could tell the difference
+    fn tick(&mut self, _elapsed_micros: u64) {
+        if self.dirty.is_some() {
+            self.flush_if(FlushPolicy::WriteThrough);
+        }
+    }
+
+    fn memory(&self, offset: u32, len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        Some(vec![self.bytes(offset, len)])
+    }
+
+    fn memory_mut(&mut self, offset: u32, len: u32) -> Option<Vec<&mut [rv32::Byte]>> {
+        self.file.as_ref()?;
+        Some(vec![self.bytes_mut(offset, len)])
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,510 @@
+use std::any::Any;
+
+use crate::helpers::{parse_address, parse_size};
+use crate::system::device::{self, Device, DeviceKind};
+use crate::system::fdt::FdtWriter;
+use crate::system::file_memory::{FileMemory, FlushPolicy};
+use crate::system::rv32;
+
+// CFI parallel NOR flash
+//
+// Reads of the array return the contents of the backing image, so code can
+// run straight out of flash, writes are commands. Either the Intel command
+// set (0x0001, one write commands and a status register) or the AMD one
+// (0x0002, unlock cycles at 0x555/0x2aa and data polling) is spoken.
+//
+// Like the real thing programming can only clear bits and erasing sets a
+// whole block back to 0xff. Every operation finishes instantly, so the
+// status register always reads ready and AMD data polling sees the final
+// value on the first read. Blocks start out unlocked, lock bits aren't kept
+// across runs.
+//
+// Command and query addresses are counted in bank width units, a bank width
+// of 4 is a 32 bit bus with the commands in the low byte.
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 64 * 1024;
+pub const DEFAULT_WIDTH: u32 = 4;
+
+// Intel status register bits
+pub const STATUS_READY: u8 = 0x80;
+pub const STATUS_ERASE_ERROR: u8 = 0x20;
+pub const STATUS_PROGRAM_ERROR: u8 = 0x10;
+pub const STATUS_LOCKED: u8 = 0x02;
+
+// Where the primary vendor specific extended query table starts
+const EXTENDED_QUERY: usize = 0x31;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommandSet {
+    Intel,
+    Amd,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    ReadArray,
+    Id,
+    Query,
+    Status,       // Intel, reads return the status register
+    Program,      // the next write is the data to program
+    EraseSetup,   // Intel waiting for the confirm, AMD for the second unlock
+    LockSetup,    // Intel waiting for lock or unlock
+    Unlock1,      // AMD, seen 0xaa at 0x555
+    Unlock2,      // AMD, seen 0x55 at 0x2aa
+    EraseUnlock1, // AMD, seen 0xaa after 0x80
+    EraseUnlock2, // AMD, seen 0x55 after that, erase command next
+}
+
+pub struct Flash {
+    store: FileMemory,
+    command_set: CommandSet,
+    width: u32,
+    block_size: u32,
+    mode: Mode,
+    status: u8,
+    locked: Vec<bool>,
+    query: Vec<rv32::Byte>,
+}
+
+impl Flash {
+    pub fn new(
+        store: FileMemory,
+        command_set: CommandSet,
+        width: u32,
+        block_size: u32,
+    ) -> Result<Flash, String> {
+        let size = store.size();
+        if !matches!(width, 1 | 2 | 4) {
+            return Err(format!("bank width {} should be 1, 2 or 4", width));
+        }
+        if !size.is_power_of_two() {
+            return Err(format!("flash size 0x{:x} isn't a power of two", size));
+        }
+        if !block_size.is_multiple_of(256) || block_size > size || !size.is_multiple_of(block_size)
+        {
+            return Err(format!(
+                "block size 0x{:x} doesn't divide the flash size 0x{:x} into 256 byte multiples",
+                block_size, size
+            ));
+        }
+        let blocks = size / block_size;
+        if blocks > 0x10000 || block_size / 256 > 0xffff {
+            return Err(format!(
+                "{} blocks of 0x{:x} bytes can't be described in the query table",
+                blocks, block_size
+            ));
+        }
+        println!(
+            "VM > Initialised {} CFI flash with {} blocks of {} bytes",
+            match command_set {
+                CommandSet::Intel => "Intel",
+                CommandSet::Amd => "AMD",
+            },
+            blocks,
+            block_size
+        );
+        Ok(Flash {
+            query: query_table(command_set, size, blocks, block_size),
+            store,
+            command_set,
+            width,
+            block_size,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+            locked: vec![false; blocks as usize],
+        })
+    }
+
+    // FILE@ADDR[,size=SIZE][,block=SIZE][,width=N][,amd], returning where
+    // to map it
+    pub fn from_spec(spec: &str) -> Result<(u32, Flash), String> {
+        let mut parts = spec.split(',');
+        let (path, base) = parts
+            .next()
+            .and_then(|region| region.rsplit_once('@'))
+            .ok_or(format!("{} should be FILE@ADDR", spec))?;
+        let base = parse_address(base)?;
+        let (mut size, mut block_size) = (None, DEFAULT_BLOCK_SIZE);
+        let (mut width, mut command_set) = (DEFAULT_WIDTH, CommandSet::Intel);
+        for option in parts {
+            match option.split_once('=') {
+                Some(("size", value)) => size = Some(parse_size(value)?),
+                Some(("block", value)) => block_size = parse_size(value)?,
+                Some(("width", value)) => width = parse_address(value)?,
+                None if option == "intel" => command_set = CommandSet::Intel,
+                None if option == "amd" => command_set = CommandSet::Amd,
+                _ => return Err(format!("unknown flash option {}", option)),
+            }
+        }
+        // programs and erases are written back as they happen, the image
+        // is up to date even if the VM doesn't exit cleanly
+        let store = FileMemory::open(path, size, 0xff, true, FlushPolicy::WriteThrough)?;
+        Ok((base, Flash::new(store, command_set, width, block_size)?))
+    }
+
+    pub fn size(&self) -> u32 {
+        self.store.size()
+    }
+
+    fn block(&self, offset: u32) -> usize {
+        (offset / self.block_size) as usize
+    }
+
+    // Programming can only take bits from 1 to 0
+    fn program(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) {
+        if self.locked[self.block(offset)] {
+            self.status |= STATUS_PROGRAM_ERROR | STATUS_LOCKED;
+            return;
+        }
+        let bytes = value.to_le_bytes();
+        for (cell, byte) in self.store.bytes_mut(offset, size).iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+    }
+
+    fn erase(&mut self, offset: u32) {
+        let block = self.block(offset);
+        if self.locked[block] {
+            self.status |= STATUS_ERASE_ERROR | STATUS_LOCKED;
+            return;
+        }
+        let start = block as u32 * self.block_size;
+        self.store.bytes_mut(start, self.block_size).fill(0xff);
+    }
+
+    fn intel_command(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) {
+        let command = value as u8;
+        self.mode = match (self.mode, command) {
+            (Mode::Program, _) => {
+                self.program(offset, size, value);
+                Mode::Status
+            }
+            (Mode::EraseSetup, 0xd0) => {
+                self.erase(offset);
+                Mode::Status
+            }
+            (Mode::LockSetup, 0x01 | 0x2f) => {
+                let block = self.block(offset);
+                self.locked[block] = true;
+                Mode::Status
+            }
+            (Mode::LockSetup, 0xd0) => {
+                let block = self.block(offset);
+                self.locked[block] = false;
+                Mode::Status
+            }
+            // anything but the confirm is a command sequence error
+            (Mode::EraseSetup | Mode::LockSetup, _) => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::Status
+            }
+            (_, 0xff | 0x00) => Mode::ReadArray,
+            (_, 0x90) => Mode::Id,
+            (_, 0x98) => Mode::Query,
+            (_, 0x70) => Mode::Status,
+            (mode, 0x50) => {
+                self.status = STATUS_READY;
+                mode
+            }
+            (_, 0x40 | 0x10) => Mode::Program,
+            (_, 0x20) => Mode::EraseSetup,
+            (_, 0x60) => Mode::LockSetup,
+            // suspend and resume, nothing is ever in progress to suspend
+            (mode, 0xb0 | 0xd0) => mode,
+            (_, _) => {
+                println!("VM > Flash ignoring unknown command 0x{:02x}", command);
+                Mode::ReadArray
+            }
+        };
+    }
+
+    fn amd_command(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) {
+        let command = value as u8;
+        let address = (offset / self.width) & 0x7ff;
+        self.mode = match (self.mode, address, command) {
+            (Mode::Program, _, _) => {
+                self.program(offset, size, value);
+                Mode::ReadArray
+            }
+            (_, _, 0xf0) => Mode::ReadArray,
+            (Mode::ReadArray | Mode::Id | Mode::Query, 0x55, 0x98) => Mode::Query,
+            (Mode::ReadArray | Mode::Id, 0x555, 0xaa) => Mode::Unlock1,
+            (Mode::Unlock1, 0x2aa, 0x55) => Mode::Unlock2,
+            (Mode::Unlock2, 0x555, 0x90) => Mode::Id,
+            (Mode::Unlock2, 0x555, 0xa0) => Mode::Program,
+            (Mode::Unlock2, 0x555, 0x80) => Mode::EraseSetup,
+            (Mode::EraseSetup, 0x555, 0xaa) => Mode::EraseUnlock1,
+            (Mode::EraseUnlock1, 0x2aa, 0x55) => Mode::EraseUnlock2,
+            (Mode::EraseUnlock2, _, 0x30) => {
+                self.erase(offset);
+                Mode::ReadArray
+            }
+            (Mode::EraseUnlock2, 0x555, 0x10) => {
+                for block in 0..self.locked.len() as u32 {
+                    self.erase(block * self.block_size);
+                }
+                Mode::ReadArray
+            }
+            // a broken sequence puts the chip back to reading the array
+            (_, _, _) => Mode::ReadArray,
+        };
+    }
+
+    // Manufacturer and device ids, then whether the block is locked
+    fn id(&self, offset: u32) -> rv32::DoubleWord {
+        let (manufacturer, device) = match self.command_set {
+            CommandSet::Intel => (0x89, 0x18),
+            CommandSet::Amd => (0x01, 0x7e),
+        };
+        match (offset % self.block_size) / self.width {
+            0 => manufacturer,
+            1 => device,
+            2 => self.locked[self.block(offset)] as rv32::DoubleWord,
+            _ => 0,
+        }
+    }
+}
+
+// The CFI query structure, one entry per address in bank width units
+fn query_table(command_set: CommandSet, size: u32, blocks: u32, block_size: u32) -> Vec<u8> {
+    let mut table = vec![0; EXTENDED_QUERY + 16];
+    table[0x10..0x13].copy_from_slice(b"QRY");
+    let (primary, version): (u8, &[u8; 2]) = match command_set {
+        CommandSet::Intel => (0x01, b"10"),
+        CommandSet::Amd => (0x02, b"11"),
+    };
+    table[0x13] = primary;
+    table[0x15] = EXTENDED_QUERY as u8;
+    // 2.7 to 3.6V, no programming voltage
+    table[0x1b] = 0x27;
+    table[0x1c] = 0x36;
+    // typical and maximum times as powers of two, 16us a word and 1s a
+    // block. Buffered programming and chip erase times are zero, the
+    // buffer commands aren't supported
+    table[0x1f] = 0x04;
+    table[0x21] = 0x0a;
+    table[0x23] = 0x01;
+    table[0x25] = 0x01;
+    table[0x27] = size.trailing_zeros() as u8;
+    table[0x28] = 0x02; // x8/x16 interface
+
+    // one region of equally sized blocks
+    table[0x2c] = 1;
+    table[0x2d..0x2f].copy_from_slice(&((blocks - 1) as u16).to_le_bytes());
+    table[0x2f..0x31].copy_from_slice(&((block_size / 256) as u16).to_le_bytes());
+    // the extended table, just the signature and version with nothing
+    // optional supported
+    let extended = &mut table[EXTENDED_QUERY..];
+    extended[..3].copy_from_slice(b"PRI");
+    extended[3..5].copy_from_slice(version);
+    if command_set == CommandSet::Intel {
+        extended[14] = 0x01; // one protection register field
+    }
+    table
+}
+
+impl Device for Flash {
+    fn name(&self) -> &'static str {
+        "CFI flash"
+    }
+
+    // Executable like a ROM, it can only be changed through commands
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Rom
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        Ok(match self.mode {
+            Mode::Id => self.id(offset),
+            Mode::Query => {
+                let index = (offset / self.width) as usize;
+                *self.query.get(index).unwrap_or(&0) as rv32::DoubleWord
+            }
+            Mode::Status | Mode::Program | Mode::EraseSetup | Mode::LockSetup
+                if self.command_set == CommandSet::Intel =>
+            {
+                self.status as rv32::DoubleWord
+            }
+            _ => device::read_le(self.store.bytes(0, self.size()), offset, size),
+        })
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        match self.command_set {
+            CommandSet::Intel => self.intel_command(offset, size, value),
+            CommandSet::Amd => self.amd_command(offset, size, value),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.mode = Mode::ReadArray;
+        self.status = STATUS_READY;
+    }
+
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.store.tick(elapsed_micros);
+    }
+
+    // Only the array can be copied out, not whatever a command has the
+    // chip returning
+    fn memory(&self, offset: u32, len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        match self.mode {
+            Mode::ReadArray => Some(vec![self.store.bytes(offset, len)]),
+            _ => None,
+        }
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("flash")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_string("compatible", "cfi-flash");
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.property_u32("bank-width", self.width);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u32 = 0x1000;
+
+    // Two blocks, nothing is ever written back to /dev/null
+    fn flash(command_set: CommandSet, width: u32) -> Flash {
+        let store = FileMemory::open(
+            "/dev/null",
+            Some(2 * BLOCK),
+            0xff,
+            false,
+            FlushPolicy::OnExit,
+        )
+        .unwrap();
+        Flash::new(store, command_set, width, BLOCK).unwrap()
+    }
+
+    fn read_word(flash: &mut Flash, offset: u32) -> u64 {
+        flash.read(offset, 4).unwrap()
+    }
+
+    fn command(flash: &mut Flash, offset: u32, value: u64) {
+        flash.write(offset, 1, value).unwrap();
+    }
+
+    #[test]
+    fn intel_program_only_clears_bits() {
+        let mut flash = flash(CommandSet::Intel, 1);
+        command(&mut flash, 0x10, 0x40);
+        flash.write(0x10, 4, 0x12345678).unwrap();
+        assert_eq!(read_word(&mut flash, 0x10) as u8, STATUS_READY);
+        command(&mut flash, 0, 0xff);
+        assert_eq!(read_word(&mut flash, 0x10), 0x12345678);
+
+        command(&mut flash, 0x10, 0x40);
+        flash.write(0x10, 4, 0xff00ff00).unwrap();
+        command(&mut flash, 0, 0xff);
+        assert_eq!(read_word(&mut flash, 0x10), 0x12005600);
+    }
+
+    #[test]
+    fn intel_erase_sets_one_block() {
+        let mut flash = flash(CommandSet::Intel, 1);
+        for offset in [0x10, BLOCK + 0x10] {
+            command(&mut flash, offset, 0x40);
+            flash.write(offset, 4, 0).unwrap();
+        }
+        command(&mut flash, BLOCK + 4, 0x20);
+        command(&mut flash, BLOCK + 4, 0xd0);
+        command(&mut flash, 0, 0xff);
+        assert_eq!(read_word(&mut flash, 0x10), 0);
+        assert_eq!(read_word(&mut flash, BLOCK + 0x10), 0xffffffff);
+    }
+
+    #[test]
+    fn intel_sequence_errors_and_locks() {
+        let mut flash = flash(CommandSet::Intel, 1);
+        // erase wants its confirm next
+        command(&mut flash, 0, 0x20);
+        command(&mut flash, 0, 0x00);
+        let errors = STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+        assert_eq!(read_word(&mut flash, 0) as u8 & errors, errors);
+        command(&mut flash, 0, 0x50);
+        assert_eq!(read_word(&mut flash, 0) as u8, STATUS_READY);
+
+        command(&mut flash, 0, 0x60);
+        command(&mut flash, 0, 0x01);
+        command(&mut flash, 0x10, 0x40);
+        flash.write(0x10, 4, 0).unwrap();
+        let locked = STATUS_PROGRAM_ERROR | STATUS_LOCKED;
+        assert_eq!(read_word(&mut flash, 0) as u8 & locked, locked);
+        command(&mut flash, 0, 0xff);
+        assert_eq!(read_word(&mut flash, 0x10), 0xffffffff);
+    }
+
+    #[test]
+    fn query_is_addressed_in_bank_width_units() {
+        let mut flash = flash(CommandSet::Intel, 4);
+        command(&mut flash, 0x55 * 4, 0x98);
+        let qry: Vec<u8> = (0x10..0x13)
+            .map(|i| read_word(&mut flash, i * 4) as u8)
+            .collect();
+        assert_eq!(qry, b"QRY");
+        assert_eq!(read_word(&mut flash, 0x13 * 4), 0x01);
+    }
+
+    fn unlock(flash: &mut Flash, width: u32, last: u64) {
+        command(flash, 0x555 * width, 0xaa);
+        command(flash, 0x2aa * width, 0x55);
+        command(flash, 0x555 * width, last);
+    }
+
+    #[test]
+    fn amd_program_and_id() {
+        let mut flash = flash(CommandSet::Amd, 2);
+        unlock(&mut flash, 2, 0xa0);
+        flash.write(0x20, 4, 0x12345678).unwrap();
+        // back to the array straight away, data polling sees the result
+        assert_eq!(read_word(&mut flash, 0x20), 0x12345678);
+
+        unlock(&mut flash, 2, 0x90);
+        assert_eq!(read_word(&mut flash, 0) as u8, 0x01);
+        assert_eq!(read_word(&mut flash, 2) as u8, 0x7e);
+        command(&mut flash, 0, 0xf0);
+        assert_eq!(read_word(&mut flash, 0x20), 0x12345678);
+    }
+
+    #[test]
+    fn amd_sector_erase() {
+        let mut flash = flash(CommandSet::Amd, 2);
+        for offset in [0x20, BLOCK + 0x20] {
+            unlock(&mut flash, 2, 0xa0);
+            flash.write(offset, 4, 0).unwrap();
+        }
+        unlock(&mut flash, 2, 0x80);
+        command(&mut flash, 0x555 * 2, 0xaa);
+        command(&mut flash, 0x2aa * 2, 0x55);
+        command(&mut flash, BLOCK, 0x30);
+        assert_eq!(read_word(&mut flash, 0x20), 0);
+        assert_eq!(read_word(&mut flash, BLOCK + 0x20), 0xffffffff);
+    }
+
+    #[test]
+    fn amd_broken_unlock_is_ignored() {
+        let mut flash = flash(CommandSet::Amd, 2);
+        command(&mut flash, 0x555 * 2, 0xaa);
+        command(&mut flash, 0x123 * 2, 0x55);
+        command(&mut flash, 0x555 * 2, 0xa0);
+        flash.write(0x20, 4, 0).unwrap();
+        assert_eq!(read_word(&mut flash, 0x20), 0xffffffff);
+    }
+}
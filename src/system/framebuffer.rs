@@ -0,0 +1,295 @@
+use std::any::Any;
+use std::path::PathBuf;
+
+use crate::helpers::parse_address;
+use crate::system::device::{self, Device, DeviceKind};
+use crate::system::fdt::FdtWriter;
+use crate::system::pma::Pma;
+use crate::system::rv32;
+use crate::system::screenshot;
+
+// A linear framebuffer, plain memory the guest draws into that's described
+// to it as a simple-framebuffer so Linux brings up simplefb on it. There's
+// no display, what's on it is seen through screenshots: on demand, when
+// the VM exits and every so often into a directory, skipping frames that
+// haven't changed since the last one.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    R5G6B5,
+    R8G8B8,   // b, g, r in memory
+    X8R8G8B8, // b, g, r, x
+    A8R8G8B8,
+    X8B8G8R8, // r, g, b, x
+    A8B8G8R8,
+}
+
+impl Format {
+    fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "r5g6b5" => Ok(Format::R5G6B5),
+            "r8g8b8" => Ok(Format::R8G8B8),
+            "x8r8g8b8" => Ok(Format::X8R8G8B8),
+            "a8r8g8b8" => Ok(Format::A8R8G8B8),
+            "x8b8g8r8" => Ok(Format::X8B8G8R8),
+            "a8b8g8r8" => Ok(Format::A8B8G8R8),
+            _ => Err(format!("unknown pixel format {}", name)),
+        }
+    }
+
+    // As the simple-framebuffer binding spells it
+    fn name(&self) -> &'static str {
+        match self {
+            Format::R5G6B5 => "r5g6b5",
+            Format::R8G8B8 => "r8g8b8",
+            Format::X8R8G8B8 => "x8r8g8b8",
+            Format::A8R8G8B8 => "a8r8g8b8",
+            Format::X8B8G8R8 => "x8b8g8r8",
+            Format::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> u32 {
+        match self {
+            Format::R5G6B5 => 2,
+            Format::R8G8B8 => 3,
+            _ => 4,
+        }
+    }
+
+    // Alpha is ignored, there's nothing behind the screen
+    fn rgb(&self, pixel: &[rv32::Byte]) -> [rv32::Byte; 3] {
+        match self {
+            Format::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (r, g, b) = (value >> 11, value >> 5 & 0x3f, value & 0x1f);
+                [
+                    (r << 3 | r >> 2) as u8,
+                    (g << 2 | g >> 4) as u8,
+                    (b << 3 | b >> 2) as u8,
+                ]
+            }
+            Format::R8G8B8 | Format::X8R8G8B8 | Format::A8R8G8B8 => [pixel[2], pixel[1], pixel[0]],
+            Format::X8B8G8R8 | Format::A8B8G8R8 => [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+// Frames written every so often, numbered from 0
+struct FrameDump {
+    dir: PathBuf,
+    extension: &'static str,
+    every_micros: u64,
+    since: u64,
+    count: u64,
+    last: Option<Vec<rv32::Byte>>,
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: Format,
+    stride: u32,
+    data: Vec<rv32::Byte>,
+    exit_screenshot: Option<String>,
+    frame_dump: Option<FrameDump>,
+}
+
+impl Framebuffer {
+    // WIDTHxHEIGHT[@ADDR][,format=FORMAT], returning where to map it if
+    // it was given
+    pub fn from_spec(spec: &str) -> Result<(Option<u32>, Framebuffer), String> {
+        let mut parts = spec.split(',');
+        let mode = parts.next().unwrap_or("");
+        let (resolution, base) = match mode.split_once('@') {
+            Some((resolution, base)) => (resolution, Some(parse_address(base)?)),
+            None => (mode, None),
+        };
+        let (width, height) = resolution
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+            .filter(|(w, h)| *w > 0 && *h > 0 && *w <= 8192 && *h <= 8192)
+            .ok_or(format!("{} isn't a resolution", resolution))?;
+        let mut format = Format::X8R8G8B8;
+        for option in parts {
+            match option.split_once('=') {
+                Some(("format", value)) => format = Format::parse(value)?,
+                _ => return Err(format!("unknown framebuffer option {}", option)),
+            }
+        }
+        let stride = width * format.bytes_per_pixel();
+        // mapped in whole pages
+        let size = (stride * height + 0xfff) & !0xfff;
+        println!(
+            "VM > Initialised {}x{} {} framebuffer",
+            width,
+            height,
+            format.name()
+        );
+        Ok((
+            base,
+            Framebuffer {
+                width,
+                height,
+                format,
+                stride,
+                data: vec![0; size as usize],
+                exit_screenshot: None,
+                frame_dump: None,
+            },
+        ))
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    // What's on screen, 8 bit RGB a row at a time
+    pub fn rgb(&self) -> Vec<rv32::Byte> {
+        let bpp = self.format.bytes_per_pixel() as usize;
+        let mut rgb = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for row in self
+            .data
+            .chunks(self.stride as usize)
+            .take(self.height as usize)
+        {
+            for pixel in row.chunks(bpp) {
+                rgb.extend_from_slice(&self.format.rgb(pixel));
+            }
+        }
+        rgb
+    }
+
+    pub fn screenshot(&self, path: &str) -> Result<(), String> {
+        screenshot::write(path, self.width, self.height, &self.rgb())
+    }
+
+    pub fn screenshot_at_exit(&mut self, path: &str) {
+        self.exit_screenshot = Some(path.to_string());
+    }
+
+    // DIR[,every=MS][,ppm], PNGs once a second unless asked otherwise
+    pub fn dump_frames(&mut self, spec: &str) -> Result<(), String> {
+        let mut parts = spec.split(',');
+        let dir = PathBuf::from(parts.next().unwrap_or(""));
+        let (mut every, mut extension) = (1000, "png");
+        for option in parts {
+            match option.split_once('=') {
+                Some(("every", value)) => every = parse_address(value)?.max(1),
+                None if option == "ppm" => extension = "ppm",
+                _ => return Err(format!("unknown frame dump option {}", option)),
+            }
+        }
+        std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        println!("VM > Dumping frames to {} every {}ms", dir.display(), every);
+        self.frame_dump = Some(FrameDump {
+            dir,
+            extension,
+            every_micros: every as u64 * 1000,
+            since: 0,
+            count: 0,
+            last: None,
+        });
+        Ok(())
+    }
+
+    fn dump_frame(&mut self) {
+        let rgb = self.rgb();
+        let dump = self.frame_dump.as_mut().unwrap();
+        if dump.last.as_ref() == Some(&rgb) {
+            return;
+        }
+        let path = dump
+            .dir
+            .join(format!("frame-{:05}.{}", dump.count, dump.extension));
+        let path = path.to_string_lossy();
+        if let Err(e) = screenshot::write(&path, self.width, self.height, &rgb) {
+            println!("VM > Failed to dump frame {}", e);
+        }
+        dump.count += 1;
+        dump.last = Some(rgb);
+    }
+}
+
+// The last frame goes out with the VM
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        if let Some(path) = self.exit_screenshot.as_ref() {
+            match self.screenshot(path) {
+                Ok(()) => println!("VM > Screenshot written to {}", path),
+                Err(e) => println!("VM > Failed to write screenshot {}", e),
+            }
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Ram
+    }
+
+    // Memory, but not for running code from
+    fn pma(&self) -> Pma {
+        Pma {
+            executable: false,
+            ..Pma::MEMORY
+        }
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        Ok(device::read_le(&self.data, offset, size))
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        device::write_le(&mut self.data, offset, size, value);
+        Ok(())
+    }
+
+    // Frames are dumped by the host's clock
+    fn tick(&mut self, elapsed_micros: u64) {
+        let dump = match self.frame_dump.as_mut() {
+            Some(dump) => dump,
+            None => return,
+        };
+        dump.since += elapsed_micros;
+        if dump.since >= dump.every_micros {
+            dump.since = 0;
+            self.dump_frame();
+        }
+    }
+
+    fn memory(&self, offset: u32, len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        Some(vec![&self.data[offset as usize..(offset + len) as usize]])
+    }
+
+    fn memory_mut(&mut self, offset: u32, len: u32) -> Option<Vec<&mut [rv32::Byte]>> {
+        Some(vec![
+            &mut self.data[offset as usize..(offset + len) as usize],
+        ])
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("framebuffer")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.property_u32("width", self.width);
+        fdt.property_u32("height", self.height);
+        fdt.property_u32("stride", self.stride);
+        fdt.property_string("format", self.format.name());
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -2,3 +2,26 @@ pub mod bus;
 pub mod uart;
 pub mod ram;
 pub mod rv32;
+pub mod clint;
+pub mod plic;
+pub mod fdt;
+pub mod boot;
+pub mod dtb;
+pub mod rom;
+pub mod device;
+pub mod file_memory;
+pub mod flash;
+pub mod pma;
+pub mod console;
+pub mod serial;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod net;
+pub mod virtio_net;
+pub mod p9;
+pub mod virtio_9p;
+pub mod virtio_rng;
+pub mod virtio_console;
+pub mod screenshot;
+pub mod framebuffer;
+pub mod rtc;
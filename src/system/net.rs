@@ -0,0 +1,221 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Host side of a network card
+//
+// Nothing here reaches a real network, frames only go between NICs we
+// know about so guest networking can be tested offline.
+//
+//   loopback                 frames sent come straight back
+//   hub:NAME                 every NIC on the hub sees what the others send
+//   unix:PATH,peer=PATH      a Unix datagram socket, for joining two VMs
+//   pcap:FILE                frames sent are captured, nothing comes back
+//
+// Ethernet frames without the FCS, as virtio-net hands them over.
+
+pub const MAX_FRAME: usize = 65535; // largest frame passed on
+
+pub trait Backend {
+    fn describe(&self) -> String;
+
+    fn send(&mut self, frame: &[u8]);
+
+    // A frame for us if one has arrived, never waits
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+// options is what's left of the NIC's spec for the backend to take its
+// own from
+pub fn from_spec(spec: &str, options: &mut Vec<(&str, &str)>) -> Result<Box<dyn Backend>, String> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "loopback" => Ok(Box::new(Loopback(VecDeque::new()))),
+        "hub" => Ok(Box::new(Hub::join(arg))),
+        "unix" => {
+            let peer = take_option(options, "peer")
+                .ok_or("unix backend needs a peer, unix:PATH,peer=PATH".to_string())?;
+            Ok(Box::new(Datagram::bind(arg, peer)?))
+        }
+        "pcap" => Ok(Box::new(Capture::create(arg)?)),
+        _ => Err(format!("unknown network backend {}", spec)),
+    }
+}
+
+pub fn take_option<'a>(options: &mut Vec<(&str, &'a str)>, key: &str) -> Option<&'a str> {
+    let index = options.iter().position(|(k, _)| *k == key)?;
+    Some(options.remove(index).1)
+}
+
+pub struct Loopback(VecDeque<Vec<u8>>);
+
+impl Backend for Loopback {
+    fn describe(&self) -> String {
+        "loopback".to_string()
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        self.0.push_back(frame.to_vec());
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.0.pop_front()
+    }
+}
+
+// Hubs are shared by every NIC in the process that names them
+type Ports = Vec<(usize, Sender<Vec<u8>>)>;
+
+static HUBS: OnceLock<Mutex<HashMap<String, Ports>>> = OnceLock::new();
+
+pub struct Hub {
+    name: String,
+    port: usize,
+    input: Receiver<Vec<u8>>,
+}
+
+impl Hub {
+    fn join(name: &str) -> Hub {
+        let mut hubs = HUBS
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap();
+        let ports = hubs.entry(name.to_string()).or_default();
+        let port = ports.len();
+        let (sender, input) = mpsc::channel();
+        ports.push((port, sender));
+        Hub {
+            name: name.to_string(),
+            port,
+            input,
+        }
+    }
+}
+
+impl Backend for Hub {
+    fn describe(&self) -> String {
+        format!("hub {} port {}", self.name, self.port)
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        let hubs = HUBS.get().unwrap().lock().unwrap();
+        for (port, sender) in hubs[&self.name].iter() {
+            if *port != self.port {
+                let _ = sender.send(frame.to_vec());
+            }
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.input.try_recv().ok()
+    }
+}
+
+pub struct Datagram {
+    path: String,
+    peer: String,
+    socket: UnixDatagram,
+    buffer: Vec<u8>,
+}
+
+impl Datagram {
+    fn bind(path: &str, peer: &str) -> Result<Datagram, String> {
+        // a socket left behind by an earlier run would stop us binding
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        let socket = UnixDatagram::bind(path).map_err(|e| format!("{}: {}", path, e))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Datagram {
+            path: path.to_string(),
+            peer: peer.to_string(),
+            socket,
+            buffer: vec![0; MAX_FRAME],
+        })
+    }
+}
+
+impl Backend for Datagram {
+    fn describe(&self) -> String {
+        format!("unix {} to {}", self.path, self.peer)
+    }
+
+    // frames sent before the peer is up are lost, as on a real wire
+    fn send(&mut self, frame: &[u8]) {
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.socket.recv(&mut self.buffer) {
+                Ok(n) => return Some(self.buffer[..n].to_vec()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+// pcap capture, as a backend of its own or tapping another NIC's traffic
+pub struct Capture {
+    path: String,
+    file: BufWriter<File>,
+}
+
+impl Capture {
+    pub fn create(path: &str) -> Result<Capture, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut capture = Capture {
+            path: path.to_string(),
+            file: BufWriter::new(file),
+        };
+        // magic, version 2.4, GMT, accuracy, snaplen and Ethernet
+        let header = [0xa1b2c3d4, 0x00040002, 0, 0, MAX_FRAME as u32, 1];
+        capture.write(&header, &[]);
+        Ok(capture)
+    }
+
+    fn write(&mut self, header: &[u32], data: &[u8]) {
+        let bytes: Vec<u8> = header.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let written = self
+            .file
+            .write_all(&bytes)
+            .and_then(|_| self.file.write_all(data));
+        // whole packets at a time, so a capture can be followed as it's taken
+        if written.and(self.file.flush()).is_err() {
+            println!("VM > Failed to write capture {}", self.path);
+        }
+    }
+
+    pub fn record(&mut self, frame: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = frame.len() as u32;
+        let header = [now.as_secs() as u32, now.subsec_micros(), len, len];
+        self.write(&header, frame);
+    }
+}
+
+impl Backend for Capture {
+    fn describe(&self) -> String {
+        format!("pcap {}", self.path)
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        self.record(frame);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
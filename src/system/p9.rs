@@ -0,0 +1,904 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// 9P2000.L file server exporting a host directory
+//
+// Requests come in and replies go out whole, the transport carries them.
+// Every fid is a path relative to the exported root, built up a name at a
+// time by walking. Symlinks are never followed, the client reads them and
+// resolves them itself, and each host path's real parent is checked to
+// still be inside the root before it's used, so the guest can't get out
+// of the export through .. or a symlink however the tree changes under
+// us. Files are created as whoever runs the VM, the guest's uids and
+// gids aren't applied.
+
+pub const VERSION: &str = "9P2000.L";
+
+pub const MAX_MSIZE: u32 = 512 * 1024;
+
+pub const TLERROR: u8 = 6;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TMKNOD: u8 = 18;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TXATTRWALK: u8 = 30;
+pub const TXATTRCREATE: u8 = 32;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLOCK: u8 = 52;
+pub const TGETLOCK: u8 = 54;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TAUTH: u8 = 102;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+// Linux's dirent types
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+const DT_UNKNOWN: u8 = 0;
+
+// setattr valid bits
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+const HEADER_SIZE: usize = 7; // size, type and tag
+const IOHDR_SIZE: usize = HEADER_SIZE + 4; // and a count
+
+type Qid = (u8, u32, u64);
+
+// Errors are errnos, as Rlerror sends them
+type Result<T> = std::result::Result<T, u32>;
+
+fn errno(error: io::Error) -> u32 {
+    error.raw_os_error().unwrap_or(libc::EIO) as u32
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or(libc::EPROTO as u32)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| libc::EINVAL as u32)
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) -> &mut Writer {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Writer {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Writer {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Writer {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Writer {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Writer {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    fn qid(&mut self, (kind, version, path): Qid) -> &mut Writer {
+        self.u8(kind).u32(version).u64(path)
+    }
+}
+
+fn qid(metadata: &Metadata) -> Qid {
+    let kind = if metadata.is_dir() {
+        QTDIR
+    } else if metadata.file_type().is_symlink() {
+        QTSYMLINK
+    } else {
+        QTFILE
+    };
+    // the version changes with the file, so the client knows to drop
+    // what it has cached
+    (
+        kind,
+        metadata.mtime() as u32 ^ metadata.size() as u32,
+        metadata.ino(),
+    )
+}
+
+fn dirent_type(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        DT_DIR
+    } else if file_type.is_file() {
+        DT_REG
+    } else if file_type.is_symlink() {
+        DT_LNK
+    } else {
+        DT_UNKNOWN
+    }
+}
+
+// A name inside a directory, never a path
+fn check_name(name: &str) -> Result<()> {
+    match name {
+        "" | "." | ".." => Err(libc::EINVAL as u32),
+        _ if name.contains('/') => Err(libc::EINVAL as u32),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Default)]
+struct Fid {
+    path: PathBuf, // relative to the root
+    file: Option<File>,
+    entries: Vec<(String, Qid, u8)>, // directory listing being read
+}
+
+pub struct Server {
+    root: PathBuf,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: &str, read_only: bool) -> std::result::Result<Server, String> {
+        let root = fs::canonicalize(root).map_err(|e| format!("{}: {}", root, e))?;
+        if !root.is_dir() {
+            return Err(format!("{} isn't a directory", root.display()));
+        }
+        Ok(Server {
+            root,
+            read_only,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Handle a whole request, the reply is cut to fit in limit bytes
+    pub fn handle(&mut self, request: &[u8], limit: usize) -> Vec<u8> {
+        let mut reader = Reader {
+            data: request,
+            offset: 0,
+        };
+        let header = (reader.u32(), reader.u8(), reader.u16());
+        let (kind, tag) = match header {
+            (Ok(_), Ok(kind), Ok(tag)) => (kind, tag),
+            _ => (TLERROR, 0xffff),
+        };
+        // replies can't be bigger than the client said or than we're given
+        let limit = std::cmp::min(limit, self.msize as usize);
+        let mut reply = Writer::default();
+        let result = self.dispatch(kind, &mut reader, &mut reply, limit);
+        let (kind, body) = match result {
+            Ok(()) => (kind + 1, reply.0),
+            Err(errno) => (TLERROR + 1, errno.to_le_bytes().to_vec()),
+        };
+        let mut message = Writer::default();
+        message
+            .u32((HEADER_SIZE + body.len()) as u32)
+            .u8(kind)
+            .u16(tag)
+            .bytes(&body);
+        message.0
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer, limit: usize) -> Result<()> {
+        let writes = matches!(
+            kind,
+            TLCREATE
+                | TSYMLINK
+                | TMKNOD
+                | TRENAME
+                | TSETATTR
+                | TXATTRCREATE
+                | TLINK
+                | TMKDIR
+                | TRENAMEAT
+                | TUNLINKAT
+                | TWRITE
+                | TREMOVE
+        );
+        if writes && self.read_only {
+            // a remove still clunks the fid
+            if kind == TREMOVE {
+                self.fids.remove(&r.u32()?);
+            }
+            return Err(libc::EROFS as u32);
+        }
+        match kind {
+            TVERSION => self.version(r, w),
+            TATTACH => self.attach(r, w),
+            TWALK => self.walk(r, w),
+            TLOPEN => self.lopen(r, w),
+            TLCREATE => self.lcreate(r, w),
+            TREAD => self.read(r, w, limit),
+            TWRITE => self.write(r, w),
+            TCLUNK => {
+                self.fids.remove(&r.u32()?);
+                Ok(())
+            }
+            TREMOVE => self.remove(r),
+            TGETATTR => self.getattr(r, w),
+            TSETATTR => self.setattr(r),
+            TREADDIR => self.readdir(r, w, limit),
+            TSTATFS => self.statfs(r, w),
+            TMKDIR => self.mkdir(r, w),
+            TUNLINKAT => self.unlinkat(r),
+            TRENAMEAT => self.renameat(r),
+            TRENAME => self.rename(r),
+            TSYMLINK => self.symlink(r, w),
+            TREADLINK => self.readlink(r, w),
+            TLINK => self.link(r),
+            TFSYNC => {
+                let fid = self.fid(r.u32()?)?;
+                match fid.file.as_ref() {
+                    Some(file) => file.sync_all().map_err(errno),
+                    None => Ok(()),
+                }
+            }
+            // locks are the guest's business, nobody else is using the files
+            TLOCK => {
+                w.u8(LOCK_SUCCESS);
+                Ok(())
+            }
+            TGETLOCK => {
+                let (fid, _kind) = (r.u32()?, r.u8()?);
+                self.fid(fid)?;
+                let (start, length, proc_id) = (r.u64()?, r.u64()?, r.u32()?);
+                let client = r.string()?;
+                w.u8(LOCK_TYPE_UNLCK)
+                    .u64(start)
+                    .u64(length)
+                    .u32(proc_id)
+                    .string(&client);
+                Ok(())
+            }
+            // requests are answered as they arrive, there's nothing to flush
+            TFLUSH => Ok(()),
+            TAUTH | TXATTRWALK | TXATTRCREATE | TMKNOD => Err(libc::EOPNOTSUPP as u32),
+            _ => Err(libc::ENOSYS as u32),
+        }
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(libc::EBADF as u32)
+    }
+
+    fn fid_path(&mut self, fid: u32) -> Result<PathBuf> {
+        Ok(self.fid(fid)?.path.clone())
+    }
+
+    // Where a path in the export is on the host. Its parent is resolved
+    // and has to still be inside the root, the path itself is left as it
+    // is so symlinks are operated on rather than followed
+    fn host_path(&self, path: &Path) -> Result<PathBuf> {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return Ok(self.root.clone()),
+        };
+        let parent = self.root.join(path.parent().unwrap());
+        let parent = fs::canonicalize(parent).map_err(errno)?;
+        if !parent.starts_with(&self.root) {
+            return Err(libc::EACCES as u32);
+        }
+        Ok(parent.join(name))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        fs::symlink_metadata(self.host_path(path)?).map_err(errno)
+    }
+
+    // The host path of something a request goes through rather than
+    // operating on, a symlink would be followed out of the export
+    fn followed_path(&self, path: &Path) -> Result<PathBuf> {
+        match self.metadata(path)? {
+            m if m.file_type().is_symlink() => Err(libc::ELOOP as u32),
+            _ => self.host_path(path),
+        }
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+        // a new session, everything from the last one is gone
+        self.fids.clear();
+        self.msize = std::cmp::min(msize, MAX_MSIZE);
+        let version = if version.starts_with(VERSION) {
+            VERSION
+        } else {
+            "unknown"
+        };
+        w.u32(self.msize).string(version);
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let (_afid, _uname, _aname) = (r.u32()?, r.string()?, r.string()?);
+        let root = self.metadata(Path::new(""))?;
+        self.fids.insert(fid, Fid::default());
+        w.qid(qid(&root));
+        Ok(())
+    }
+
+    // Walking from a fid only goes through directories, never a symlink,
+    // and .. stops at the root
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let (fid, newfid) = (r.u32()?, r.u32()?);
+        let names: Vec<String> = (0..r.u16()?).map(|_| r.string()).collect::<Result<_>>()?;
+        let mut path = self.fid_path(fid)?;
+        let mut qids = Vec::new();
+        for name in names.iter() {
+            let next = match name.as_str() {
+                ".." => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                _ => {
+                    check_name(name)?;
+                    path.join(name)
+                }
+            };
+            let found = match self.metadata(&path) {
+                Ok(dir) if !dir.is_dir() => Err(libc::ENOTDIR as u32),
+                Ok(_) => self.metadata(&next),
+                Err(e) => Err(e),
+            };
+            match found {
+                Ok(metadata) => qids.push(qid(&metadata)),
+                // only the first name failing is an error, otherwise the
+                // client gets however far we got
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+            path = next;
+        }
+        if qids.len() == names.len() {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    path,
+                    ..Default::default()
+                },
+            );
+        }
+        w.u16(qids.len() as u16);
+        for qid in qids {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn open(&self, path: &Path, flags: u32, mode: Option<u32>) -> Result<File> {
+        let access = flags as i32 & libc::O_ACCMODE;
+        let writes = access != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0;
+        if writes && self.read_only {
+            return Err(libc::EROFS as u32);
+        }
+        let mut options = OpenOptions::new();
+        options
+            .read(access != libc::O_WRONLY)
+            .write(access != libc::O_RDONLY)
+            .append(flags as i32 & libc::O_APPEND != 0)
+            .truncate(flags as i32 & libc::O_TRUNC != 0)
+            .custom_flags(libc::O_NOFOLLOW);
+        // creating needs write access whatever the client asked for
+        if let Some(mode) = mode {
+            options.write(true).create_new(true).mode(mode & 0o7777);
+        }
+        options.open(self.host_path(path)?).map_err(errno)
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let (fid, flags) = (r.u32()?, r.u32()?);
+        let path = self.fid_path(fid)?;
+        let file = self.open(&path, flags, None)?;
+        let metadata = file.metadata().map_err(errno)?;
+        self.fid(fid)?.file = Some(file);
+        w.qid(qid(&metadata)).u32(0);
+        Ok(())
+    }
+
+    // Create a file in the fid's directory, the fid becomes the file
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let (fid, name, flags, mode, _gid) = (r.u32()?, r.string()?, r.u32()?, r.u32()?, r.u32()?);
+        check_name(&name)?;
+        let path = self.fid_path(fid)?.join(&name);
+        let file = self.open(&path, flags, Some(mode))?;
+        let metadata = file.metadata().map_err(errno)?;
+        let fid = self.fid(fid)?;
+        fid.path = path;
+        fid.file = Some(file);
+        w.qid(qid(&metadata)).u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer, limit: usize) -> Result<()> {
+        let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+        let count = std::cmp::min(count as usize, limit.saturating_sub(IOHDR_SIZE));
+        let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF as u32)?;
+        let mut data = vec![0; count];
+        let read = file.read_at(&mut data, offset).map_err(errno)?;
+        w.u32(read as u32).bytes(&data[..read]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+        let data = r.take(count as usize)?.to_vec();
+        let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF as u32)?;
+        let written = file.write_at(&data, offset).map_err(errno)?;
+        w.u32(written as u32);
+        Ok(())
+    }
+
+    fn remove_path(&self, path: &Path) -> Result<()> {
+        let host = self.host_path(path)?;
+        match self.metadata(path)?.is_dir() {
+            true => fs::remove_dir(host).map_err(errno),
+            false => fs::remove_file(host).map_err(errno),
+        }
+    }
+
+    // The fid is clunked whether or not the remove worked
+    fn remove(&mut self, r: &mut Reader) -> Result<()> {
+        let fid = r.u32()?;
+        let path = self.fid_path(fid)?;
+        self.fids.remove(&fid);
+        if path.as_os_str().is_empty() {
+            return Err(libc::EBUSY as u32);
+        }
+        self.remove_path(&path)
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let fid = r.u32()?;
+        let _mask = r.u64()?;
+        let path = self.fid_path(fid)?;
+        let m = self.metadata(&path)?;
+        w.u64(GETATTR_BASIC)
+            .qid(qid(&m))
+            .u32(m.mode())
+            .u32(m.uid())
+            .u32(m.gid())
+            .u64(m.nlink())
+            .u64(m.rdev())
+            .u64(m.size())
+            .u64(m.blksize())
+            .u64(m.blocks())
+            .u64(m.atime() as u64)
+            .u64(m.atime_nsec() as u64)
+            .u64(m.mtime() as u64)
+            .u64(m.mtime_nsec() as u64)
+            .u64(m.ctime() as u64)
+            .u64(m.ctime_nsec() as u64)
+            .u64(0) // btime isn't in the basic set
+            .u64(0)
+            .u64(0) // gen
+            .u64(0); // data_version
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> Result<()> {
+        let (fid, valid, mode) = (r.u32()?, r.u32()?, r.u32()?);
+        let (_uid, _gid, size) = (r.u32()?, r.u32()?, r.u64()?);
+        let atime = (r.u64()? as i64, r.u64()? as i64);
+        let mtime = (r.u64()? as i64, r.u64()? as i64);
+        let path = self.fid_path(fid)?;
+        let host = self.host_path(&path)?;
+        if valid & SETATTR_MODE != 0 {
+            let permissions = fs::Permissions::from_mode(mode & 0o7777);
+            fs::set_permissions(self.followed_path(&path)?, permissions).map_err(errno)?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            let file = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&host)
+                .map_err(errno)?;
+            file.set_len(size).map_err(errno)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            // times not given are left alone, ones given without a value
+            // are now
+            let time = |set: u32, given: u32, (sec, nsec): (i64, i64)| match valid {
+                v if v & set == 0 => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+                v if v & given == 0 => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_NOW,
+                },
+                _ => libc::timespec {
+                    tv_sec: sec,
+                    tv_nsec: nsec,
+                },
+            };
+            let times = [
+                time(SETATTR_ATIME, SETATTR_ATIME_SET, atime),
+                time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime),
+            ];
+            let host = CString::new(host.as_os_str().as_bytes()).unwrap();
+            let set = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    host.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if set != 0 {
+                return Err(errno(io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+
+    // The listing is read once when the client starts from the beginning,
+    // offsets are indexes into it
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer, limit: usize) -> Result<()> {
+        let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+        let count = std::cmp::min(count as usize, limit.saturating_sub(IOHDR_SIZE));
+        let path = self.fid_path(fid)?;
+        if offset == 0 {
+            let entries = self.list(&path)?;
+            self.fid(fid)?.entries = entries;
+        }
+        let mut data = Writer::default();
+        let fid = self.fid(fid)?;
+        for (index, (name, qid, kind)) in fid.entries.iter().enumerate().skip(offset as usize) {
+            let size = 13 + 8 + 1 + 2 + name.len();
+            if data.0.len() + size > count {
+                break;
+            }
+            data.qid(*qid).u64(index as u64 + 1).u8(*kind).string(name);
+        }
+        w.u32(data.0.len() as u32).bytes(&data.0);
+        Ok(())
+    }
+
+    fn list(&self, path: &Path) -> Result<Vec<(String, Qid, u8)>> {
+        let this = self.metadata(path)?;
+        let parent = self.metadata(path.parent().unwrap_or(path))?;
+        let mut entries = vec![
+            (".".to_string(), qid(&this), DT_DIR),
+            ("..".to_string(), qid(&parent), DT_DIR),
+        ];
+        for entry in fs::read_dir(self.followed_path(path)?).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            // entries going away while we list them aren't an error
+            if let Ok(metadata) = entry.metadata() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                entries.push((name, qid(&metadata), dirent_type(&metadata)));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let path = self.fid_path(r.u32()?)?;
+        let host = CString::new(self.followed_path(&path)?.as_os_str().as_bytes()).unwrap();
+        let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+        if unsafe { libc::statvfs(host.as_ptr(), &mut stat) } != 0 {
+            return Err(errno(io::Error::last_os_error()));
+        }
+        w.u32(0x01021997) // V9FS_MAGIC
+            .u32(stat.f_bsize as u32)
+            .u64(stat.f_blocks as u64)
+            .u64(stat.f_bfree as u64)
+            .u64(stat.f_bavail as u64)
+            .u64(stat.f_files as u64)
+            .u64(stat.f_ffree as u64)
+            .u64(stat.f_fsid as u64)
+            .u32(stat.f_namemax as u32);
+        Ok(())
+    }
+
+    // A name in a directory fid
+    fn child(&mut self, r: &mut Reader) -> Result<PathBuf> {
+        let dir = self.fid_path(r.u32()?)?;
+        let name = r.string()?;
+        check_name(&name)?;
+        Ok(dir.join(name))
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let path = self.child(r)?;
+        let (mode, _gid) = (r.u32()?, r.u32()?);
+        let host = self.host_path(&path)?;
+        fs::DirBuilder::new()
+            .mode(mode & 0o7777)
+            .create(host)
+            .map_err(errno)?;
+        w.qid(qid(&self.metadata(&path)?));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> Result<()> {
+        let path = self.child(r)?;
+        let _flags = r.u32()?;
+        self.remove_path(&path)
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> Result<()> {
+        let from = self.child(r)?;
+        let to = self.child(r)?;
+        fs::rename(self.host_path(&from)?, self.host_path(&to)?).map_err(errno)?;
+        self.renamed(&from, &to);
+        Ok(())
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> Result<()> {
+        let from = self.fid_path(r.u32()?)?;
+        let to = self.child(r)?;
+        fs::rename(self.host_path(&from)?, self.host_path(&to)?).map_err(errno)?;
+        self.renamed(&from, &to);
+        Ok(())
+    }
+
+    // Fids at or under a renamed path follow it
+    fn renamed(&mut self, from: &Path, to: &Path) {
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.path.strip_prefix(from) {
+                fid.path = to.join(rest);
+            }
+        }
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let path = self.child(r)?;
+        let (target, _gid) = (r.string()?, r.u32()?);
+        // the target is only ever read back by the guest, wherever it points
+        std::os::unix::fs::symlink(target, self.host_path(&path)?).map_err(errno)?;
+        w.qid(qid(&self.metadata(&path)?));
+        Ok(())
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> Result<()> {
+        let path = self.fid_path(r.u32()?)?;
+        let target = fs::read_link(self.host_path(&path)?).map_err(errno)?;
+        w.string(&target.to_string_lossy());
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> Result<()> {
+        let dir = self.fid_path(r.u32()?)?;
+        let target = self.fid_path(r.u32()?)?;
+        let name = r.string()?;
+        check_name(&name)?;
+        let host = self.host_path(&dir.join(name))?;
+        fs::hard_link(self.host_path(&target)?, host).map_err(errno)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An export with a directory, a file next to the export it shouldn't
+    // reach and symlinks pointing out at it
+    struct Export {
+        dir: PathBuf,
+        server: Server,
+    }
+
+    impl Export {
+        fn new(name: &str) -> Export {
+            let dir =
+                std::env::temp_dir().join(format!("riscy-p9-{}-{}", std::process::id(), name));
+            let root = dir.join("root");
+            fs::create_dir_all(root.join("sub")).unwrap();
+            fs::write(root.join("sub/file"), b"inside").unwrap();
+            fs::write(dir.join("secret"), b"outside").unwrap();
+            std::os::unix::fs::symlink(&dir, root.join("out")).unwrap();
+            std::os::unix::fs::symlink("../..", root.join("sub/up")).unwrap();
+            let server = Server::new(root.to_str().unwrap(), false).unwrap();
+            Export { dir, server }
+        }
+
+        fn request(&mut self, kind: u8, body: &Writer) -> Vec<u8> {
+            let mut message = Writer::default();
+            message
+                .u32((HEADER_SIZE + body.0.len()) as u32)
+                .u8(kind)
+                .u16(1)
+                .bytes(&body.0);
+            self.server.handle(&message.0, MAX_MSIZE as usize)
+        }
+
+        fn attach(&mut self, fid: u32) {
+            let mut body = Writer::default();
+            body.u32(fid).u32(!0).string("guest").string("");
+            assert_eq!(self.request(TATTACH, &body)[4], TATTACH + 1);
+        }
+
+        // How many names the walk got through, or the errno
+        fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Result<u16> {
+            let mut body = Writer::default();
+            body.u32(fid).u32(newfid).u16(names.len() as u16);
+            for name in names {
+                body.string(name);
+            }
+            let reply = self.request(TWALK, &body);
+            let value = &reply[HEADER_SIZE..];
+            match reply[4] {
+                kind if kind == TWALK + 1 => Ok(u16::from_le_bytes([value[0], value[1]])),
+                _ => Err(u32::from_le_bytes(value[..4].try_into().unwrap())),
+            }
+        }
+    }
+
+    // The errno of an error reply
+    fn error(reply: &[u8]) -> Option<u32> {
+        match reply[4] {
+            kind if kind == TLERROR + 1 => Some(u32::from_le_bytes(
+                reply[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap(),
+            )),
+            _ => None,
+        }
+    }
+
+    impl Drop for Export {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn host_paths_stay_in_the_root() {
+        let export = Export::new("host-path");
+        let root = export.server.root().to_path_buf();
+        assert_eq!(export.server.host_path(Path::new("")), Ok(root.clone()));
+        assert_eq!(
+            export.server.host_path(Path::new("sub/file")),
+            Ok(root.join("sub/file"))
+        );
+        // a symlink is the link itself, not where it points
+        assert_eq!(
+            export.server.host_path(Path::new("out")),
+            Ok(root.join("out"))
+        );
+
+        let eacces = Err(libc::EACCES as u32);
+        assert_eq!(export.server.host_path(Path::new("../secret")), eacces);
+        assert_eq!(export.server.host_path(Path::new("out/secret")), eacces);
+        assert_eq!(export.server.host_path(Path::new("sub/up/secret")), eacces);
+    }
+
+    #[test]
+    fn dot_dot_stops_at_the_root() {
+        let mut export = Export::new("dot-dot");
+        export.attach(0);
+        assert_eq!(export.walk(0, 1, &["..", ".."]), Ok(2));
+        assert_eq!(export.server.fids[&1].path, PathBuf::new());
+        assert_eq!(
+            export.walk(1, 2, &["sub", "..", "..", "sub", "file"]),
+            Ok(5)
+        );
+        assert_eq!(export.server.fids[&2].path, PathBuf::from("sub/file"));
+        // names are names, not paths
+        assert_eq!(export.walk(0, 3, &["../secret"]), Err(libc::EINVAL as u32));
+    }
+
+    #[test]
+    fn walks_dont_follow_symlinks() {
+        let mut export = Export::new("symlinks");
+        export.attach(0);
+        // the link is found, but it isn't a directory to walk through
+        assert_eq!(export.walk(0, 1, &["out", "secret"]), Ok(1));
+        assert!(!export.server.fids.contains_key(&1));
+        assert_eq!(export.walk(0, 1, &["sub", "up", "secret"]), Ok(2));
+        assert!(!export.server.fids.contains_key(&1));
+        assert_eq!(export.walk(0, 1, &["out"]), Ok(1));
+        assert_eq!(export.server.fids[&1].path, PathBuf::from("out"));
+        assert_eq!(export.walk(1, 2, &["secret"]), Err(libc::ENOTDIR as u32));
+    }
+
+    #[test]
+    fn requests_dont_go_through_symlinks() {
+        let mut export = Export::new("through");
+        export.attach(0);
+        assert_eq!(export.walk(0, 1, &["out"]), Ok(1));
+        let eloop = Some(libc::ELOOP as u32);
+
+        // changing the mode would change the directory outside
+        let mode = fs::metadata(&export.dir).unwrap().permissions().mode();
+        let mut setattr = Writer::default();
+        setattr.u32(1).u32(SETATTR_MODE).u32(0o777).u32(0).u32(0);
+        setattr.u64(0).u64(0).u64(0).u64(0).u64(0);
+        assert_eq!(error(&export.request(TSETATTR, &setattr)), eloop);
+        assert_eq!(
+            fs::metadata(&export.dir).unwrap().permissions().mode(),
+            mode
+        );
+
+        let mut readdir = Writer::default();
+        readdir.u32(1).u64(0).u32(4096);
+        assert_eq!(error(&export.request(TREADDIR, &readdir)), eloop);
+        let mut statfs = Writer::default();
+        statfs.u32(1);
+        assert_eq!(error(&export.request(TSTATFS, &statfs)), eloop);
+
+        // a real directory still lists
+        assert_eq!(export.walk(0, 2, &["sub"]), Ok(1));
+        readdir = Writer::default();
+        readdir.u32(2).u64(0).u32(4096);
+        assert_eq!(error(&export.request(TREADDIR, &readdir)), None);
+    }
+}
@@ -0,0 +1,293 @@
+use std::any::Any;
+
+use crate::system::bus;
+use crate::system::device::{self, Device};
+use crate::system::fdt::{FdtWriter, PHANDLE_CPU_INTC, PHANDLE_PLIC};
+use crate::system::pma::{self, Pma};
+use crate::system::rv32;
+
+// Platform Level Interrupt Controller, routes device interrupt lines to
+// the hart's external interrupt. We have one hart with two contexts,
+// context 0 is machine mode and context 1 is supervisor mode
+pub const PLIC_SOURCES: usize = 32;
+pub const PLIC_CONTEXTS: usize = 2;
+
+const PRIORITY: u32 = 0x000000;
+const PENDING: u32 = 0x001000;
+const ENABLE: u32 = 0x002000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x200000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+pub struct PLIC {
+    priority: [rv32::Word; PLIC_SOURCES],
+    pending: rv32::Word,
+    claimed: rv32::Word,
+    enable: [rv32::Word; PLIC_CONTEXTS],
+    threshold: [rv32::Word; PLIC_CONTEXTS],
+}
+
+impl PLIC {
+    pub fn new() -> PLIC {
+        println!("VM > Initialised PLIC");
+        PLIC {
+            priority: [0; PLIC_SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    // Interrupt lines are level triggered, a source stays pending until
+    // it is claimed, and won't pend again until the claim is completed
+    pub fn set_irq(&mut self, irq: usize, level: bool) {
+        if irq == 0 || irq >= PLIC_SOURCES {
+            return;
+        }
+        if level && self.claimed & (1 << irq) == 0 {
+            self.pending |= 1 << irq;
+        } else if !level {
+            self.pending &= !(1 << irq);
+        }
+    }
+
+    // Highest priority pending source enabled for the context that
+    // is above the context's threshold
+    fn best(&self, context: usize) -> usize {
+        let mut best = 0;
+        let mut best_priority = self.threshold[context];
+        for irq in 1..PLIC_SOURCES {
+            let candidate = self.pending & self.enable[context] & (1 << irq) != 0;
+            if candidate && self.priority[irq] > best_priority {
+                best = irq;
+                best_priority = self.priority[irq];
+            }
+        }
+        best
+    }
+
+    fn interrupt_pending(&self, context: usize) -> bool {
+        self.best(context) != 0
+    }
+
+    fn claim(&mut self, context: usize) -> rv32::Word {
+        let irq = self.best(context);
+        if irq != 0 {
+            self.pending &= !(1 << irq);
+            self.claimed |= 1 << irq;
+        }
+        irq as rv32::Word
+    }
+
+    fn complete(&mut self, irq: rv32::Word) {
+        if (irq as usize) < PLIC_SOURCES {
+            self.claimed &= !(1 << irq);
+        }
+    }
+
+    fn read_word(&mut self, address: u32) -> rv32::Word {
+        match address {
+            a if a < PENDING => {
+                let irq = ((a - PRIORITY) / 4) as usize;
+                if irq < PLIC_SOURCES {
+                    self.priority[irq]
+                } else {
+                    0
+                }
+            }
+            PENDING => self.pending,
+            a if (ENABLE..CONTEXT).contains(&a) => {
+                let context = ((a - ENABLE) / ENABLE_STRIDE) as usize;
+                match (context < PLIC_CONTEXTS, (a - ENABLE) % ENABLE_STRIDE) {
+                    (true, 0) => self.enable[context],
+                    _ => 0,
+                }
+            }
+            a if a >= CONTEXT => {
+                let context = ((a - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= PLIC_CONTEXTS {
+                    return 0;
+                }
+                match (a - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_word(&mut self, address: u32, value: rv32::Word) {
+        match address {
+            a if a < PENDING => {
+                let irq = ((a - PRIORITY) / 4) as usize;
+                if irq < PLIC_SOURCES {
+                    self.priority[irq] = value & 0x7;
+                }
+            }
+            a if (ENABLE..CONTEXT).contains(&a) => {
+                let context = ((a - ENABLE) / ENABLE_STRIDE) as usize;
+                if context < PLIC_CONTEXTS && (a - ENABLE).is_multiple_of(ENABLE_STRIDE) {
+                    // source 0 doesn't exist
+                    self.enable[context] = value & !1;
+                }
+            }
+            a if a >= CONTEXT => {
+                let context = ((a - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= PLIC_CONTEXTS {
+                    return;
+                }
+                match (a - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = value & 0x7,
+                    4 => self.complete(value),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Device for PLIC {
+    fn name(&self) -> &'static str {
+        "PLIC"
+    }
+
+    // every register is 32 bits
+    fn pma(&self) -> Pma {
+        Pma::io(pma::WIDTH_32)
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        device::read_words(offset, size, |o| self.read_word(o))
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        device::write_words(offset, size, value, |o, v| self.write_word(o, v))
+    }
+
+    fn reset(&mut self) {
+        *self = PLIC {
+            priority: [0; PLIC_SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        };
+    }
+
+    fn interrupts(&self) -> rv32::Word {
+        let mut mip = 0;
+        if self.interrupt_pending(0) {
+            mip |= bus::MIP_MEIP;
+        }
+        if self.interrupt_pending(1) {
+            mip |= bus::MIP_SEIP;
+        }
+        mip
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("plic")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        // machine external and supervisor external interrupts, one per context
+        fdt.property_cells(
+            "interrupts-extended",
+            &[PHANDLE_CPU_INTC, 11, PHANDLE_CPU_INTC, 9],
+        );
+        fdt.property_u32("riscv,ndev", PLIC_SOURCES as u32 - 1);
+        fdt.property_u32("phandle", PHANDLE_PLIC);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S_ENABLE: u32 = ENABLE + ENABLE_STRIDE;
+    const S_THRESHOLD: u32 = CONTEXT + CONTEXT_STRIDE;
+    const S_CLAIM: u32 = S_THRESHOLD + 4;
+
+    // Sources 3 and 5 enabled for supervisor mode, 5 at the higher priority
+    fn plic() -> PLIC {
+        let mut plic = PLIC::new();
+        plic.write(PRIORITY + 3 * 4, 4, 1).unwrap();
+        plic.write(PRIORITY + 5 * 4, 4, 2).unwrap();
+        plic.write(S_ENABLE, 4, 1 << 3 | 1 << 5).unwrap();
+        plic
+    }
+
+    fn claim(plic: &mut PLIC) -> u64 {
+        plic.read(S_CLAIM, 4).unwrap()
+    }
+
+    #[test]
+    fn claims_by_priority() {
+        let mut plic = plic();
+        plic.set_irq(3, true);
+        plic.set_irq(5, true);
+        assert_eq!(plic.interrupts(), bus::MIP_SEIP);
+        assert_eq!(claim(&mut plic), 5);
+        assert_eq!(claim(&mut plic), 3);
+        assert_eq!(claim(&mut plic), 0);
+        assert_eq!(plic.interrupts(), 0);
+    }
+
+    #[test]
+    fn claimed_sources_wait_for_complete() {
+        let mut plic = plic();
+        plic.set_irq(5, true);
+        assert_eq!(claim(&mut plic), 5);
+        // the line is still high but the handler hasn't finished
+        plic.set_irq(5, true);
+        assert_eq!(plic.interrupts(), 0);
+        assert_eq!(claim(&mut plic), 0);
+
+        plic.write(S_CLAIM, 4, 5).unwrap();
+        plic.set_irq(5, true);
+        assert_eq!(plic.interrupts(), bus::MIP_SEIP);
+        assert_eq!(claim(&mut plic), 5);
+    }
+
+    #[test]
+    fn lowering_the_line_clears_pending() {
+        let mut plic = plic();
+        plic.set_irq(3, true);
+        plic.set_irq(3, false);
+        assert_eq!(plic.read(PENDING, 4).unwrap(), 0);
+        assert_eq!(claim(&mut plic), 0);
+    }
+
+    #[test]
+    fn threshold_and_enable_mask_sources() {
+        let mut plic = plic();
+        plic.set_irq(5, true);
+        plic.write(S_THRESHOLD, 4, 2).unwrap();
+        assert_eq!(plic.interrupts(), 0);
+        assert_eq!(claim(&mut plic), 0);
+
+        plic.write(S_THRESHOLD, 4, 0).unwrap();
+        plic.write(S_ENABLE, 4, 1 << 3).unwrap();
+        assert_eq!(plic.interrupts(), 0);
+        // machine mode never had it enabled
+        plic.write(ENABLE, 4, 1 << 5).unwrap();
+        assert_eq!(plic.interrupts(), bus::MIP_MEIP);
+    }
+}
@@ -0,0 +1,107 @@
+// Physical memory attributes
+//
+// Every region on the bus says what kind of accesses it supports, the way
+// real platforms have a PMA checker in front of the bus. Accesses breaking
+// them are access faults: fetching from something that isn't executable,
+// an access width the region doesn't decode, an LR/SC on a region that
+// can't hold a reservation or an AMO beyond what the region can do.
+//
+// Cacheability and idempotency aren't enforced, we have no caches and
+// never replay accesses, but say whether a region is memory or IO.
+
+// AMOs a region supports, each class includes the ones before it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AmoClass {
+    None,
+    Swap,       // AMOSWAP
+    Logical,    // AMOAND, AMOOR, AMOXOR
+    Arithmetic, // AMOADD, AMOMIN, AMOMAX and unsigned
+}
+
+// Access widths, as a mask of sizes in bytes
+pub const WIDTH_8: u8 = 1;
+pub const WIDTH_16: u8 = 2;
+pub const WIDTH_32: u8 = 4;
+pub const WIDTH_64: u8 = 8;
+pub const WIDTH_ANY: u8 = WIDTH_8 | WIDTH_16 | WIDTH_32 | WIDTH_64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pma {
+    pub cacheable: bool,
+    pub idempotent: bool,
+    pub widths: u8,
+    pub amo: AmoClass,
+    pub lrsc: bool,
+    pub executable: bool,
+}
+
+impl Pma {
+    // Main memory supports everything
+    pub const MEMORY: Pma = Pma {
+        cacheable: true,
+        idempotent: true,
+        widths: WIDTH_ANY,
+        amo: AmoClass::Arithmetic,
+        lrsc: true,
+        executable: true,
+    };
+
+    // Memory that can't be written can't hold a reservation or do AMOs
+    pub const ROM: Pma = Pma {
+        amo: AmoClass::None,
+        lrsc: false,
+        ..Pma::MEMORY
+    };
+
+    // Device registers, only the widths the device decodes
+    pub const fn io(widths: u8) -> Pma {
+        Pma {
+            cacheable: false,
+            idempotent: false,
+            widths,
+            amo: AmoClass::None,
+            lrsc: false,
+            executable: false,
+        }
+    }
+
+    pub fn supports_width(&self, size: u32) -> bool {
+        size <= WIDTH_64 as u32 && self.widths & size as u8 != 0
+    }
+
+    // Widest access up to limit bytes the region supports
+    pub fn widest(&self, limit: usize) -> usize {
+        [8, 4, 2, 1]
+            .into_iter()
+            .find(|size| *size <= limit && self.supports_width(*size as u32))
+            .unwrap_or(1)
+    }
+}
+
+impl std::fmt::Display for Pma {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let widths: Vec<String> = [1, 2, 4, 8]
+            .iter()
+            .filter(|size| self.supports_width(**size))
+            .map(|size| (size * 8).to_string())
+            .collect();
+        write!(
+            f,
+            "{}, {}, {} bit, AMO {:?}{}{}",
+            if self.cacheable {
+                "cacheable"
+            } else {
+                "uncached"
+            },
+            if self.idempotent {
+                "idempotent"
+            } else {
+                "side effects"
+            },
+            widths.join("/"),
+            self.amo,
+            if self.lrsc { ", LR/SC" } else { "" },
+            if self.executable { ", executable" } else { "" },
+        )
+    }
+}
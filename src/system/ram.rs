@@ -1,88 +1,153 @@
-use crate::system::bus;
+use std::any::Any;
+
+use crate::system::device::{self, Device, DeviceKind};
 use crate::system::rv32;
 
-pub struct RAM(pub Vec<rv32::Byte>);
+// Guest RAM is allocated a page at a time, the first time the page is
+// written. Pages nothing has written read as zero, so a VM with a gigabyte
+// of RAM running a tiny program only costs the pages it touches
+pub const PAGE_SIZE: u32 = 4096;
+
+type Page = Box<[rv32::Byte; PAGE_SIZE as usize]>;
+
+static ZERO_PAGE: [rv32::Byte; PAGE_SIZE as usize] = [0; PAGE_SIZE as usize];
+
+pub struct RAM {
+    pages: Vec<Option<Page>>,
+    dirty: Vec<bool>, // written since the last clear_dirty
+}
+
+// Split [offset, offset + len) at page boundaries, as the page number,
+// where the span starts in that page and how long it is
+fn spans(offset: u32, len: u32) -> impl Iterator<Item = (usize, usize, usize)> {
+    let end = offset as u64 + len as u64;
+    let mut at = offset as u64;
+    std::iter::from_fn(move || {
+        if at >= end {
+            return None;
+        }
+        let page = at / PAGE_SIZE as u64;
+        let start = at % PAGE_SIZE as u64;
+        let len = std::cmp::min(PAGE_SIZE as u64 - start, end - at);
+        at += len;
+        Some((page as usize, start as usize, len as usize))
+    })
+}
 
 impl RAM {
-    pub fn new() -> RAM {
-        println!("VM > Initialised RAM with size: {} bytes", bus::DRAM_SIZE);
-        RAM(vec![0; bus::DRAM_SIZE as usize])
+    pub fn new(size: u32) -> RAM {
+        println!("VM > Initialised RAM with size: {} bytes", size);
+        let pages = (size as u64).div_ceil(PAGE_SIZE as u64) as usize;
+        RAM {
+            pages: (0..pages).map(|_| None).collect(),
+            dirty: vec![false; pages],
+        }
+    }
+
+    // How many pages have been allocated
+    pub fn resident_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    // Offsets of the pages written since the last clear_dirty, a snapshot
+    // only has to save these on top of the one before it
+    pub fn dirty_pages(&self) -> Vec<u32> {
+        self.dirty
+            .iter()
+            .enumerate()
+            .filter(|(_, dirty)| **dirty)
+            .map(|(page, _)| page as u32 * PAGE_SIZE)
+            .collect()
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.iter_mut().for_each(|dirty| *dirty = false);
+    }
+
+    fn page(&self, page: usize) -> &[rv32::Byte] {
+        match &self.pages[page] {
+            Some(page) => &page[..],
+            None => &ZERO_PAGE,
+        }
     }
 
-    pub fn len(&mut self) -> usize {
-        self.0.len()
+    fn page_mut(&mut self, page: usize) -> &mut [rv32::Byte] {
+        self.dirty[page] = true;
+        &mut self.pages[page].get_or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))[..]
     }
+}
 
-    pub fn read_8(&mut self, address: rv32::XLen) -> rv32::Byte {
-        let memory = &self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        memory[address]
+impl Device for RAM {
+    fn name(&self) -> &'static str {
+        "RAM"
     }
 
-    pub fn read_16(&mut self, address: rv32::XLen) -> rv32::HalfWord {
-        let memory = &self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        let ret: rv32::HalfWord =
-            memory[address] as rv32::HalfWord | (memory[address + 1] as rv32::HalfWord) << 8;
-        ret
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Ram
     }
 
-    pub fn read_32(&mut self, address: rv32::XLen) -> rv32::Word {
-        let memory = &self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        let ret: rv32::Word = memory[address] as rv32::Word
-            | (memory[address + 1] as rv32::Word) << 8
-            | (memory[address + 2] as rv32::Word) << 16
-            | (memory[address + 3] as rv32::Word) << 24;
-        ret
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        let (page, start) = ((offset / PAGE_SIZE) as usize, offset % PAGE_SIZE);
+        if start + size <= PAGE_SIZE {
+            return Ok(device::read_le(self.page(page), start, size));
+        }
+        // a misaligned access straddling two pages
+        let mut bytes = [0; 8];
+        let mut done = 0;
+        for (page, start, len) in spans(offset, size) {
+            bytes[done..done + len].copy_from_slice(&self.page(page)[start..start + len]);
+            done += len;
+        }
+        Ok(rv32::DoubleWord::from_le_bytes(bytes))
     }
 
-    pub fn read_64(&mut self, address: rv32::XLen) -> rv32::DoubleWord {
-        let memory = &self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        let ret: rv32::DoubleWord = memory[address] as rv32::DoubleWord
-            | (memory[address + 1] as rv32::DoubleWord) << 8
-            | (memory[address + 2] as rv32::DoubleWord) << 16
-            | (memory[address + 3] as rv32::DoubleWord) << 24
-            | (memory[address + 4] as rv32::DoubleWord) << 32
-            | (memory[address + 5] as rv32::DoubleWord) << 40
-            | (memory[address + 6] as rv32::DoubleWord) << 48
-            | (memory[address + 7] as rv32::DoubleWord) << 56;
-        ret
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        let (page, start) = ((offset / PAGE_SIZE) as usize, offset % PAGE_SIZE);
+        if start + size <= PAGE_SIZE {
+            device::write_le(self.page_mut(page), start, size, value);
+            return Ok(());
+        }
+        let bytes = value.to_le_bytes();
+        let mut done = 0;
+        for (page, start, len) in spans(offset, size) {
+            self.page_mut(page)[start..start + len].copy_from_slice(&bytes[done..done + len]);
+            done += len;
+        }
+        Ok(())
     }
 
-    pub fn write_8(&mut self, address: rv32::XLen, data: rv32::Byte) {
-        let memory = &mut self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        memory[address] = data;
+    fn memory(&self, offset: u32, len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        Some(
+            spans(offset, len)
+                .map(|(page, start, len)| &self.page(page)[start..start + len])
+                .collect(),
+        )
     }
 
-    pub fn write_16(&mut self, address: rv32::XLen, data: rv32::HalfWord) {
-        let memory = &mut self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        memory[address] = (data & 0xFF) as rv32::Byte;
-        memory[address + 1] = ((data >> 8) & 0xFF) as rv32::Byte;
+    // Every page handed out is allocated and counted as dirty
+    fn memory_mut(&mut self, offset: u32, len: u32) -> Option<Vec<&mut [rv32::Byte]>> {
+        let spans: Vec<_> = spans(offset, len).collect();
+        let first = spans.first().map_or(0, |span| span.0);
+        let pages = self.pages[first..]
+            .iter_mut()
+            .zip(self.dirty[first..].iter_mut());
+        Some(
+            pages
+                .zip(spans)
+                .map(|((page, dirty), (_, start, len))| {
+                    *dirty = true;
+                    let page = page.get_or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+                    &mut page[start..start + len]
+                })
+                .collect(),
+        )
     }
 
-    pub fn write_32(&mut self, address: rv32::XLen, data: rv32::Word) {
-        let memory = &mut self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        memory[address] = (data & 0xFF) as rv32::Byte;
-        memory[address + 1] = ((data >> 8) & 0xFF) as rv32::Byte;
-        memory[address + 2] = ((data >> 16) & 0xFF) as rv32::Byte;
-        memory[address + 3] = ((data >> 24) & 0xFF) as rv32::Byte;
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 
-    pub fn write_64(&mut self, address: rv32::XLen, data: rv32::DoubleWord) {
-        let memory = &mut self.0;
-        let address = (address - bus::DRAM_BASE) as usize;
-        memory[address] = (data & 0xFF) as rv32::Byte;
-        memory[address + 1] = ((data >> 8) & 0xFF) as rv32::Byte;
-        memory[address + 2] = ((data >> 16) & 0xFF) as rv32::Byte;
-        memory[address + 3] = ((data >> 24) & 0xFF) as rv32::Byte;
-        memory[address + 4] = ((data >> 32) & 0xFF) as rv32::Byte;
-        memory[address + 5] = ((data >> 40) & 0xFF) as rv32::Byte;
-        memory[address + 6] = ((data >> 48) & 0xFF) as rv32::Byte;
-        memory[address + 7] = ((data >> 56) & 0xFF) as rv32::Byte;
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
     }
 }
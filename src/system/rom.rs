@@ -0,0 +1,108 @@
+use std::any::Any;
+
+use crate::system::device::{self, Device, DeviceKind};
+use crate::system::rv32;
+
+// Boot ROM, holds the reset vector trampoline and optionally a copy of the
+// device tree. The guest can't write to it, the contents are fixed when the
+// machine is set up. It usually sits at 0x1000, the same place as QEMU's
+// virt machine puts its mrom
+pub const ROM_ALIGN: u32 = 0x1000;
+
+// Offsets of the words the trampoline loads, right after its code
+const ROM_ENTRY: usize = 24;
+const ROM_A1: usize = 28;
+const ROM_A2: usize = 32;
+pub const ROM_DTB: usize = 40;
+
+pub struct ROM {
+    base: u32,
+    size: u32,
+    data: Vec<rv32::Byte>,
+}
+
+// Instruction encoders for the trampoline
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn u_type(imm: u32, rd: u32, opcode: u32) -> u32 {
+    imm & 0xfffff000 | rd << 7 | opcode
+}
+
+const T0: u32 = 5;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+
+// The reset vector, it jumps to entry with a0 = mhartid and a1 / a2 set to
+// whatever the payload expects, the device tree and fw_dynamic_info for
+// OpenSBI. The values are read from the ROM rather than baked into
+// instructions, the same as QEMU's reset vector
+pub fn trampoline(entry: u32, a1: u32, a2: u32) -> Vec<rv32::Byte> {
+    let code = [
+        u_type(0, T0, 0b0010111),                           // auipc t0, 0
+        i_type(0xf14, 0, 0b010, A0, 0b1110011),             // csrr  a0, mhartid
+        i_type(ROM_A1 as u32, T0, 0b010, A1, 0b0000011),    // lw    a1, 28(t0)
+        i_type(ROM_A2 as u32, T0, 0b010, A2, 0b0000011),    // lw    a2, 32(t0)
+        i_type(ROM_ENTRY as u32, T0, 0b010, T0, 0b0000011), // lw    t0, 24(t0)
+        i_type(0, T0, 0b000, 0, 0b1100111),                 // jr    t0
+    ];
+    let mut rom: Vec<rv32::Byte> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    for word in [entry, a1, a2] {
+        rom.extend_from_slice(&word.to_le_bytes());
+    }
+    rom
+}
+
+impl ROM {
+    pub fn new(base: u32, mut data: Vec<rv32::Byte>) -> ROM {
+        // round up to whole pages, the padding reads as zero
+        let size = (data.len() as u32 + ROM_ALIGN - 1) & !(ROM_ALIGN - 1);
+        data.resize(size as usize, 0);
+        println!(
+            "VM > Initialised boot ROM at 0x{:08x} with size: {} bytes",
+            base, size
+        );
+        ROM { base, size, data }
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+impl Device for ROM {
+    fn name(&self) -> &'static str {
+        "ROM"
+    }
+
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Rom
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        Ok(device::read_le(&self.data, offset, size))
+    }
+
+    fn write(&mut self, _offset: u32, _size: u32, _value: rv32::DoubleWord) -> Result<(), String> {
+        Err("write to read only memory".to_string())
+    }
+
+    fn memory(&self, offset: u32, len: u32) -> Option<Vec<&[rv32::Byte]>> {
+        let (start, end) = (offset as usize, offset as usize + len as usize);
+        Some(vec![&self.data[start..end]])
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,147 @@
+use std::any::Any;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::system::device::{self, Device};
+use crate::system::fdt::{FdtWriter, PHANDLE_PLIC};
+use crate::system::pma::{self, Pma};
+use crate::system::rv32;
+
+// Goldfish RTC, nanoseconds since 1970 and an alarm, as on QEMU's virt
+// machine so the kernel's rtc-goldfish driver works as is. Reading the low
+// half of the time latches the high half, setting the time or the alarm
+// writes the high half first and takes effect with the low.
+//
+// It starts at the host's time or at a fixed epoch, either way it then
+// moves on with the host's clock.
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const CLEAR_ALARM: u32 = 0x14;
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+pub struct RTC {
+    irq: usize,
+    time: rv32::DoubleWord,
+    time_high: rv32::Word, // latched by reading TIME_LOW
+    set_high: rv32::Word,  // written to TIME_HIGH, for the next TIME_LOW
+    alarm: rv32::DoubleWord,
+    alarm_running: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl RTC {
+    pub fn new(irq: usize) -> RTC {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as rv32::DoubleWord);
+        println!("VM > Initialised RTC");
+        RTC {
+            irq,
+            time: now,
+            time_high: 0,
+            set_high: 0,
+            alarm: 0,
+            alarm_running: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    // Seconds since 1970
+    pub fn set_epoch(&mut self, seconds: u64) {
+        println!("VM > RTC starts {} seconds after the epoch", seconds);
+        self.time = seconds.saturating_mul(1_000_000_000);
+    }
+
+    fn read_word(&mut self, offset: u32) -> rv32::Word {
+        match offset {
+            TIME_LOW => {
+                self.time_high = (self.time >> 32) as rv32::Word;
+                self.time as rv32::Word
+            }
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm as rv32::Word,
+            ALARM_HIGH => (self.alarm >> 32) as rv32::Word,
+            IRQ_ENABLED => self.irq_enabled as rv32::Word,
+            ALARM_STATUS => self.alarm_running as rv32::Word,
+            _ => 0,
+        }
+    }
+
+    fn write_word(&mut self, offset: u32, value: rv32::Word) {
+        match offset {
+            TIME_LOW => self.time = (self.set_high as u64) << 32 | value as u64,
+            TIME_HIGH => self.set_high = value,
+            // an alarm in the past goes off at the next tick
+            ALARM_LOW => {
+                self.alarm = (self.alarm & 0xffffffff_00000000) | value as u64;
+                self.alarm_running = true;
+            }
+            ALARM_HIGH => self.alarm = (self.alarm & 0x00000000_ffffffff) | (value as u64) << 32,
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.alarm_running = false,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => (),
+        }
+    }
+}
+
+impl Device for RTC {
+    fn name(&self) -> &'static str {
+        "RTC"
+    }
+
+    fn pma(&self) -> Pma {
+        Pma::io(pma::WIDTH_32)
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        device::read_words(offset, size, |o| self.read_word(o))
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        device::write_words(offset, size, value, |o, v| self.write_word(o, v))
+    }
+
+    // The time keeps going across a reset, like a battery backed clock
+    fn reset(&mut self) {
+        self.alarm_running = false;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+    }
+
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.time = self.time.wrapping_add(elapsed_micros * 1000);
+        if self.alarm_running && self.time >= self.alarm {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> Option<(usize, bool)> {
+        Some((self.irq, self.irq_enabled && self.irq_pending))
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("rtc")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_string("compatible", "google,goldfish-rtc");
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", self.irq as u32);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
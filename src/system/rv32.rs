@@ -1,3 +1,5 @@
+#![allow(dead_code)] // not every size is used on RV32
+
 pub const XLEN: usize = 32;
 pub type XLen = u32;
 
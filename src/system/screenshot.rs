@@ -0,0 +1,82 @@
+use std::path::Path;
+
+// Writing images of the framebuffer, as PNG or binary PPM going by the
+// file's extension. Both are 8 bit RGB.
+//
+// The PNG isn't compressed, the deflate stream is all stored blocks. That
+// keeps it free of dependencies and screenshots from the same frame come
+// out identical, which is what matters for diffing them.
+
+pub fn write(path: &str, width: u32, height: u32, rgb: &[u8]) -> Result<(), String> {
+    let image = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("png") => png(width, height, rgb),
+        Some("ppm") => ppm(width, height, rgb),
+        _ => return Err(format!("{} should end in .png or .ppm", path)),
+    };
+    std::fs::write(path, image).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    image.extend_from_slice(rgb);
+    image
+}
+
+pub fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    // every row starts with its filter, none
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks(width as usize * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8); // BFINAL, stored
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    // 8 bits a channel, truecolour, no interlacing
+    let mut header = width.to_be_bytes().to_vec();
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut image, b"IHDR", &header);
+    chunk(&mut image, b"IDAT", &zlib);
+    chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+fn chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend_from_slice(kind);
+    image.extend_from_slice(data);
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
@@ -0,0 +1,309 @@
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::system::console;
+
+// Host side of a serial port
+//
+// A UART hands every byte the guest sends to its backend and polls it for
+// bytes to receive. Reading is never allowed to block the VM, backends
+// that wait on the host read on a thread of its own into a channel.
+//
+//   stdio             the terminal we were started from
+//   file:OUT[,in=IN]  output to a file, input from another
+//   unix:PATH         listen on a Unix domain socket
+//   tcp:PORT          listen on localhost
+//   pty               a new pseudo-terminal, its path is printed
+//
+// Sockets take one client at a time, output while nobody is connected is
+// dropped, as it is for a PTY nothing has open.
+
+pub trait Backend {
+    // Where the port goes, for the startup message
+    fn describe(&self) -> String;
+
+    fn write(&mut self, byte: u8);
+
+    // A byte from the host if one has arrived, never waits
+    fn read(&mut self) -> Option<u8>;
+}
+
+pub fn from_spec(spec: &str) -> Result<Box<dyn Backend>, String> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "stdio" => Ok(Box::new(Stdio())),
+        "file" => Ok(Box::new(FileBackend::open(arg)?)),
+        "unix" => Ok(Box::new(Socket::unix(arg)?)),
+        "tcp" => Ok(Box::new(Socket::tcp(arg)?)),
+        "pty" => Ok(Box::new(Pty::open()?)),
+        _ => Err(format!("unknown serial backend {}", spec)),
+    }
+}
+
+// Read on a thread of its own until the reader runs dry
+fn spawn_reader<R: Read + Send + 'static>(reader: R, sender: Sender<u8>) {
+    std::thread::spawn(move || read_into(reader, &sender));
+}
+
+fn read_into<R: Read>(mut reader: R, sender: &Sender<u8>) {
+    let mut buffer = [0; 256];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                if buffer[..n].iter().any(|byte| sender.send(*byte).is_err()) {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            // a non-blocking descriptor with nothing to read
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+pub struct Stdio();
+
+impl Backend for Stdio {
+    fn describe(&self) -> String {
+        "stdio".to_string()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&[byte]).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        console::try_read()
+    }
+}
+
+pub struct FileBackend {
+    path: String,
+    output: BufWriter<File>,
+    input: Option<Receiver<u8>>,
+}
+
+impl FileBackend {
+    // OUT[,in=IN]
+    fn open(spec: &str) -> Result<FileBackend, String> {
+        let mut parts = spec.split(',');
+        let path = parts.next().unwrap_or("");
+        if path.is_empty() {
+            return Err("file backend needs an output file, file:OUT[,in=IN]".to_string());
+        }
+        let output = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut input = None;
+        for option in parts {
+            match option.split_once('=') {
+                Some(("in", file)) => {
+                    let file = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+                    let (sender, receiver) = mpsc::channel();
+                    spawn_reader(file, sender);
+                    input = Some(receiver);
+                }
+                _ => return Err(format!("unknown file backend option {}", option)),
+            }
+        }
+        Ok(FileBackend {
+            path: path.to_string(),
+            output: BufWriter::new(output),
+            input,
+        })
+    }
+}
+
+impl Backend for FileBackend {
+    fn describe(&self) -> String {
+        format!("file {}", self.path)
+    }
+
+    // whole lines at a time, so a log can be followed as it's written
+    fn write(&mut self, byte: u8) {
+        let written = self.output.write_all(&[byte]);
+        if written.is_ok() && byte == b'\n' {
+            let _ = self.output.flush();
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.as_ref()?.try_recv().ok()
+    }
+}
+
+type Client = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
+
+pub struct Socket {
+    address: String,
+    client: Client,
+    input: Receiver<u8>,
+}
+
+impl Socket {
+    fn unix(path: &str) -> Result<Socket, String> {
+        // a socket left behind by an earlier run would stop us binding,
+        // anything else at the path is left alone
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        let listener = UnixListener::bind(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Socket::listen(
+            format!("unix socket {}", path),
+            listener,
+            |listener| listener.accept().map(|(stream, _)| stream),
+            |stream| {
+                let writer = stream.try_clone()?;
+                writer.set_nonblocking(true)?;
+                Ok(writer)
+            },
+        ))
+    }
+
+    fn tcp(port: &str) -> Result<Socket, String> {
+        let address = format!("127.0.0.1:{}", port);
+        let listener = TcpListener::bind(&address).map_err(|e| format!("{}: {}", address, e))?;
+        Ok(Socket::listen(
+            format!("tcp {}", address),
+            listener,
+            |listener| listener.accept().map(|(stream, _)| stream),
+            |stream| {
+                let writer = stream.try_clone()?;
+                writer.set_nonblocking(true)?;
+                Ok(writer)
+            },
+        ))
+    }
+
+    // Clients are accepted and read from on a thread of their own, one
+    // after the other. The writer is non-blocking, which the reader shares,
+    // so a client that stops reading loses output rather than stalling us
+    fn listen<L, S>(
+        address: String,
+        listener: L,
+        accept: fn(&L) -> std::io::Result<S>,
+        clone: fn(&S) -> std::io::Result<S>,
+    ) -> Socket
+    where
+        L: Send + 'static,
+        S: Read + Write + Send + 'static,
+    {
+        let client: Client = Arc::new(Mutex::new(None));
+        let (sender, input) = mpsc::channel();
+        let connected = client.clone();
+        std::thread::spawn(move || {
+            while let Ok(stream) = accept(&listener) {
+                if let Ok(writer) = clone(&stream) {
+                    *connected.lock().unwrap() = Some(Box::new(writer));
+                }
+                read_into(stream, &sender);
+                *connected.lock().unwrap() = None;
+            }
+        });
+        Socket {
+            address,
+            client,
+            input,
+        }
+    }
+}
+
+impl Backend for Socket {
+    fn describe(&self) -> String {
+        self.address.clone()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut client = self.client.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            match stream.write(&[byte]) {
+                Ok(_) => (),
+                // the client isn't keeping up, the byte is dropped
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(_) => *client = None,
+            }
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+pub struct Pty {
+    path: String,
+    master: File,
+    _slave: File, // kept open so reading the master doesn't fail between clients
+    input: Receiver<u8>,
+}
+
+impl Pty {
+    fn open() -> Result<Pty, String> {
+        let error = |what: &str| format!("{}: {}", what, std::io::Error::last_os_error());
+        unsafe {
+            // non-blocking, output nobody is reading is dropped rather than
+            // stopping the VM once the PTY's buffer fills
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            if master < 0 {
+                return Err(error("posix_openpt"));
+            }
+            let master = File::from_raw_fd(master);
+            let fd = std::os::unix::io::AsRawFd::as_raw_fd(&master);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(error("grantpt"));
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(error("ptsname"));
+            }
+            let path = std::ffi::CStr::from_ptr(name)
+                .to_string_lossy()
+                .into_owned();
+            let slave = libc::open(name, libc::O_RDWR | libc::O_NOCTTY);
+            if slave < 0 {
+                return Err(error(&path));
+            }
+            // the guest does its own echoing and line editing
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(slave, libc::TCSANOW, &termios);
+            }
+            let (sender, input) = mpsc::channel();
+            spawn_reader(master.try_clone().map_err(|e| e.to_string())?, sender);
+            Ok(Pty {
+                path,
+                master,
+                _slave: File::from_raw_fd(slave),
+                input,
+            })
+        }
+    }
+}
+
+impl Backend for Pty {
+    fn describe(&self) -> String {
+        format!("pty {}", self.path)
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = self.master.write(&[byte]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
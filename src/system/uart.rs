@@ -1,51 +1,319 @@
-use crate::system::bus;
+use std::any::Any;
+use std::collections::VecDeque;
+
+use crate::system::device::Device;
+use crate::system::fdt::{FdtWriter, PHANDLE_PLIC};
+use crate::system::pma::{self, Pma};
 use crate::system::rv32;
+use crate::system::serial::Backend;
 
-pub const UART_TXD: u32 = bus::UART_BASE + 0x00;
-pub const UART_RXD: u32 = bus::UART_BASE + 0x05;
+// NS16550A UART
+//
+// Transmitted bytes go straight to the host backend so the transmitter is
+// always empty, received bytes come from it through a 16 byte FIFO.
+// Interrupts are raised on the PLIC line we're given for received data and
+// for the transmitter becoming empty. There are no line errors or modem
+// status changes, input waits on the host side rather than overrunning.
 
-fn didkeypress() -> bool {
-    use std::io::Read;
-    let mut stdin = std::io::stdin();
-    let mut buffer = [0; 1];
-    stdin.read_exact(&mut buffer).unwrap();
-    buffer[0] != 0
-}
+pub const UART_RBR: u32 = 0x00; // receive buffer (read)
+pub const UART_THR: u32 = 0x00; // transmit holding (write)
+pub const UART_IER: u32 = 0x01; // interrupt enable
+pub const UART_IIR: u32 = 0x02; // interrupt identification (read)
+pub const UART_FCR: u32 = 0x02; // FIFO control (write)
+pub const UART_LCR: u32 = 0x03; // line control
+pub const UART_MCR: u32 = 0x04; // modem control
+pub const UART_LSR: u32 = 0x05; // line status
+pub const UART_MSR: u32 = 0x06; // modem status
+pub const UART_SCR: u32 = 0x07; // scratch
+pub const UART_DLL: u32 = 0x00; // divisor latch low, with LCR_DLAB set
+pub const UART_DLM: u32 = 0x01; // divisor latch high, with LCR_DLAB set
 
-fn getkey() -> u8 {
-    use std::io::Read;
-    let mut stdin = std::io::stdin();
-    let mut buffer = [0; 1];
-    stdin.read_exact(&mut buffer).unwrap();
-    buffer[0]
-}
+pub const IER_RDI: u8 = 0x01; // received data available
+pub const IER_THRI: u8 = 0x02; // transmitter holding register empty
+
+pub const IIR_NO_INT: u8 = 0x01;
+pub const IIR_THRI: u8 = 0x02;
+pub const IIR_RDI: u8 = 0x04;
+pub const IIR_TIMEOUT: u8 = 0x0c; // data below the trigger level
+pub const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+pub const FCR_ENABLE_FIFO: u8 = 0x01;
+pub const FCR_CLEAR_RCVR: u8 = 0x02;
+
+pub const LCR_DLAB: u8 = 0x80;
+
+pub const MCR_DTR: u8 = 0x01;
+pub const MCR_RTS: u8 = 0x02;
+pub const MCR_OUT1: u8 = 0x04;
+pub const MCR_OUT2: u8 = 0x08;
+pub const MCR_LOOP: u8 = 0x10;
+
+pub const LSR_DR: u8 = 0x01; // data ready
+pub const LSR_THRE: u8 = 0x20;
+pub const LSR_TEMT: u8 = 0x40;
+
+pub const MSR_CTS: u8 = 0x10;
+pub const MSR_DSR: u8 = 0x20;
+pub const MSR_RI: u8 = 0x40;
+pub const MSR_DCD: u8 = 0x80;
 
+pub const FIFO_SIZE: usize = 16;
+
+// How often the host is checked for input while the guest isn't
+// looking at the UART
+const POLL_MICROS: u64 = 1000;
+
+pub struct UART {
+    irq: usize,
+    backend: Box<dyn Backend>,
+    rx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    thr_empty_pending: bool, // cleared by reading it from IIR or writing THR
+    since_poll: u64,
+}
 
-pub struct UART();
 impl UART {
-    pub fn new() -> UART {
-        println!("VM > Initialised UART");
-        UART()
+    pub fn new(irq: usize, backend: Box<dyn Backend>) -> UART {
+        println!("VM > Initialised UART on {}", backend.describe());
+        UART {
+            irq,
+            backend,
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            thr_empty_pending: false,
+            since_poll: 0,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE_FIFO != 0
+    }
+
+    // Without the FIFO there's a single holding register
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn trigger_level(&self) -> usize {
+        [1, 4, 8, 14][(self.fcr >> 6) as usize]
+    }
+
+    // Where the guest's output goes and its input comes from
+    pub fn set_backend(&mut self, backend: Box<dyn Backend>) {
+        println!("VM > UART now on {}", backend.describe());
+        self.backend = backend;
+    }
+
+    // The SBI console shares the backend with the guest's driver, and what
+    // it reads comes out of the same receive queue
+    pub fn console_write(&mut self, byte: u8) {
+        self.backend.write(byte);
+    }
+
+    pub fn console_read(&mut self) -> Option<u8> {
+        self.poll_input();
+        self.rx.pop_front()
     }
 
-    pub fn write(&mut self, address: rv32::XLen, value: rv32::Word) {
-        match address {
-            UART_TXD => {
-                print!("{}", value as u8 as char);
+    // Take what the host has sent, as much as the FIFO has room for.
+    // In loopback the receiver only hears the transmitter
+    fn poll_input(&mut self) {
+        while self.mcr & MCR_LOOP == 0 && self.rx.len() < self.rx_capacity() {
+            match self.backend.read() {
+                Some(byte) => self.rx.push_back(byte),
+                None => break,
             }
-            _ => {
-                panic!("VM > UART > Peripheral at 0x{:08x} does not exist", address);
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            if self.rx.len() < self.rx_capacity() {
+                self.rx.push_back(byte);
             }
+        } else {
+            self.backend.write(byte);
         }
+        // gone as soon as it was written
+        self.thr_empty_pending = true;
     }
 
-    pub fn read_kb(&mut self, address: rv32::XLen) -> rv32::Word {
-        if address != UART_RXD {
-            return 0x60 | didkeypress() as u8 as rv32::Word;
-        } else if address == UART_TXD && didkeypress() {
-            return getkey() as rv32::Word;
+    // Highest priority interrupt pending, received data before the
+    // transmitter
+    fn interrupt(&self) -> u8 {
+        if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            if !self.fifo_enabled() || self.rx.len() >= self.trigger_level() {
+                IIR_RDI
+            } else {
+                IIR_TIMEOUT
+            }
+        } else if self.ier & IER_THRI != 0 && self.thr_empty_pending {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
         }
-        0
+    }
+
+    fn read_register(&mut self, offset: u32) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            UART_DLL if dlab => self.divisor as u8,
+            UART_DLM if dlab => (self.divisor >> 8) as u8,
+            UART_RBR => {
+                self.poll_input();
+                self.rx.pop_front().unwrap_or(0)
+            }
+            UART_IER => self.ier,
+            UART_IIR => {
+                let interrupt = self.interrupt();
+                if interrupt == IIR_THRI {
+                    self.thr_empty_pending = false;
+                }
+                let fifo = if self.fifo_enabled() {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                interrupt | fifo
+            }
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => {
+                self.poll_input();
+                let ready = if self.rx.is_empty() { 0 } else { LSR_DR };
+                ready | LSR_THRE | LSR_TEMT
+            }
+            // the modem lines are always up, in loopback they follow MCR
+            UART_MSR if self.mcr & MCR_LOOP != 0 => [
+                (MCR_RTS, MSR_CTS),
+                (MCR_DTR, MSR_DSR),
+                (MCR_OUT1, MSR_RI),
+                (MCR_OUT2, MSR_DCD),
+            ]
+            .iter()
+            .filter(|(mcr, _)| self.mcr & mcr != 0)
+            .fold(0, |msr, (_, bit)| msr | bit),
+            UART_MSR => MSR_CTS | MSR_DSR | MSR_DCD,
+            UART_SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, value: u8) -> Result<(), String> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            UART_DLL if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            UART_DLM if dlab => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
+            UART_THR => self.transmit(value),
+            UART_IER => {
+                // enabling the interrupt while the transmitter is empty
+                // raises it straight away
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            UART_FCR => {
+                // changing the FIFO mode empties them, nothing ever waits
+                // in the transmit FIFO so only the receiver is cleared
+                if (value ^ self.fcr) & FCR_ENABLE_FIFO != 0 || value & FCR_CLEAR_RCVR != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value & 0xc1;
+            }
+            UART_LCR => self.lcr = value,
+            UART_MCR => self.mcr = value & 0x1f,
+            // the status registers are read only
+            UART_LSR | UART_MSR => (),
+            UART_SCR => self.scr = value,
+            _ => return Err(format!("UART has no register at offset 0x{:x}", offset)),
+        }
+        Ok(())
     }
 }
 
+// The registers are a byte wide and only byte accesses are decoded
+impl Device for UART {
+    fn name(&self) -> &'static str {
+        "UART"
+    }
+
+    fn pma(&self) -> Pma {
+        Pma::io(pma::WIDTH_8)
+    }
+
+    fn read(&mut self, offset: u32, _size: u32) -> Result<rv32::DoubleWord, String> {
+        if offset > UART_SCR {
+            return Err(format!("UART has no register at offset 0x{:x}", offset));
+        }
+        Ok(self.read_register(offset) as rv32::DoubleWord)
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        self.write_register(offset, value as u8)
+    }
+
+    // the host side stays connected
+    fn reset(&mut self) {
+        self.rx.clear();
+        self.ier = 0;
+        self.fcr = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.divisor = 0;
+        self.thr_empty_pending = false;
+    }
+
+    // Received data only raises an interrupt if something notices it
+    // arrived, so keep checking even when the guest isn't polling
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.since_poll += elapsed_micros;
+        if self.since_poll >= POLL_MICROS {
+            self.since_poll = 0;
+            self.poll_input();
+        }
+    }
+
+    fn irq(&self) -> Option<(usize, bool)> {
+        Some((self.irq, self.interrupt() != IIR_NO_INT))
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("serial")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", self.irq as u32);
+        fdt.property_u32("clock-frequency", 0x384000);
+    }
+
+    fn is_console(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,749 @@
+use std::any::Any;
+
+use crate::system::bus::GuestMemory;
+use crate::system::device::{self, Device};
+use crate::system::fdt::{FdtWriter, PHANDLE_PLIC};
+use crate::system::rv32;
+
+// virtio over MMIO, version 2 (virtio 1.x, no legacy interface)
+//
+// The transport has the registers every virtio device shares: feature
+// negotiation, status and the split virtqueues. The device behind it only
+// sees the chains of buffers the driver makes available. A driver writing
+// QueueNotify has the queue serviced before its next instruction, devices
+// with input from the host say which queues want servicing. Used buffers
+// and configuration changes interrupt through the PLIC line we're given.
+
+pub const MAGIC: u32 = 0x74726976; // "virt"
+pub const VERSION: u32 = 2;
+pub const VENDOR_ID: u32 = 0x43534952; // "RISC"
+
+pub const MAGIC_VALUE: u32 = 0x000;
+pub const VERSION_REG: u32 = 0x004;
+pub const DEVICE_ID: u32 = 0x008;
+pub const VENDOR_ID_REG: u32 = 0x00c;
+pub const DEVICE_FEATURES: u32 = 0x010;
+pub const DEVICE_FEATURES_SEL: u32 = 0x014;
+pub const DRIVER_FEATURES: u32 = 0x020;
+pub const DRIVER_FEATURES_SEL: u32 = 0x024;
+pub const QUEUE_SEL: u32 = 0x030;
+pub const QUEUE_NUM_MAX: u32 = 0x034;
+pub const QUEUE_NUM: u32 = 0x038;
+pub const QUEUE_READY: u32 = 0x044;
+pub const QUEUE_NOTIFY: u32 = 0x050;
+pub const INTERRUPT_STATUS: u32 = 0x060;
+pub const INTERRUPT_ACK: u32 = 0x064;
+pub const STATUS: u32 = 0x070;
+pub const QUEUE_DESC_LOW: u32 = 0x080; // each address is followed by its high half
+pub const QUEUE_DRIVER_LOW: u32 = 0x090;
+pub const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+pub const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+pub const CONFIG_GENERATION: u32 = 0x0fc;
+pub const CONFIG: u32 = 0x100;
+
+pub const STATUS_DRIVER_OK: u32 = 0x04;
+pub const STATUS_FEATURES_OK: u32 = 0x08;
+pub const STATUS_NEEDS_RESET: u32 = 0x40;
+
+pub const INT_USED_BUFFER: u32 = 0x1;
+pub const INT_CONFIG_CHANGE: u32 = 0x2;
+
+pub const F_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const F_VERSION_1: u64 = 1 << 32;
+
+pub const DESC_F_NEXT: u16 = 0x1;
+pub const DESC_F_WRITE: u16 = 0x2;
+pub const DESC_F_INDIRECT: u16 = 0x4;
+
+pub const QUEUE_SIZE: u16 = 256;
+
+// Longest chain we'll follow, anything longer is a loop
+const MAX_CHAIN: usize = 4096;
+
+// Most a chain can hold each way unless the device says otherwise
+pub const MAX_REQUEST: u32 = 1024 * 1024;
+
+// What a device does with the buffers it's given
+pub trait VirtioDevice {
+    fn name(&self) -> &'static str;
+    fn device_id(&self) -> u32;
+
+    // Device specific feature bits, the transport adds its own
+    fn features(&self) -> u64 {
+        0
+    }
+
+    // The features the driver settled on
+    fn set_features(&mut self, _features: u64) {}
+
+    fn queue_count(&self) -> usize;
+
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE
+    }
+
+    // The most bytes a chain can give the device to read, or to write,
+    // a chain with more is the driver's mistake
+    fn max_request(&self) -> u32 {
+        MAX_REQUEST
+    }
+
+    // The device specific configuration space
+    fn config(&self) -> Vec<rv32::Byte> {
+        Vec::new()
+    }
+
+    fn write_config(&mut self, _offset: u32, _data: &[rv32::Byte]) {}
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self, _elapsed_micros: u64) {}
+
+    // Queues with work for the device without the driver notifying it, as
+    // a mask, input from the host waiting for a buffer for instance
+    fn pending(&self) -> u32 {
+        0
+    }
+
+    // Service a queue, returns whether any buffers were used
+    fn process(
+        &mut self,
+        queue: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String>;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+// A split virtqueue, the descriptor table, the ring the driver makes
+// buffers available on and the ring we give them back on
+#[derive(Default)]
+pub struct Queue {
+    max_size: u16,
+    max_request: u32,
+    size: u16,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Queue {
+    fn new(max_size: u16, max_request: u32) -> Queue {
+        Queue {
+            max_size,
+            max_request,
+            size: max_size,
+            ..Default::default()
+        }
+    }
+
+    // The next chain the driver has made available
+    pub fn pop(&mut self, memory: &mut GuestMemory) -> Result<Option<Chain>, String> {
+        if !self.ready {
+            return Ok(None);
+        }
+        let avail = memory.read_u16(self.driver + 2)?;
+        if avail == self.next_avail {
+            return Ok(None);
+        }
+        let slot = (self.next_avail % self.size) as u64;
+        let head = memory.read_u16(self.driver + 4 + 2 * slot)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        Chain::walk(memory, self.desc, self.size as u32, head, self.max_request).map(Some)
+    }
+
+    // Hand a chain back with how many bytes were written to it
+    pub fn push(
+        &mut self,
+        memory: &mut GuestMemory,
+        chain: &Chain,
+        written: u32,
+    ) -> Result<(), String> {
+        let slot = (self.next_used % self.size) as u64;
+        let element = self.device + 4 + 8 * slot;
+        memory.write_u32(element, chain.head as u32)?;
+        memory.write_u32(element + 4, written)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        memory.write_u16(self.device + 2, self.next_used)
+    }
+}
+
+// The buffers making up one request, the driver puts everything the
+// device reads before everything it writes. Both are treated as one
+// stream of bytes however the driver split them up
+pub struct Chain {
+    head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+    readable_len: u32,
+    writable_len: u32,
+}
+
+impl Chain {
+    fn walk(
+        memory: &mut GuestMemory,
+        desc: u64,
+        size: u32,
+        head: u16,
+        max_request: u32,
+    ) -> Result<Chain, String> {
+        let mut chain = Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+            readable_len: 0,
+            writable_len: 0,
+        };
+        let (mut table, mut size, mut index) = (desc, size, head);
+        let mut indirect = false;
+        for _ in 0..MAX_CHAIN {
+            if index as u32 >= size {
+                return Err(format!("descriptor {} is past the end of the table", index));
+            }
+            // the whole descriptor, so reading its fields can't wrap
+            let entry = table
+                .checked_add(16 * (index as u64 + 1))
+                .ok_or(format!("descriptor table at 0x{:x} wraps around", table))?
+                - 16;
+            let address = memory.read_u64(entry)?;
+            let len = memory.read_u32(entry + 8)?;
+            let flags = memory.read_u16(entry + 12)?;
+            let next = memory.read_u16(entry + 14)?;
+            if flags & DESC_F_INDIRECT != 0 {
+                if indirect {
+                    return Err("indirect descriptor in an indirect table".to_string());
+                }
+                indirect = true;
+                (table, size, index) = (address, len / 16, 0);
+                continue;
+            }
+            let (buffers, total) = if flags & DESC_F_WRITE != 0 {
+                (&mut chain.writable, &mut chain.writable_len)
+            } else if chain.writable.is_empty() {
+                (&mut chain.readable, &mut chain.readable_len)
+            } else {
+                return Err("readable descriptor after a writable one".to_string());
+            };
+            *total = total
+                .checked_add(len)
+                .filter(|total| *total <= max_request)
+                .ok_or(format!(
+                    "chain from {} is over the {} bytes a request can be",
+                    head, max_request
+                ))?;
+            address
+                .checked_add(len as u64)
+                .ok_or(format!("buffer at 0x{:x} wraps around", address))?;
+            buffers.push((address, len));
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = next;
+        }
+        Err(format!("descriptor chain from {} doesn't end", head))
+    }
+
+    pub fn readable_len(&self) -> u32 {
+        self.readable_len
+    }
+
+    pub fn writable_len(&self) -> u32 {
+        self.writable_len
+    }
+
+    // Everything the driver gave us to read
+    pub fn read_all(&self, memory: &mut GuestMemory) -> Result<Vec<rv32::Byte>, String> {
+        let mut data = vec![0; self.readable_len() as usize];
+        let mut done = 0;
+        for (address, len) in self.readable.iter() {
+            memory.read(*address, &mut data[done..done + *len as usize])?;
+            done += *len as usize;
+        }
+        Ok(data)
+    }
+
+    // Write into the writable buffers from offset bytes in, returns how
+    // much fitted
+    pub fn write_at(
+        &self,
+        memory: &mut GuestMemory,
+        offset: u32,
+        data: &[rv32::Byte],
+    ) -> Result<u32, String> {
+        let (mut skip, mut done) = (offset as usize, 0);
+        for (address, len) in self.writable.iter() {
+            let len = *len as usize;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let run = std::cmp::min(len - skip, data.len() - done);
+            memory.write(address + skip as u64, &data[done..done + run])?;
+            done += run;
+            skip = 0;
+            if done == data.len() {
+                break;
+            }
+        }
+        Ok(done as u32)
+    }
+}
+
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    irq: usize,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    notified: u32, // queues the driver has notified since the last dma
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+fn new_queues(device: &dyn VirtioDevice) -> Vec<Queue> {
+    (0..device.queue_count())
+        .map(|_| Queue::new(device.queue_size(), device.max_request()))
+        .collect()
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>, irq: usize) -> VirtioMmio {
+        println!("VM > Initialised {} on irq {}", device.name(), irq);
+        let queues = new_queues(device.as_ref());
+        VirtioMmio {
+            device,
+            irq,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            notified: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    pub fn device_mut(&mut self) -> &mut dyn VirtioDevice {
+        self.device.as_mut()
+    }
+
+    // Let the driver know the device changed its configuration
+    pub fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.interrupt_status |= INT_CONFIG_CHANGE;
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | F_VERSION_1 | F_RING_INDIRECT_DESC
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    // Replace the low or high half of a 64 bit value
+    fn set_half(value: &mut u64, high: bool, half: rv32::Word) {
+        *value = match high {
+            true => (*value & 0xffffffff) | (half as u64) << 32,
+            false => (*value & !0xffffffff) | half as u64,
+        };
+    }
+
+    fn read_register(&self, offset: u32) -> rv32::Word {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |q| q.max_size as u32),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, value: rv32::Word) -> Result<(), String> {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                // there are only 64 feature bits, other words are ignored
+                if self.driver_features_sel <= 1 {
+                    let high = self.driver_features_sel == 1;
+                    VirtioMmio::set_half(&mut self.driver_features, high, value);
+                }
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => {
+                if let Some(queue) = self.queue() {
+                    if !value.is_power_of_two() || value > queue.max_size as u32 {
+                        return Err(format!("queue size {} isn't supported", value));
+                    }
+                    queue.size = value as u16;
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => {
+                if (value as usize) < self.queues.len() {
+                    self.notified |= 1 << value;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => self.write_status(value),
+            QUEUE_DESC_LOW..=QUEUE_DEVICE_HIGH => {
+                let high = offset & 4 != 0;
+                if let Some(queue) = self.queue() {
+                    match offset & !4 {
+                        QUEUE_DESC_LOW => VirtioMmio::set_half(&mut queue.desc, high, value),
+                        QUEUE_DRIVER_LOW => VirtioMmio::set_half(&mut queue.driver, high, value),
+                        QUEUE_DEVICE_LOW => VirtioMmio::set_half(&mut queue.device, high, value),
+                        _ => {
+                            return Err(format!("virtio has no register at offset 0x{:x}", offset))
+                        }
+                    }
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "virtio register at offset 0x{:x} is read only",
+                    offset
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn write_status(&mut self, value: rv32::Word) {
+        if value == 0 {
+            self.reset();
+            return;
+        }
+        // features the driver wants that we didn't offer are refused by
+        // leaving FEATURES_OK clear
+        let newly = value & !self.status;
+        if newly & STATUS_FEATURES_OK != 0 {
+            if self.driver_features & !self.features() != 0 {
+                self.status = value & !STATUS_FEATURES_OK;
+                return;
+            }
+            self.device.set_features(self.driver_features);
+        }
+        self.status = value;
+    }
+
+    fn read_config(&self, offset: u32, size: u32) -> rv32::DoubleWord {
+        let config = self.device.config();
+        (0..size as usize)
+            .map(|i| *config.get(offset as usize + i).unwrap_or(&0))
+            .rev()
+            .fold(0, |value, byte| value << 8 | byte as rv32::DoubleWord)
+    }
+
+    // Something went wrong with the driver's buffers, it has to reset us
+    fn needs_reset(&mut self, error: String) {
+        println!("VM > {} > {}, needs reset", self.device.name(), error);
+        self.status |= STATUS_NEEDS_RESET;
+        self.interrupt_status |= INT_CONFIG_CHANGE;
+    }
+}
+
+impl Device for VirtioMmio {
+    fn name(&self) -> &'static str {
+        self.device.name()
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> Result<rv32::DoubleWord, String> {
+        if offset >= CONFIG {
+            return Ok(self.read_config(offset - CONFIG, size));
+        }
+        device::read_words(offset, size, |offset| self.read_register(offset))
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: rv32::DoubleWord) -> Result<(), String> {
+        if offset >= CONFIG {
+            let bytes = value.to_le_bytes();
+            self.device
+                .write_config(offset - CONFIG, &bytes[..size as usize]);
+            return Ok(());
+        }
+        // registers are 32 bits, only the configuration space is narrower
+        if size != 4 {
+            return Err(format!("{} bit write to a virtio register", size * 8));
+        }
+        self.write_register(offset, value as rv32::Word)
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.device.reset();
+        // after the device, it may have been given more queues since
+        self.queues = new_queues(self.device.as_ref());
+    }
+
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.device.tick(elapsed_micros);
+    }
+
+    fn dma_pending(&self) -> bool {
+        let running = self.status & (STATUS_DRIVER_OK | STATUS_NEEDS_RESET) == STATUS_DRIVER_OK;
+        running && (self.notified | self.device.pending()) != 0
+    }
+
+    fn dma(&mut self, memory: &mut GuestMemory) {
+        let queues = self.notified | self.device.pending();
+        self.notified = 0;
+        for index in (0..self.queues.len()).filter(|i| queues & (1 << i) != 0) {
+            match self.device.process(index, &mut self.queues[index], memory) {
+                Ok(true) => self.interrupt_status |= INT_USED_BUFFER,
+                Ok(false) => (),
+                Err(e) => return self.needs_reset(e),
+            }
+        }
+    }
+
+    fn irq(&self) -> Option<(usize, bool)> {
+        Some((self.irq, self.interrupt_status != 0))
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("virtio_mmio")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter, base: u32, size: u32) {
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &[0, base, 0, size]);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", self.irq as u32);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::bus::Bus;
+
+    const TABLE: u64 = 0x80001000;
+    const INDIRECT_TABLE: u64 = 0x80002000;
+    const BUFFER: u64 = 0x80003000;
+    const SIZE: u32 = 8;
+
+    fn bus() -> Bus {
+        Bus::new(0x80000000, 0x10000).unwrap()
+    }
+
+    fn descriptor(
+        memory: &mut GuestMemory,
+        table: u64,
+        index: u16,
+        (address, len, flags, next): (u64, u32, u16, u16),
+    ) {
+        let entry = table + 16 * index as u64;
+        memory.write(entry, &address.to_le_bytes()).unwrap();
+        memory.write_u32(entry + 8, len).unwrap();
+        memory.write_u16(entry + 12, flags).unwrap();
+        memory.write_u16(entry + 14, next).unwrap();
+    }
+
+    fn walk(memory: &mut GuestMemory, max_request: u32) -> Result<Chain, String> {
+        Chain::walk(memory, TABLE, SIZE, 0, max_request)
+    }
+
+    #[test]
+    fn readable_then_writable() {
+        let mut bus = bus();
+        let mut memory = bus.guest_memory();
+        descriptor(&mut memory, TABLE, 0, (BUFFER, 16, DESC_F_NEXT, 3));
+        descriptor(&mut memory, TABLE, 3, (BUFFER + 16, 32, DESC_F_WRITE, 0));
+        let chain = walk(&mut memory, MAX_REQUEST).unwrap();
+        assert_eq!(chain.readable_len(), 16);
+        assert_eq!(chain.writable_len(), 32);
+        assert_eq!(chain.writable, [(BUFFER + 16, 32)]);
+
+        descriptor(
+            &mut memory,
+            TABLE,
+            0,
+            (BUFFER, 16, DESC_F_WRITE | DESC_F_NEXT, 1),
+        );
+        descriptor(&mut memory, TABLE, 1, (BUFFER + 16, 16, 0, 0));
+        assert!(walk(&mut memory, MAX_REQUEST).is_err());
+    }
+
+    #[test]
+    fn follows_indirect_tables() {
+        let mut bus = bus();
+        let mut memory = bus.guest_memory();
+        descriptor(
+            &mut memory,
+            TABLE,
+            0,
+            (INDIRECT_TABLE, 2 * 16, DESC_F_INDIRECT, 0),
+        );
+        descriptor(&mut memory, INDIRECT_TABLE, 0, (BUFFER, 16, DESC_F_NEXT, 1));
+        descriptor(
+            &mut memory,
+            INDIRECT_TABLE,
+            1,
+            (BUFFER + 16, 8, DESC_F_WRITE, 0),
+        );
+        let chain = walk(&mut memory, MAX_REQUEST).unwrap();
+        assert_eq!(chain.readable, [(BUFFER, 16)]);
+        assert_eq!(chain.writable, [(BUFFER + 16, 8)]);
+
+        // an indirect table only holds as many descriptors as its length says
+        descriptor(
+            &mut memory,
+            INDIRECT_TABLE,
+            1,
+            (BUFFER + 16, 8, DESC_F_NEXT, 2),
+        );
+        assert!(walk(&mut memory, MAX_REQUEST).is_err());
+
+        // and can't point at another one
+        descriptor(
+            &mut memory,
+            INDIRECT_TABLE,
+            1,
+            (INDIRECT_TABLE, 16, DESC_F_INDIRECT, 0),
+        );
+        assert!(walk(&mut memory, MAX_REQUEST).is_err());
+    }
+
+    #[test]
+    fn loops_are_errors() {
+        let mut bus = bus();
+        let mut memory = bus.guest_memory();
+        descriptor(&mut memory, TABLE, 0, (BUFFER, 16, DESC_F_NEXT, 1));
+        descriptor(&mut memory, TABLE, 1, (BUFFER, 16, DESC_F_NEXT, 0));
+        let e = walk(&mut memory, u32::MAX).err().unwrap();
+        assert!(e.contains("doesn't end"), "{}", e);
+        // a small enough cap catches the loop first
+        assert!(walk(&mut memory, 1024).is_err());
+    }
+
+    #[test]
+    fn descriptors_past_the_table_are_errors() {
+        let mut bus = bus();
+        let mut memory = bus.guest_memory();
+        descriptor(
+            &mut memory,
+            TABLE,
+            0,
+            (BUFFER, 16, DESC_F_NEXT, SIZE as u16),
+        );
+        let e = walk(&mut memory, MAX_REQUEST).err().unwrap();
+        assert!(e.contains("past the end of the table"), "{}", e);
+        assert!(Chain::walk(&mut memory, TABLE, SIZE, SIZE as u16, MAX_REQUEST).is_err());
+    }
+
+    #[test]
+    fn requests_are_capped() {
+        let mut bus = bus();
+        let mut memory = bus.guest_memory();
+        descriptor(&mut memory, TABLE, 0, (BUFFER, 0x800, DESC_F_NEXT, 1));
+        descriptor(&mut memory, TABLE, 1, (BUFFER, 0x800, 0, 0));
+        assert_eq!(walk(&mut memory, 0x1000).unwrap().readable_len(), 0x1000);
+        assert!(walk(&mut memory, 0xfff).is_err());
+
+        // lengths that would overflow a u32 don't wrap under the cap
+        descriptor(&mut memory, TABLE, 0, (BUFFER, u32::MAX, DESC_F_NEXT, 1));
+        assert!(walk(&mut memory, u32::MAX).is_err());
+    }
+
+    struct Null;
+
+    impl VirtioDevice for Null {
+        fn name(&self) -> &'static str {
+            "null"
+        }
+
+        fn device_id(&self) -> u32 {
+            0
+        }
+
+        fn queue_count(&self) -> usize {
+            1
+        }
+
+        fn process(
+            &mut self,
+            _: usize,
+            _: &mut Queue,
+            _: &mut GuestMemory,
+        ) -> Result<bool, String> {
+            Ok(false)
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn feature_words_and_queue_sizes() {
+        let mut mmio = VirtioMmio::new(Box::new(Null), 1);
+        let features = F_VERSION_1 | F_RING_INDIRECT_DESC;
+        for (sel, word) in [
+            (0, features as u32),
+            (1, (features >> 32) as u32),
+            (2, 0),
+            (3, 0),
+        ] {
+            mmio.write_register(DEVICE_FEATURES_SEL, sel).unwrap();
+            assert_eq!(mmio.read_register(DEVICE_FEATURES), word, "word {}", sel);
+        }
+        mmio.write_register(DRIVER_FEATURES_SEL, 3).unwrap();
+        mmio.write_register(DRIVER_FEATURES, !0).unwrap();
+        assert_eq!(mmio.driver_features, 0);
+        mmio.write_register(DRIVER_FEATURES_SEL, 1).unwrap();
+        mmio.write_register(DRIVER_FEATURES, 1).unwrap();
+        assert_eq!(mmio.driver_features, 1 << 32);
+
+        for size in [0, 3, 100, 2 * QUEUE_SIZE as u32] {
+            assert!(
+                mmio.write_register(QUEUE_NUM, size).is_err(),
+                "size {}",
+                size
+            );
+        }
+        mmio.write_register(QUEUE_NUM, 64).unwrap();
+        assert_eq!(mmio.queues[0].size, 64);
+    }
+}
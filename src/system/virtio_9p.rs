@@ -0,0 +1,102 @@
+use std::any::Any;
+
+use crate::system::bus::GuestMemory;
+use crate::system::p9::{self, Server};
+use crate::system::rv32;
+use crate::system::virtio::{Queue, VirtioDevice};
+
+// virtio-9p, a host directory the guest mounts by its tag with
+//
+//   mount -t 9p -o trans=virtio,version=9p2000.L TAG /mnt
+//
+// Each request is one chain, the T-message to read and room for the
+// R-message after it.
+
+pub const DEVICE_ID: u32 = 9;
+
+pub const F_MOUNT_TAG: u64 = 1 << 0;
+
+pub const DEFAULT_TAG: &str = "hostshare";
+
+pub struct Share {
+    tag: String,
+    server: Server,
+}
+
+impl Share {
+    // DIR[,tag=TAG][,ro]
+    pub fn from_spec(spec: &str) -> Result<Share, String> {
+        let mut parts = spec.split(',');
+        let root = parts.next().unwrap_or("");
+        let (mut tag, mut read_only) = (DEFAULT_TAG.to_string(), false);
+        for option in parts {
+            match option.split_once('=') {
+                Some(("tag", value)) => tag = value.to_string(),
+                None if option == "ro" => read_only = true,
+                _ => return Err(format!("unknown share option {}", option)),
+            }
+        }
+        if tag.is_empty() || tag.len() > u16::MAX as usize {
+            return Err(format!("{} can't be a mount tag", tag));
+        }
+        let server = Server::new(root, read_only)?;
+        println!(
+            "VM > Sharing {} as {}{}",
+            server.root().display(),
+            tag,
+            if read_only { ", read only" } else { "" }
+        );
+        Ok(Share { tag, server })
+    }
+}
+
+impl VirtioDevice for Share {
+    fn name(&self) -> &'static str {
+        "virtio-9p"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        F_MOUNT_TAG
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    // Neither a request nor its reply can be bigger than msize
+    fn max_request(&self) -> u32 {
+        p9::MAX_MSIZE
+    }
+
+    // tag_len and the tag, without a terminator
+    fn config(&self) -> Vec<rv32::Byte> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn process(
+        &mut self,
+        _queue: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let request = chain.read_all(memory)?;
+            let reply = self.server.handle(&request, chain.writable_len() as usize);
+            let written = chain.write_at(memory, 0, &reply)?;
+            vq.push(memory, &chain, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
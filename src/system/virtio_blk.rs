@@ -0,0 +1,249 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+use crate::system::bus::GuestMemory;
+use crate::system::rv32;
+use crate::system::virtio::{Chain, Queue, VirtioDevice};
+
+// virtio-blk backed by a raw image on the host
+//
+// The image can be read only, or sit under a copy-on-write overlay that
+// keeps every sector written in memory so the image itself is never
+// touched and the writes are gone when the VM exits.
+
+pub const DEVICE_ID: u32 = 2;
+
+pub const F_SIZE_MAX: u64 = 1 << 1;
+pub const F_SEG_MAX: u64 = 1 << 2;
+pub const F_RO: u64 = 1 << 5;
+pub const F_BLK_SIZE: u64 = 1 << 6;
+pub const F_FLUSH: u64 = 1 << 9;
+
+pub const T_IN: u32 = 0;
+pub const T_OUT: u32 = 1;
+pub const T_FLUSH: u32 = 4;
+pub const T_GET_ID: u32 = 8;
+
+pub const S_OK: u8 = 0;
+pub const S_IOERR: u8 = 1;
+pub const S_UNSUPP: u8 = 2;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const ID_BYTES: usize = 20;
+
+const HEADER_SIZE: usize = 16; // type, reserved and sector
+
+// Largest buffer in a request, which with seg_max of them bounds how big
+// a request gets
+const SEGMENT_SIZE: u32 = 64 * 1024;
+
+pub struct Block {
+    path: String,
+    file: File,
+    sectors: u64,
+    read_only: bool,
+    overlay: Option<HashMap<u64, Box<[rv32::Byte]>>>, // sectors written, when copy-on-write
+}
+
+impl Block {
+    // FILE[,ro][,cow]
+    pub fn from_spec(spec: &str) -> Result<Block, String> {
+        let mut parts = spec.split(',');
+        let path = parts.next().unwrap_or("");
+        let (mut read_only, mut cow) = (false, false);
+        for option in parts {
+            match option {
+                "ro" => read_only = true,
+                "cow" => cow = true,
+                _ => return Err(format!("unknown drive option {}", option)),
+            }
+        }
+        // the image is only opened for writing if it's written to
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only && !cow)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let size = file
+            .metadata()
+            .map_err(|e| format!("{}: {}", path, e))?
+            .len();
+        let sectors = size / SECTOR_SIZE as u64;
+        println!(
+            "VM > Opened disk image {}, {} sectors{}",
+            path,
+            sectors,
+            match (read_only, cow) {
+                (true, _) => ", read only",
+                (false, true) => ", copy-on-write",
+                _ => "",
+            }
+        );
+        Ok(Block {
+            path: path.to_string(),
+            file,
+            sectors,
+            read_only,
+            overlay: if cow { Some(HashMap::new()) } else { None },
+        })
+    }
+
+    // Sectors being accessed, as long as they're all on the disk
+    fn extent(&self, sector: u64, len: usize) -> Result<std::ops::Range<u64>, String> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(format!("{} bytes isn't a whole number of sectors", len));
+        }
+        let end = sector.saturating_add((len / SECTOR_SIZE) as u64);
+        if end > self.sectors {
+            return Err(format!("sector {} is past the end of the disk", end - 1));
+        }
+        Ok(sector..end)
+    }
+
+    fn read_sectors(&self, sector: u64, data: &mut [rv32::Byte]) -> Result<(), String> {
+        let extent = self.extent(sector, data.len())?;
+        for (sector, buffer) in extent.zip(data.chunks_mut(SECTOR_SIZE)) {
+            match self.overlay.as_ref().and_then(|o| o.get(&sector)) {
+                Some(written) => buffer.copy_from_slice(written),
+                None => self
+                    .file
+                    .read_exact_at(buffer, sector * SECTOR_SIZE as u64)
+                    .map_err(|e| format!("{}: {}", self.path, e))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[rv32::Byte]) -> Result<(), String> {
+        let extent = self.extent(sector, data.len())?;
+        match self.overlay.as_mut() {
+            Some(overlay) => {
+                for (sector, buffer) in extent.zip(data.chunks(SECTOR_SIZE)) {
+                    overlay.insert(sector, buffer.into());
+                }
+                Ok(())
+            }
+            None => self
+                .file
+                .write_all_at(data, sector * SECTOR_SIZE as u64)
+                .map_err(|e| format!("{}: {}", self.path, e)),
+        }
+    }
+
+    // Leaving room in the queue for the header and the status
+    fn seg_max(&self) -> u32 {
+        self.queue_size() as u32 - 2
+    }
+
+    // Serial number, the image's file name as far as it fits
+    fn id(&self) -> Vec<rv32::Byte> {
+        let name = std::path::Path::new(&self.path)
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let mut id = name.into_bytes();
+        id.resize(ID_BYTES, 0);
+        id
+    }
+
+    // Carry out a request, the status byte goes last in what the driver
+    // gave us to write to. Returns the number of bytes written
+    fn request(&mut self, chain: &Chain, memory: &mut GuestMemory) -> Result<u32, String> {
+        let request = chain.read_all(memory)?;
+        let writable = chain.writable_len();
+        if request.len() < HEADER_SIZE || writable == 0 {
+            return Err("malformed block request".to_string());
+        }
+        let kind = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+        let data_len = writable - 1;
+        let (status, written) = match kind {
+            T_IN => {
+                let mut data = vec![0; data_len as usize];
+                match self.read_sectors(sector, &mut data) {
+                    Ok(()) => (S_OK, chain.write_at(memory, 0, &data)?),
+                    Err(e) => (self.io_error(e), 0),
+                }
+            }
+            T_OUT if self.read_only => (S_IOERR, 0),
+            T_OUT => match self.write_sectors(sector, &request[HEADER_SIZE..]) {
+                Ok(()) => (S_OK, 0),
+                Err(e) => (self.io_error(e), 0),
+            },
+            // nothing is held back, just get the host to write it out
+            T_FLUSH if self.overlay.is_some() || self.read_only => (S_OK, 0),
+            T_FLUSH => match self.file.sync_data() {
+                Ok(()) => (S_OK, 0),
+                Err(e) => (self.io_error(e.to_string()), 0),
+            },
+            T_GET_ID => {
+                let id = self.id();
+                let len = std::cmp::min(ID_BYTES, data_len as usize);
+                (S_OK, chain.write_at(memory, 0, &id[..len])?)
+            }
+            _ => (S_UNSUPP, 0),
+        };
+        chain.write_at(memory, data_len, &[status])?;
+        Ok(written + 1)
+    }
+
+    fn io_error(&self, error: String) -> u8 {
+        println!("VM > virtio-blk > {}", error);
+        S_IOERR
+    }
+}
+
+impl VirtioDevice for Block {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        let read_only = if self.read_only { F_RO } else { 0 };
+        F_SIZE_MAX | F_SEG_MAX | F_BLK_SIZE | F_FLUSH | read_only
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    // The segments, the header and the status byte
+    fn max_request(&self) -> u32 {
+        self.seg_max() * SEGMENT_SIZE + HEADER_SIZE as u32 + 1
+    }
+
+    // capacity, size_max, seg_max, geometry and blk_size
+    fn config(&self) -> Vec<rv32::Byte> {
+        let mut config = Vec::with_capacity(24);
+        config.extend_from_slice(&self.sectors.to_le_bytes());
+        config.extend_from_slice(&SEGMENT_SIZE.to_le_bytes());
+        config.extend_from_slice(&self.seg_max().to_le_bytes());
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config.extend_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config
+    }
+
+    fn process(
+        &mut self,
+        _queue: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let written = self.request(&chain, memory)?;
+            vq.push(memory, &chain, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,337 @@
+use std::any::Any;
+use std::collections::VecDeque;
+
+use crate::system::bus::GuestMemory;
+use crate::system::rv32;
+use crate::system::serial::{self, Backend};
+use crate::system::virtio::{Queue, VirtioDevice};
+
+// virtio-console with multiport, every port on one of the serial backends
+//
+// Ports without a name are consoles, hvc0 onwards, named ports turn up
+// in the guest as /dev/virtio-ports/NAME. The queues go receive and
+// transmit for port 0, receive and transmit for the control messages,
+// then a receive and transmit pair for each port after the first.
+
+pub const DEVICE_ID: u32 = 3;
+
+pub const F_MULTIPORT: u64 = 1 << 1;
+
+// Control messages, id, event and value
+pub const DEVICE_READY: u16 = 0;
+pub const PORT_ADD: u16 = 1;
+pub const PORT_READY: u16 = 3;
+pub const CONSOLE_PORT: u16 = 4;
+pub const PORT_OPEN: u16 = 6;
+pub const PORT_NAME: u16 = 7;
+
+const CONTROL_SIZE: usize = 8;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+// Two queues a port and two for control messages, out of the 32 the
+// transport keeps track of
+pub const MAX_PORTS: usize = 15;
+
+// How often the backends are checked for input
+const POLL_MICROS: u64 = 1000;
+
+// Bytes held for a port the guest isn't reading, the rest wait in the
+// backend
+const MAX_BACKLOG: usize = 4096;
+
+struct Port {
+    backend: Box<dyn Backend>,
+    name: Option<String>,
+    open: bool, // by the guest
+    rx: VecDeque<rv32::Byte>,
+}
+
+impl Port {
+    fn console(&self) -> bool {
+        self.name.is_none()
+    }
+}
+
+pub struct Console {
+    ports: Vec<Port>,
+    multiport: bool,
+    control: VecDeque<Vec<rv32::Byte>>, // waiting for the driver
+    since_poll: u64,
+}
+
+impl Console {
+    pub fn from_spec(spec: &str) -> Result<Console, String> {
+        let mut console = Console {
+            ports: Vec::new(),
+            multiport: false,
+            control: VecDeque::new(),
+            since_poll: 0,
+        };
+        console.add_port(spec)?;
+        Ok(console)
+    }
+
+    // BACKEND[,name=NAME], the transport has to be reset to pick up the
+    // queues for it
+    pub fn add_port(&mut self, spec: &str) -> Result<(), String> {
+        if self.ports.len() == MAX_PORTS {
+            return Err(format!("a console has at most {} ports", MAX_PORTS));
+        }
+        let (backend, name) = match spec.rsplit_once(",name=") {
+            Some((backend, name)) => (backend, Some(name.to_string())),
+            None => (spec, None),
+        };
+        if let Some(name) = name.as_ref() {
+            if name.is_empty() || name.contains('/') {
+                return Err(format!("{} can't be a port name", name));
+            }
+        }
+        let backend = serial::from_spec(backend)?;
+        println!(
+            "VM > Console port {} on {}{}",
+            self.ports.len(),
+            backend.describe(),
+            name.as_ref()
+                .map_or(String::new(), |name| format!(" as {}", name))
+        );
+        self.ports.push(Port {
+            backend,
+            name,
+            open: false,
+            rx: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    // Port 0 comes first, the control queues are in the way of the rest
+    fn receive_queue(port: usize) -> usize {
+        match port {
+            0 => 0,
+            _ => 2 + 2 * port,
+        }
+    }
+
+    // The port a queue belongs to and whether it's the transmit queue
+    fn port_of(queue: usize) -> Option<(usize, bool)> {
+        match queue {
+            0 | 1 => Some((0, queue == 1)),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some(((queue - 2) / 2, queue % 2 == 1)),
+        }
+    }
+
+    // The driver throws away what arrives on a port nobody has open
+    fn accepting(&self, port: &Port) -> bool {
+        !self.multiport || port.console() || port.open
+    }
+
+    fn send_control(&mut self, id: usize, event: u16, value: u16, data: &[rv32::Byte]) {
+        let mut message = (id as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+    }
+
+    fn control_message(&mut self, message: &[rv32::Byte]) -> Result<(), String> {
+        if message.len() < CONTROL_SIZE {
+            return Err("short console control message".to_string());
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.send_control(id, PORT_ADD, 0, &[]);
+                }
+            }
+            DEVICE_READY => println!("VM > virtio-console > Driver failed to start"),
+            PORT_READY if id >= self.ports.len() => (),
+            PORT_READY if value == 1 => {
+                let port = &self.ports[id];
+                let (console, name) = (port.console(), port.name.clone());
+                if console {
+                    self.send_control(id, CONSOLE_PORT, 1, &[]);
+                }
+                if let Some(name) = name {
+                    self.send_control(id, PORT_NAME, 1, name.as_bytes());
+                }
+                // the host end is always there
+                self.send_control(id, PORT_OPEN, 1, &[]);
+            }
+            PORT_READY => println!("VM > virtio-console > Driver failed to add port {}", id),
+            PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id) {
+                    port.open = value != 0;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn control_receive(
+        &mut self,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        let mut used = false;
+        while !self.control.is_empty() {
+            let chain = match vq.pop(memory)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write_at(memory, 0, &message)?;
+            vq.push(memory, &chain, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn control_transmit(
+        &mut self,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let message = chain.read_all(memory)?;
+            self.control_message(&message)?;
+            vq.push(memory, &chain, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(
+        &mut self,
+        port: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        if !self.accepting(&self.ports[port]) {
+            return Ok(false);
+        }
+        let mut used = false;
+        let port = &mut self.ports[port];
+        while !port.rx.is_empty() {
+            let chain = match vq.pop(memory)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let len = std::cmp::min(chain.writable_len() as usize, port.rx.len());
+            let data: Vec<rv32::Byte> = port.rx.drain(..len).collect();
+            let written = chain.write_at(memory, 0, &data)?;
+            vq.push(memory, &chain, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn transmit(
+        &mut self,
+        port: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let data = chain.read_all(memory)?;
+            let port = &mut self.ports[port];
+            data.iter().for_each(|byte| port.backend.write(*byte));
+            vq.push(memory, &chain, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for Console {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        F_MULTIPORT
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.multiport = features & F_MULTIPORT != 0;
+    }
+
+    fn queue_count(&self) -> usize {
+        2 + 2 * self.ports.len()
+    }
+
+    // cols, rows, max_nr_ports and emerg_wr
+    fn config(&self) -> Vec<rv32::Byte> {
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+        for port in self.ports.iter_mut() {
+            port.open = false;
+            port.rx.clear();
+        }
+    }
+
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.since_poll += elapsed_micros;
+        if self.since_poll < POLL_MICROS {
+            return;
+        }
+        self.since_poll = 0;
+        for port in self.ports.iter_mut() {
+            while port.rx.len() < MAX_BACKLOG {
+                match port.backend.read() {
+                    Some(byte) => port.rx.push_back(byte),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn pending(&self) -> u32 {
+        let mut pending = match self.control.is_empty() {
+            true => 0,
+            false => 1 << CONTROL_RX,
+        };
+        for (index, port) in self.ports.iter().enumerate() {
+            if !port.rx.is_empty() && self.accepting(port) {
+                pending |= 1 << Console::receive_queue(index);
+            }
+        }
+        pending
+    }
+
+    fn process(
+        &mut self,
+        queue: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        match Console::port_of(queue) {
+            None if queue == CONTROL_RX => self.control_receive(vq, memory),
+            None => self.control_transmit(vq, memory),
+            Some((port, true)) => self.transmit(port, vq, memory),
+            Some((port, false)) => self.receive(port, vq, memory),
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,226 @@
+use std::any::Any;
+use std::collections::VecDeque;
+
+use crate::system::bus::GuestMemory;
+use crate::system::net::{self, Backend, Capture};
+use crate::system::rv32;
+use crate::system::virtio::{Queue, VirtioDevice};
+
+// virtio-net on one of the host-local network backends
+//
+// No offloads and no mergeable receive buffers, every frame goes in a
+// buffer of its own behind the 12 byte virtio-net header. Frames from the
+// backend wait here for the driver to give us somewhere to put them, and
+// while the link is down nothing is sent or received.
+
+pub const DEVICE_ID: u32 = 1;
+
+pub const F_MAC: u64 = 1 << 5;
+pub const F_STATUS: u64 = 1 << 16;
+
+pub const S_LINK_UP: u16 = 1;
+
+pub const HEADER_SIZE: usize = 12; // virtio_net_hdr with num_buffers
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+// How often the backend is checked for frames
+const POLL_MICROS: u64 = 1000;
+
+// Frames held for a driver that isn't taking them, more are dropped
+const MAX_BACKLOG: usize = 256;
+
+pub struct Net {
+    backend: Box<dyn Backend>,
+    capture: Option<Capture>, // both directions, when asked for
+    mac: [rv32::Byte; 6],
+    link_up: bool,
+    rx: VecDeque<Vec<rv32::Byte>>,
+    since_poll: u64,
+}
+
+impl Net {
+    // BACKEND[,mac=MAC][,link=up|down][,pcap=FILE], the nth NIC has a MAC
+    // of its own unless it's given one
+    pub fn from_spec(spec: &str, index: usize) -> Result<Net, String> {
+        let mut parts = spec.split(',');
+        let backend = parts.next().unwrap_or("");
+        let mut options = Vec::new();
+        for option in parts {
+            options.push(
+                option
+                    .split_once('=')
+                    .ok_or(format!("unknown nic option {}", option))?,
+            );
+        }
+        let backend = net::from_spec(backend, &mut options)?;
+        let mac = match net::take_option(&mut options, "mac") {
+            Some(mac) => parse_mac(mac)?,
+            None => [
+                0x52,
+                0x54,
+                0x00,
+                0x12,
+                0x34,
+                0x56u8.wrapping_add(index as u8),
+            ],
+        };
+        let link_up = match net::take_option(&mut options, "link") {
+            None | Some("up") => true,
+            Some("down") => false,
+            Some(link) => return Err(format!("link is up or down, not {}", link)),
+        };
+        let capture = net::take_option(&mut options, "pcap")
+            .map(Capture::create)
+            .transpose()?;
+        if let Some((option, _)) = options.first() {
+            return Err(format!("unknown nic option {}", option));
+        }
+        println!("VM > NIC {} on {}", format_mac(&mac), backend.describe());
+        Ok(Net {
+            backend,
+            capture,
+            mac,
+            link_up,
+            rx: VecDeque::new(),
+            since_poll: 0,
+        })
+    }
+
+    // The transport has to tell the driver the configuration changed
+    pub fn set_link(&mut self, up: bool) {
+        println!(
+            "VM > NIC {} link {}",
+            format_mac(&self.mac),
+            if up { "up" } else { "down" }
+        );
+        self.link_up = up;
+    }
+
+    fn transmit(&mut self, vq: &mut Queue, memory: &mut GuestMemory) -> Result<bool, String> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let packet = chain.read_all(memory)?;
+            if packet.len() < HEADER_SIZE {
+                return Err("packet without a header".to_string());
+            }
+            let frame = &packet[HEADER_SIZE..];
+            if self.link_up {
+                if let Some(capture) = self.capture.as_mut() {
+                    capture.record(frame);
+                }
+                self.backend.send(frame);
+            }
+            vq.push(memory, &chain, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(&mut self, vq: &mut Queue, memory: &mut GuestMemory) -> Result<bool, String> {
+        let mut used = false;
+        while !self.rx.is_empty() {
+            let chain = match vq.pop(memory)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let frame = self.rx.pop_front().unwrap();
+            // one buffer per frame, anything that doesn't fit is cut off
+            let mut header = [0; HEADER_SIZE];
+            header[10] = 1; // num_buffers
+            let written = chain.write_at(memory, 0, &header)?
+                + chain.write_at(memory, HEADER_SIZE as u32, &frame)?;
+            if let Some(capture) = self.capture.as_mut() {
+                capture.record(&frame);
+            }
+            vq.push(memory, &chain, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+fn parse_mac(mac: &str) -> Result<[rv32::Byte; 6], String> {
+    let octets: Vec<rv32::Byte> = mac
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("{} isn't a MAC address", mac))?;
+    octets
+        .try_into()
+        .map_err(|_| format!("{} isn't a MAC address", mac))
+}
+
+fn format_mac(mac: &[rv32::Byte; 6]) -> String {
+    let octets: Vec<String> = mac.iter().map(|octet| format!("{:02x}", octet)).collect();
+    octets.join(":")
+}
+
+impl VirtioDevice for Net {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        F_MAC | F_STATUS
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    // mac and status
+    fn config(&self) -> Vec<rv32::Byte> {
+        let status = if self.link_up { S_LINK_UP } else { 0 };
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&status.to_le_bytes());
+        config
+    }
+
+    fn reset(&mut self) {
+        self.rx.clear();
+    }
+
+    // Frames arriving while the link is down are lost
+    fn tick(&mut self, elapsed_micros: u64) {
+        self.since_poll += elapsed_micros;
+        if self.since_poll < POLL_MICROS {
+            return;
+        }
+        self.since_poll = 0;
+        while let Some(frame) = self.backend.recv() {
+            if self.link_up && self.rx.len() < MAX_BACKLOG {
+                self.rx.push_back(frame);
+            }
+        }
+    }
+
+    fn pending(&self) -> u32 {
+        match self.link_up && !self.rx.is_empty() {
+            true => 1 << RX,
+            false => 0,
+        }
+    }
+
+    fn process(
+        &mut self,
+        queue: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        match queue {
+            RX => self.receive(vq, memory),
+            TX => self.transmit(vq, memory),
+            _ => Ok(false),
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
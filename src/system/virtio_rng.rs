@@ -0,0 +1,125 @@
+use std::any::Any;
+
+use crate::system::bus::GuestMemory;
+use crate::system::rv32;
+use crate::system::virtio::{Queue, VirtioDevice};
+
+// virtio-rng, entropy from the host or from a seeded generator so a run
+// can be repeated exactly
+
+pub const DEVICE_ID: u32 = 4;
+
+// Most handed over in one buffer
+const MAX_REQUEST: usize = 64 * 1024;
+
+enum Source {
+    Host,
+    Seeded { seed: u64, state: u64 },
+}
+
+pub struct Rng {
+    source: Source,
+}
+
+impl Rng {
+    // host or seed=N
+    pub fn from_spec(spec: &str) -> Result<Rng, String> {
+        let source = match spec.split_once('=') {
+            Some(("seed", seed)) => {
+                let seed = parse_seed(seed)?;
+                Source::Seeded { seed, state: seed }
+            }
+            None if spec == "host" => Source::Host,
+            _ => return Err(format!("unknown rng source {}", spec)),
+        };
+        match source {
+            Source::Host => println!("VM > Entropy from the host"),
+            Source::Seeded { seed, .. } => println!("VM > Entropy seeded with 0x{:x}", seed),
+        }
+        Ok(Rng { source })
+    }
+
+    fn fill(&mut self, buffer: &mut [rv32::Byte]) -> Result<(), String> {
+        match &mut self.source {
+            Source::Host => {
+                let mut done = 0;
+                while done < buffer.len() {
+                    let rest = &mut buffer[done..];
+                    let got = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+                    if got < 0 {
+                        let error = std::io::Error::last_os_error();
+                        if error.kind() != std::io::ErrorKind::Interrupted {
+                            return Err(format!("getrandom: {}", error));
+                        }
+                        continue;
+                    }
+                    done += got as usize;
+                }
+            }
+            // splitmix64
+            Source::Seeded { state, .. } => {
+                for chunk in buffer.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A 64 bit seed, hex with a 0x prefix or decimal
+fn parse_seed(value: &str) -> Result<u64, String> {
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.replace('_', "").parse::<u64>(),
+    };
+    parsed.map_err(|e| format!("invalid seed {}: {}", value, e))
+}
+
+impl VirtioDevice for Rng {
+    fn name(&self) -> &'static str {
+        "virtio-rng"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    // a reset machine gets the same entropy as it did the first time
+    fn reset(&mut self) {
+        if let Source::Seeded { seed, state } = &mut self.source {
+            *state = *seed;
+        }
+    }
+
+    fn process(
+        &mut self,
+        _queue: usize,
+        vq: &mut Queue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, String> {
+        let mut used = false;
+        while let Some(chain) = vq.pop(memory)? {
+            let len = std::cmp::min(chain.writable_len() as usize, MAX_REQUEST);
+            let mut entropy = vec![0; len];
+            self.fill(&mut entropy)?;
+            let written = chain.write_at(memory, 0, &entropy)?;
+            vq.push(memory, &chain, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}